@@ -2,6 +2,7 @@ use derive_more::{Display, From};
 use futures::{Future, TryFutureExt};
 use loony::http::error::BlockingError;
 use loony::web::{self, WebResponseError};
+use request_limits::Slot;
 use rusqlite::{Statement, NO_PARAMS};
 use serde::{Deserialize, Serialize};
 use std::{thread::sleep, time::Duration};
@@ -41,12 +42,16 @@ pub enum Queries {
     GetTopTenColdestMonths,
 }
 
+/// Runs the query on the thread pool, holding on to `slot` until it is done.
 pub fn execute(
     pool: &Pool,
     query: Queries,
+    slot: &Slot,
 ) -> impl Future<Output = Result<Vec<WeatherAgg>, BlockingError<Error>>> {
     let pool = pool.clone();
+    let slot = slot.clone();
     web::block(move || {
+        let _slot = slot;
         // simulate an expensive query, see comments at top of main.rs
         sleep(Duration::from_secs(2));
 
@@ -11,24 +11,34 @@ in a thread-pool using `web::block` with two examples:
 
     Note: The use of sleep(Duration::from_secs(2)); in db.rs is to make performance
           improvement with parallelism more obvious.
+
+Both handlers run behind a `Timeout` and a `ConcurrencyLimit`, see the
+`request-limits` crate, so slow queries and bursts of requests can't tie up
+every worker. The queries keep the request's `Slot`, a request that timed out
+still counts against the limit until its queries are done.
  */
 use std::io;
+use std::time::Duration;
 
 use futures::future::join_all;
 use loony::web::{self, middleware, App, HttpResponse, HttpServer};
 use r2d2_sqlite::{self, SqliteConnectionManager};
+use request_limits::{ConcurrencyLimit, Slot, Timeout};
 
 mod db;
 use db::{Error, Pool, Queries};
 
 /// Version 1: Calls 4 queries in sequential order, as an asynchronous handler
 #[web::get("/asyncio_weather")]
-async fn asyncio_weather(db: web::types::Data<Pool>) -> Result<HttpResponse, Error> {
+async fn asyncio_weather(
+    db: web::types::Data<Pool>,
+    slot: Slot,
+) -> Result<HttpResponse, Error> {
     let result = vec![
-        db::execute(&db, Queries::GetTopTenHottestYears).await?,
-        db::execute(&db, Queries::GetTopTenColdestYears).await?,
-        db::execute(&db, Queries::GetTopTenHottestMonths).await?,
-        db::execute(&db, Queries::GetTopTenColdestMonths).await?,
+        db::execute(&db, Queries::GetTopTenHottestYears, &slot).await?,
+        db::execute(&db, Queries::GetTopTenColdestYears, &slot).await?,
+        db::execute(&db, Queries::GetTopTenHottestMonths, &slot).await?,
+        db::execute(&db, Queries::GetTopTenColdestMonths, &slot).await?,
     ];
 
     Ok(HttpResponse::Ok().json(&result))
@@ -37,12 +47,15 @@ async fn asyncio_weather(db: web::types::Data<Pool>) -> Result<HttpResponse, Err
 /// Version 2: Calls 4 queries in parallel, as an asynchronous handler
 /// Returning Error types turn into None values in the response
 #[web::get("/parallel_weather")]
-async fn parallel_weather(db: web::types::Data<Pool>) -> Result<HttpResponse, Error> {
+async fn parallel_weather(
+    db: web::types::Data<Pool>,
+    slot: Slot,
+) -> Result<HttpResponse, Error> {
     let fut_result = vec![
-        Box::pin(db::execute(&db, Queries::GetTopTenHottestYears)),
-        Box::pin(db::execute(&db, Queries::GetTopTenColdestYears)),
-        Box::pin(db::execute(&db, Queries::GetTopTenHottestMonths)),
-        Box::pin(db::execute(&db, Queries::GetTopTenColdestMonths)),
+        Box::pin(db::execute(&db, Queries::GetTopTenHottestYears, &slot)),
+        Box::pin(db::execute(&db, Queries::GetTopTenColdestYears, &slot)),
+        Box::pin(db::execute(&db, Queries::GetTopTenHottestMonths, &slot)),
+        Box::pin(db::execute(&db, Queries::GetTopTenColdestMonths, &slot)),
     ];
     let result: Result<Vec<_>, _> = join_all(fut_result).await.into_iter().collect();
 
@@ -58,13 +71,23 @@ async fn main() -> io::Result<()> {
     let manager = SqliteConnectionManager::file("weather.db");
     let pool = Pool::new(manager).unwrap();
 
+    // shared by all workers: 4 requests at a time, 16 more may wait
+    let limit = ConcurrencyLimit::new(4).queue(16);
+
     // Start http server
     HttpServer::new(move || {
         App::new()
             // store db pool as Data object
             .data(pool.clone())
             .wrap(middleware::Logger::default())
-            .service((asyncio_weather, parallel_weather))
+            .service(
+                web::scope("")
+                    // the sequential version takes 8 seconds, waiting for a
+                    // slot doesn't count as the limit is registered last
+                    .wrap(Timeout::new(Duration::from_secs(10)))
+                    .wrap(limit.clone())
+                    .service((asyncio_weather, parallel_weather)),
+            )
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -1,12 +1,19 @@
+use std::sync::Arc;
+use std::time::Duration;
 use std::{env, io};
 
 // use bytes::Bytes;
 use loony::channel::mpsc;
 use loony::http::{header, Method, StatusCode};
+use loony::rt::time_driver::sleep;
 use loony::web::{self, error, guard, middleware, App, Error, HttpRequest, HttpResponse};
 use loony_files as fs;
-use loony_session::{CookieSession, Session};
+use loony_session::Session;
 use loony::util::Bytes;
+use session_store::{
+    MemoryStore, ServerSession, SessionStore, DEFAULT_ABSOLUTE_TIMEOUT,
+    DEFAULT_IDLE_TIMEOUT,
+};
 
 /// favicon handler
 #[web::get("/favicon")]
@@ -71,10 +78,28 @@ async fn main() -> io::Result<()> {
     env::set_var("RUST_LOG", "loony=info");
     env_logger::init();
 
-    web::server(|| {
+    // shared by the workers, sessions are lost on restart
+    let sessions: Arc<dyn SessionStore> = Arc::new(MemoryStore::default());
+    let store = sessions.clone();
+    loony::rt::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(60)).await;
+            let removed = session_store::remove_expired(
+                &*store,
+                DEFAULT_IDLE_TIMEOUT,
+                DEFAULT_ABSOLUTE_TIMEOUT,
+            )
+            .await;
+            if let Err(e) = removed {
+                println!("Removing expired sessions failed: {:?}", e);
+            }
+        }
+    });
+
+    web::server(move || {
         App::new()
-            // cookie session middleware
-            .wrap(CookieSession::signed(&[0; 32]).secure(false))
+            // session middleware, the cookie only holds the session id
+            .wrap(ServerSession::new(sessions.clone()).secure(false))
             // enable logger - always register actix-web Logger middleware last
             .wrap(middleware::Logger::default())
             .service((
@@ -1,11 +1,38 @@
+use csrf_middleware::{Csrf, CsrfToken};
 use loony::web::{self, middleware, App, HttpResponse};
 use loony_identity::{CookieIdentityPolicy, Identity, IdentityService};
 
-async fn index(id: Identity) -> String {
-    format!(
-        "Hello {}",
-        id.identity().unwrap_or_else(|| "Anonymous".to_owned())
-    )
+async fn index(id: Identity, csrf_token: CsrfToken) -> HttpResponse {
+    let (name, action) = match id.identity() {
+        Some(name) => (name, "logout"),
+        None => ("Anonymous".to_owned(), "login"),
+    };
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            r#"<p>Hello {}</p>
+<form action="/{}" method="post">
+    {}
+    <button type="submit">{}</button>
+</form>"#,
+            escape_html(&name),
+            action,
+            csrf_token.hidden_input(),
+            action
+        ))
+}
+
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_owned(),
+            '<' => "&lt;".to_owned(),
+            '>' => "&gt;".to_owned(),
+            '"' => "&quot;".to_owned(),
+            '\'' => "&#39;".to_owned(),
+            c => c.to_string(),
+        })
+        .collect()
 }
 
 async fn login(id: Identity) -> HttpResponse {
@@ -22,14 +49,17 @@ async fn logout(id: Identity) -> HttpResponse {
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=info");
     env_logger::init();
+    let keys = cookie_keys::Keyring::from_env().expect("Failed to load cookie keys");
 
-    web::server(|| {
+    web::server(move || {
         App::new()
+            .wrap(Csrf)
             .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&[0; 32])
+                CookieIdentityPolicy::new(keys.active())
                     .name("auth-example")
                     .secure(false),
             ))
+            .wrap(keys.rotation().private("auth-example"))
             // enable logger - always register actix-web Logger middleware last
             .wrap(middleware::Logger::default())
             .service((
@@ -0,0 +1,338 @@
+//! Cookie keys with rotation.
+//!
+//! The first key signs and encrypts new cookies, the others are still
+//! accepted for cookies issued before a rotation. Keys are hex encoded and
+//! come from `COOKIE_KEYS` (comma separated) or the file named by
+//! `COOKIE_KEYS_FILE` (one per line), active key first. Debug builds without
+//! either generate a key into `cookie-keys.txt`.
+//!
+//! Keys shorter than 32 bytes are always refused, the cookie crate can't
+//! derive from them. Keys with hardly any distinct bytes, like `[0; 32]`,
+//! are refused unless `ALLOW_INSECURE_KEYS=1` is set.
+
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::{env, fs};
+
+use cookie::{Cookie, CookieJar, Key};
+use loony::http::header::{self, HeaderMap, HeaderValue};
+use loony::web::{Error, WebRequest, WebResponse};
+use loony::{Service, Transform};
+
+const MIN_KEY_LEN: usize = 32;
+const MIN_DISTINCT_BYTES: usize = 8;
+const DEV_KEYS_FILE: &str = "cookie-keys.txt";
+
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Vec<Vec<u8>>,
+}
+
+impl Keyring {
+    pub fn from_env() -> Result<Self, String> {
+        let keys = if let Ok(keys) = env::var("COOKIE_KEYS") {
+            parse_keys(&keys.replace(',', "\n"))?
+        } else if let Ok(path) = env::var("COOKIE_KEYS_FILE") {
+            let keys = fs::read_to_string(&path)
+                .map_err(|e| format!("can't read {}: {}", path, e))?;
+            parse_keys(&keys)?
+        } else if cfg!(debug_assertions) {
+            dev_keys()?
+        } else {
+            return Err("set COOKIE_KEYS or COOKIE_KEYS_FILE".to_owned());
+        };
+        Keyring::new(keys)
+    }
+
+    pub fn new(keys: Vec<Vec<u8>>) -> Result<Self, String> {
+        if keys.is_empty() {
+            return Err("no cookie keys".to_owned());
+        }
+        if keys.iter().any(|key| key.len() < MIN_KEY_LEN) {
+            return Err(format!(
+                "refusing a short cookie key, keys need at least {} bytes",
+                MIN_KEY_LEN
+            ));
+        }
+        if !insecure_allowed() {
+            if let Some(problem) = keys.iter().find_map(|key| weakness(key)) {
+                return Err(format!(
+                    "refusing a {} cookie key, set ALLOW_INSECURE_KEYS=1 to use it anyway",
+                    problem
+                ));
+            }
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// Signs and encrypts new cookies.
+    pub fn active(&self) -> &[u8] {
+        &self.keys[0]
+    }
+
+    /// Middleware that reseals cookies from older keys with the active key
+    /// before the session or identity middleware sees them. Register it
+    /// outside of them.
+    pub fn rotation(&self) -> KeyRotation {
+        let mut keys = self.keys.iter().map(|key| Key::derive_from(key));
+        KeyRotation {
+            inner: Rc::new(Inner {
+                active: keys.next().expect("a keyring has an active key"),
+                previous: keys.collect(),
+                cookies: Vec::new(),
+            }),
+        }
+    }
+}
+
+/// Why a key is too weak to use, if it is.
+pub fn weakness(key: &[u8]) -> Option<&'static str> {
+    let distinct: HashSet<_> = key.iter().collect();
+    if key.len() < MIN_KEY_LEN {
+        Some("short")
+    } else if distinct.len() < MIN_DISTINCT_BYTES {
+        Some("low entropy")
+    } else {
+        None
+    }
+}
+
+pub fn insecure_allowed() -> bool {
+    env::var("ALLOW_INSECURE_KEYS").map_or(false, |v| v == "1" || v == "true")
+}
+
+fn dev_keys() -> Result<Vec<Vec<u8>>, String> {
+    if let Ok(keys) = fs::read_to_string(DEV_KEYS_FILE) {
+        return parse_keys(&keys);
+    }
+    let key = (0..64).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+    fs::write(DEV_KEYS_FILE, format!("{}\n", to_hex(&key)))
+        .map_err(|e| format!("can't write {}: {}", DEV_KEYS_FILE, e))?;
+    eprintln!("Generated a cookie key into {}", DEV_KEYS_FILE);
+    Ok(vec![key])
+}
+
+fn parse_keys(keys: &str) -> Result<Vec<Vec<u8>>, String> {
+    keys.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| from_hex(line).ok_or_else(|| "cookie keys must be hex".to_owned()))
+        .collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Clone, Copy)]
+enum Seal {
+    Signed,
+    Private,
+}
+
+pub struct KeyRotation {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    active: Key,
+    previous: Vec<Key>,
+    cookies: Vec<(String, Seal)>,
+}
+
+impl KeyRotation {
+    /// A cookie signed by `CookieSession::signed`.
+    pub fn signed(mut self, name: &str) -> Self {
+        self.inner_mut()
+            .cookies
+            .push((name.to_owned(), Seal::Signed));
+        self
+    }
+
+    /// A cookie encrypted by `CookieIdentityPolicy` or `CookieSession::private`.
+    pub fn private(mut self, name: &str) -> Self {
+        self.inner_mut()
+            .cookies
+            .push((name.to_owned(), Seal::Private));
+        self
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Rc::get_mut(&mut self.inner).expect("KeyRotation is configured before use")
+    }
+}
+
+impl<S, Err> Transform<S> for KeyRotation
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+{
+    type Service = KeyRotationMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        KeyRotationMiddleware {
+            service,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+pub struct KeyRotationMiddleware<S> {
+    service: S,
+    inner: Rc<Inner>,
+}
+
+impl<S, Err> Service for KeyRotationMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = S::Future;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: Self::Request) -> Self::Future {
+        if !self.inner.previous.is_empty() {
+            self.inner.reseal_all(req.headers_mut());
+        }
+        self.service.call(req)
+    }
+}
+
+impl Inner {
+    /// Rewrites the `Cookie` header, the browser keeps the old cookie until
+    /// the session or identity changes.
+    fn reseal_all(&self, headers: &mut HeaderMap) {
+        let joined = headers
+            .get_all(header::COOKIE)
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        let mut changed = false;
+        let cookies = joined
+            .split(';')
+            .map(str::trim)
+            .filter(|raw| !raw.is_empty())
+            .map(|raw| match self.reseal(raw) {
+                Some(resealed) => {
+                    changed = true;
+                    resealed
+                }
+                None => raw.to_owned(),
+            })
+            .collect::<Vec<_>>();
+
+        if changed {
+            if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+                headers.insert(header::COOKIE, value);
+            }
+        }
+    }
+
+    /// The cookie sealed with the active key, if it was sealed with an older
+    /// one.
+    fn reseal(&self, raw: &str) -> Option<String> {
+        let cookie = Cookie::parse_encoded(raw.to_owned()).ok()?;
+        let name = cookie.name().to_owned();
+        let seal = self
+            .cookies
+            .iter()
+            .find(|(configured, _)| *configured == name)
+            .map(|(_, seal)| *seal)?;
+
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie);
+        if open(&mut jar, &self.active, &name, seal).is_some() {
+            return None;
+        }
+        let plain = self
+            .previous
+            .iter()
+            .find_map(|key| open(&mut jar, key, &name, seal))?;
+
+        let mut resealed = CookieJar::new();
+        match seal {
+            Seal::Signed => resealed.signed(&self.active).add(plain),
+            Seal::Private => resealed.private(&self.active).add(plain),
+        }
+        let value = resealed.get(&name)?.value().to_owned();
+        Some(Cookie::new(name, value).encoded().to_string())
+    }
+}
+
+fn open(
+    jar: &mut CookieJar,
+    key: &Key,
+    name: &str,
+    seal: Seal,
+) -> Option<Cookie<'static>> {
+    match seal {
+        Seal::Signed => jar.signed(key).get(name),
+        Seal::Private => jar.private(key).get(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_keys() {
+        assert_eq!(weakness(&[0; 32]), Some("low entropy"));
+        assert_eq!(weakness("0123".repeat(8).as_bytes()), Some("low entropy"));
+        assert_eq!(weakness(&[1, 2, 3]), Some("short"));
+        assert_eq!(weakness(&(0..32).collect::<Vec<u8>>()), None);
+    }
+
+    #[test]
+    fn short_keys_are_always_refused() {
+        env::set_var("ALLOW_INSECURE_KEYS", "1");
+        assert!(Keyring::new(vec![(0..31).collect()]).is_err());
+        assert!(Keyring::new(vec![(0..32).collect(), vec![1, 2, 3]]).is_err());
+        assert!(Keyring::new(vec![vec![0; 32]]).is_ok());
+        env::remove_var("ALLOW_INSECURE_KEYS");
+    }
+
+    #[test]
+    fn reseals_cookies_from_old_keys() {
+        let old = (0..64).collect::<Vec<u8>>();
+        let new = (64..128).collect::<Vec<u8>>();
+
+        let mut jar = CookieJar::new();
+        jar.signed(&Key::derive_from(&old))
+            .add(Cookie::new("session", "{\"user_id\":\"1\"}"));
+        let old_cookie = jar.get("session").unwrap().clone();
+
+        let rotation = Keyring::new(vec![new.clone(), old])
+            .unwrap()
+            .rotation()
+            .signed("session");
+        let mut headers = HeaderMap::new();
+        let raw = Cookie::new("session", old_cookie.value().to_owned())
+            .encoded()
+            .to_string();
+        headers.insert(header::COOKIE, HeaderValue::from_str(&raw).unwrap());
+        rotation.inner.reseal_all(&mut headers);
+
+        let resealed = headers.get(header::COOKIE).unwrap().to_str().unwrap();
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::parse_encoded(resealed.to_owned()).unwrap());
+        let plain = jar.signed(&Key::derive_from(&new)).get("session").unwrap();
+        assert_eq!(plain.value(), "{\"user_id\":\"1\"}");
+    }
+}
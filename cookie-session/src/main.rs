@@ -1,12 +1,22 @@
-//! Example of cookie based session
-//! Session data is stored in cookie, it is limited to 4kb
+//! Example of a cookie based session
+//! The cookie only holds a random id, the session data is kept in SQLite, so
+//! it isn't limited to 4kb and survives restarts
 //!
 //! [Redis session example](https://github.com/actix/examples/tree/master/redis-session)
 //!
 //! [User guide](https://actix.rs/docs/middleware/#user-sessions)
 
+use std::sync::Arc;
+use std::time::Duration;
+
+use loony::rt::time_driver::sleep;
 use loony::web::{self, middleware::Logger, App, Error, HttpRequest};
-use loony_session::{CookieSession, Session};
+use loony_session::Session;
+use r2d2_sqlite::SqliteConnectionManager;
+use session_store::{
+    ServerSession, SessionStore, SqliteStore, DEFAULT_ABSOLUTE_TIMEOUT,
+    DEFAULT_IDLE_TIMEOUT,
+};
 
 /// simple index handler with session
 #[web::get("/")]
@@ -30,14 +40,38 @@ async fn index(session: Session, req: HttpRequest) -> Result<&'static str, Error
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=info");
     env_logger::init();
+
+    let db = std::env::var("SESSION_DB").unwrap_or_else(|_| "sessions.db".into());
+    let pool = r2d2::Pool::new(SqliteConnectionManager::file(db))
+        .expect("Failed to open the session database");
+    let sessions: Arc<dyn SessionStore> =
+        Arc::new(SqliteStore::new(pool).expect("Failed to create the session table"));
+
+    // expired sessions would stay in the table otherwise
+    let store = sessions.clone();
+    loony::rt::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(60)).await;
+            let removed = session_store::remove_expired(
+                &*store,
+                DEFAULT_IDLE_TIMEOUT,
+                DEFAULT_ABSOLUTE_TIMEOUT,
+            )
+            .await;
+            if let Err(e) = removed {
+                println!("Removing expired sessions failed: {:?}", e);
+            }
+        }
+    });
+
     println!("Starting http server: 127.0.0.1:8080");
 
-    web::server(|| {
+    web::server(move || {
         App::new()
             // enable logger
             .wrap(Logger::default())
-            // cookie session middleware
-            .wrap(CookieSession::signed(&[0; 32]).secure(false))
+            // server-side session middleware
+            .wrap(ServerSession::new(sessions.clone()).secure(false))
             .service(index)
     })
     .bind("127.0.0.1:8080")?
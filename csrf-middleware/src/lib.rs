@@ -0,0 +1,378 @@
+//! CSRF protection for form posts with a double-submit cookie.
+//!
+//! Every response carries a random token in the `csrf_token` cookie. Unsafe
+//! requests (`POST`, `PUT`, `PATCH`, `DELETE`) have to repeat it, either in
+//! the `X-CSRF-Token` header or in a `csrf_token` field of an urlencoded
+//! form. Another site can make the browser send the cookie, but it can't
+//! read it to fill in the form.
+//!
+//! Handlers get the token with the `CsrfToken` extractor and hand it to
+//! their template, it serializes and displays as the bare token:
+//!
+//! * Tera: `ctx.insert("csrf_token", &token)`, see `template_tera`
+//! * Handlebars: `json!({ "csrf_token": token })`, see `template_handlebars`
+//! * Askama: a `csrf_token: CsrfToken` field, see `template_askama`
+//! * Yarte: a `csrf_token: CsrfToken` field, see `template_yarte`
+//!
+//! and the form gets
+//! `<input type="hidden" name="csrf_token" value="{{ csrf_token }}">`, or
+//! `token.hidden_input()` as is when there is no template.
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::future::{ready, Ready};
+use futures::stream::{self, StreamExt};
+use loony::http::cookie::{Cookie, SameSite};
+use loony::http::error::PayloadError;
+use loony::http::{header, HttpMessage, Method, Payload};
+use loony::util::BytesMut;
+use loony::web::{
+    error, Error, ErrorRenderer, FromRequest, HttpRequest, HttpResponse, WebRequest,
+    WebResponse,
+};
+use loony::{Service, Transform};
+use serde::{Serialize, Serializer};
+
+const COOKIE_NAME: &str = "csrf_token";
+const FIELD_NAME: &str = "csrf_token";
+const HEADER_NAME: &str = "x-csrf-token";
+/// Forms bigger than this have to send the token in the header.
+const FORM_LIMIT: usize = 64 * 1024;
+const REJECTED: &str = "CSRF token missing or invalid, reload the page and try again";
+
+/// The CSRF token of the current request.
+#[derive(Clone, Debug)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn value(&self) -> &str {
+        &self.0
+    }
+
+    /// Hidden form field holding the token. The token is hex, so it needs
+    /// no escaping.
+    pub fn hidden_input(&self) -> String {
+        format!(
+            r#"<input type="hidden" name="{}" value="{}">"#,
+            FIELD_NAME, self.0
+        )
+    }
+}
+
+impl fmt::Display for CsrfToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for CsrfToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<Err> FromRequest<Err> for CsrfToken {
+    type Error = Error;
+    type Future = Ready<Result<CsrfToken, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<CsrfToken>().cloned().ok_or_else(|| {
+            error::ErrorInternalServerError("Csrf middleware is not registered").into()
+        }))
+    }
+}
+
+/// Verifies the CSRF token of unsafe requests and answers `403 Forbidden`
+/// when it is missing or wrong.
+pub struct Csrf;
+
+impl<S, Err> Transform<S> for Csrf
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Service = CsrfMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        CsrfMiddleware {
+            service: Rc::new(service),
+        }
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, Err> Service for CsrfMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: Self::Request) -> Self::Future {
+        let svc = self.service.clone();
+
+        Box::pin(async move {
+            let cookie = req
+                .cookie(COOKIE_NAME)
+                .map(|cookie| cookie.value().to_owned())
+                .filter(|token| is_token(token));
+
+            let is_unsafe = !matches!(
+                *req.method(),
+                Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+            );
+            if is_unsafe {
+                let submitted = match submitted_token(&req) {
+                    Some(token) => Some(token),
+                    None => form_token(&mut req).await?,
+                };
+                let valid = match (&cookie, &submitted) {
+                    (Some(cookie), Some(submitted)) => {
+                        constant_time_eq(cookie, submitted)
+                    }
+                    _ => false,
+                };
+                if !valid {
+                    return Ok(req.into_response(
+                        HttpResponse::Forbidden()
+                            .content_type("text/plain")
+                            .body(REJECTED),
+                    ));
+                }
+            }
+
+            let (token, fresh) = match cookie {
+                Some(token) => (token, false),
+                None => (new_token(), true),
+            };
+            req.extensions_mut().insert(CsrfToken(token.clone()));
+
+            let mut res = svc.call(req).await?;
+            if fresh {
+                let cookie = Cookie::build(COOKIE_NAME, token)
+                    .path("/")
+                    .http_only(true)
+                    .same_site(SameSite::Strict)
+                    .finish();
+                res.response_mut().add_cookie(&cookie)?;
+            }
+            Ok(res)
+        })
+    }
+}
+
+fn submitted_token<Err>(req: &WebRequest<Err>) -> Option<String> {
+    req.headers()
+        .get(HEADER_NAME)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// Looks for the token in an urlencoded body and puts the body back for
+/// the `Form` extractor.
+async fn form_token<Err>(req: &mut WebRequest<Err>) -> Result<Option<String>, Error> {
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |ct| {
+            ct.starts_with("application/x-www-form-urlencoded")
+        });
+    if !is_form {
+        return Ok(None);
+    }
+
+    let mut body = BytesMut::new();
+    let mut stream = req.take_payload();
+    let mut complete = false;
+    while body.len() <= FORM_LIMIT {
+        match stream.next().await {
+            Some(chunk) => body.extend_from_slice(&chunk?),
+            None => {
+                complete = true;
+                break;
+            }
+        }
+    }
+
+    // only the whole form counts, a token in the first part of a bigger one
+    // is ignored
+    let token = if complete && body.len() <= FORM_LIMIT {
+        String::from_utf8_lossy(&body)
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == FIELD_NAME)
+            .map(|(_, value)| value.to_owned())
+    } else {
+        None
+    };
+
+    let captured = body.freeze();
+    let replay = stream::once(async move { Ok::<_, PayloadError>(captured) });
+    req.set_payload(Payload::Stream(Box::pin(replay.chain(stream))));
+
+    Ok(token)
+}
+
+fn new_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_token(token: &str) -> bool {
+    token.len() == 64 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// don't give away how many leading characters of a guess were right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use loony::http::StatusCode;
+    use loony::util::Bytes;
+    use loony::web::{self, test, App};
+
+    use super::*;
+
+    fn token() -> String {
+        "ab".repeat(32)
+    }
+
+    /// Echoes the body it gets.
+    async fn echo(body: Bytes) -> HttpResponse {
+        HttpResponse::Ok().body(body)
+    }
+
+    fn post(body: String, content_type: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/")
+            .cookie(Cookie::new(COOKIE_NAME, token()))
+            .header(header::CONTENT_TYPE, content_type)
+            .set_payload(body)
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(constant_time_eq("abc", "abc"));
+        assert!(!constant_time_eq("abc", "abd"));
+        assert!(!constant_time_eq("abc", "abcd"));
+        assert!(!constant_time_eq("", "a"));
+    }
+
+    #[loony::test]
+    async fn issues_the_cookie_on_safe_requests() {
+        let mut app = test::init_service(App::new().wrap(Csrf).service(
+            web::resource("/").to(|token: CsrfToken| async move {
+                HttpResponse::Ok().body(token.value().to_owned())
+            }),
+        ))
+        .await;
+
+        let res =
+            test::call_service(&mut app, test::TestRequest::with_uri("/").to_request())
+                .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = res
+            .headers()
+            .get(header::SET_COOKIE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| Cookie::parse(value.to_owned()).ok())
+            .unwrap();
+        assert_eq!(cookie.name(), COOKIE_NAME);
+        assert!(is_token(cookie.value()));
+        let body = test::read_body(res).await;
+        assert_eq!(&body[..], cookie.value().as_bytes());
+
+        // a browser that has the cookie keeps its token
+        let req = test::TestRequest::with_uri("/")
+            .cookie(Cookie::new(COOKIE_NAME, token()))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert!(!res.headers().contains_key(header::SET_COOKIE));
+        assert_eq!(&test::read_body(res).await[..], token().as_bytes());
+    }
+
+    #[loony::test]
+    async fn checks_the_header_or_the_form_field() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Csrf)
+                .service(web::resource("/").route(web::post().to(echo))),
+        )
+        .await;
+
+        let req = post("{}".to_owned(), "application/json")
+            .header(HEADER_NAME, token())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = post("{}".to_owned(), "application/json")
+            .header(HEADER_NAME, "cd".repeat(32))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // the token isn't looked for in bodies that aren't forms
+        let body = format!("csrf_token={}", token());
+        let req = post(body, "text/plain").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // the handler gets the whole form after the middleware read it
+        let form = format!("name=John&csrf_token={}&note=a%26b", token());
+        let req = post(form.clone(), "application/x-www-form-urlencoded").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(&test::read_body(res).await[..], form.as_bytes());
+
+        let form = format!("name=John&csrf_token={}", "cd".repeat(32));
+        let req = post(form, "application/x-www-form-urlencoded").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[loony::test]
+    async fn rejects_big_forms() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Csrf)
+                .service(web::resource("/").route(web::post().to(echo))),
+        )
+        .await;
+
+        let form = format!("csrf_token={}&data={}", token(), "x".repeat(FORM_LIMIT));
+        let req = post(form.clone(), "application/x-www-form-urlencoded").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // they have to send the token in the header
+        let req = post(form.clone(), "application/x-www-form-urlencoded")
+            .header(HEADER_NAME, token())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await.len(), form.len());
+    }
+}
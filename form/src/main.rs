@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 
+use csrf_middleware::{Csrf, CsrfToken};
 use loony::web::{self, middleware, App, Error, HttpRequest, HttpResponse};
 
 struct AppState {
@@ -10,6 +11,7 @@ struct AppState {
 async fn main() -> std::io::Result<()> {
     web::server(|| {
         App::new()
+            .wrap(Csrf)
             .wrap(middleware::Logger::default())
             .configure(app_config)
     })
@@ -33,10 +35,12 @@ fn app_config(config: &mut web::ServiceConfig) {
     );
 }
 
-async fn index() -> Result<HttpResponse, Error> {
+async fn index(csrf_token: CsrfToken) -> Result<HttpResponse, Error> {
+    let page = include_str!("../static/form.html")
+        .replace("{{ csrf_token }}", csrf_token.value());
     Ok(HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(include_str!("../static/form.html")))
+        .body(page))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -81,6 +85,7 @@ mod tests {
     use super::*;
 
     use loony::http::body::{Body, ResponseBody};
+    use loony::http::cookie::Cookie;
     use loony::http::header::{HeaderValue, CONTENT_TYPE};
     use loony::http::StatusCode;
     use loony::web::test::{self, TestRequest};
@@ -222,4 +227,53 @@ mod tests {
         );
         assert_eq!(resp.response().body().as_str(), "Your name is John");
     }
+
+    #[loony::test]
+    async fn post_requires_csrf_token() {
+        let app = test::init_service(App::new().wrap(Csrf).configure(app_config)).await;
+        let form = MyParams {
+            name: "John".to_string(),
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/post1")
+            .set_form(&form)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let token = "ab".repeat(32);
+        let req = test::TestRequest::post()
+            .uri("/post1")
+            .cookie(Cookie::new("csrf_token", token.clone()))
+            .header("x-csrf-token", token)
+            .set_form(&form)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[loony::test]
+    async fn csrf_token_in_form_field() {
+        let app = test::init_service(App::new().wrap(Csrf).configure(app_config)).await;
+        let token = "cd".repeat(32);
+
+        // the middleware reads the body, the handler still gets all of it
+        let req = test::TestRequest::post()
+            .uri("/post1")
+            .cookie(Cookie::new("csrf_token", token.clone()))
+            .set_form(&[("name", "John"), ("csrf_token", token.as_str())])
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.response().body().as_str(), "Your name is John");
+
+        let req = test::TestRequest::post()
+            .uri("/post1")
+            .cookie(Cookie::new("csrf_token", token))
+            .set_form(&[("name", "John"), ("csrf_token", "ef".repeat(32).as_str())])
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
 }
@@ -1,4 +1,4 @@
-use r2d2_mysql::mysql::{Opts, OptsBuilder};
+use r2d2_mysql::mysql::{Opts, OptsBuilder, Params, Value};
 use r2d2_mysql::MysqlConnectionManager;
 
 pub type Pool = r2d2::Pool<MysqlConnectionManager>;
@@ -10,3 +10,10 @@ pub fn get_db_pool() -> Pool {
     let manager = MysqlConnectionManager::new(builder);
     r2d2::Pool::new(manager).expect("Failed to create DB Pool")
 }
+
+/// `(?, ?, ...)` for `WHERE column IN` with the keys as its parameters.
+pub fn in_list(keys: &[String]) -> (String, Params) {
+    let placeholders = vec!["?"; keys.len()].join(", ");
+    let params = keys.iter().map(|key| Value::from(key.as_str())).collect();
+    (format!("({})", placeholders), Params::Positional(params))
+}
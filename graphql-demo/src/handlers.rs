@@ -12,9 +12,7 @@ pub async fn graphql(
     schema: web::types::Data<Arc<Schema>>,
     data: web::types::Json<GraphQLRequest>,
 ) -> Result<HttpResponse, Error> {
-    let ctx = Context {
-        dbpool: pool.get_ref().to_owned(),
-    };
+    let ctx = Context::new(pool.get_ref().to_owned());
     let res = web::block(move || {
         let res = data.execute(&schema, &ctx);
         Ok::<_, serde_json::error::Error>(serde_json::to_string(&res)?)
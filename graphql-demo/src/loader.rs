@@ -0,0 +1,136 @@
+//! A batching, caching loader that lives as long as one request.
+//!
+//! Juniper runs the resolvers of a request one after the other, so keys
+//! can't be collected while they wait like in JavaScript. Instead the
+//! resolver of a list `defer`s the keys its items will ask for, and the
+//! first `load` fetches all deferred keys with a single batch call, e.g.
+//! `WHERE id IN (...)`. Everything loaded is cached until the request ends.
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+type BatchFn<K, V, E> = Box<dyn Fn(&[K]) -> Result<HashMap<K, V>, E> + Send>;
+
+pub struct Loader<K, V, E> {
+    batch: BatchFn<K, V, E>,
+    state: RefCell<State<K, V>>,
+}
+
+struct State<K, V> {
+    pending: Vec<K>,
+    /// `None` for keys the batch had nothing for
+    cache: HashMap<K, Option<V>>,
+}
+
+impl<K, V, E> Loader<K, V, E>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+{
+    /// `batch` gets the keys to load, keys it returns nothing for load as
+    /// `None`.
+    pub fn new<F>(batch: F) -> Self
+    where
+        F: Fn(&[K]) -> Result<HashMap<K, V>, E> + Send + 'static,
+    {
+        Loader {
+            batch: Box::new(batch),
+            state: RefCell::new(State {
+                pending: Vec::new(),
+                cache: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Queues keys for the next batch, without loading anything yet.
+    pub fn defer<I: IntoIterator<Item = K>>(&self, keys: I) {
+        let mut state = self.state.borrow_mut();
+        let State { pending, cache } = &mut *state;
+        pending.extend(keys.into_iter().filter(|key| !cache.contains_key(key)));
+    }
+
+    /// Caches a value that was loaded some other way.
+    pub fn prime(&self, key: K, value: V) {
+        self.state.borrow_mut().cache.insert(key, Some(value));
+    }
+
+    /// The cached value, or the result of a batch with `key` and all the
+    /// deferred keys.
+    pub fn load(&self, key: K) -> Result<Option<V>, E> {
+        if let Some(value) = self.state.borrow().cache.get(&key) {
+            return Ok(value.clone());
+        }
+
+        let keys = {
+            let mut state = self.state.borrow_mut();
+            let State { pending, cache } = &mut *state;
+            let mut seen = HashSet::new();
+            pending
+                .drain(..)
+                .chain(Some(key.clone()))
+                .filter(|key| !cache.contains_key(key) && seen.insert(key.clone()))
+                .collect::<Vec<K>>()
+        };
+        // the state isn't borrowed while the batch runs
+        let mut loaded = (self.batch)(&keys)?;
+
+        let mut state = self.state.borrow_mut();
+        for key in keys {
+            let value = loaded.remove(&key);
+            state.cache.insert(key, value);
+        }
+        Ok(state.cache[&key].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// A loader of `key * 10` that counts its queries.
+    fn counting_loader() -> (Loader<u32, u32, ()>, Arc<AtomicUsize>) {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        let loader = Loader::new(move |keys: &[u32]| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(keys
+                .iter()
+                .filter(|key| **key != 0)
+                .map(|key| (*key, key * 10))
+                .collect())
+        });
+        (loader, queries)
+    }
+
+    #[test]
+    fn batches_deferred_keys_into_one_query() {
+        let (loader, queries) = counting_loader();
+
+        // like `products { user }`: 100 products of 10 users
+        let user_ids: Vec<u32> = (0..100).map(|i| i % 10 + 1).collect();
+        loader.defer(user_ids.iter().copied());
+        for id in &user_ids {
+            assert_eq!(loader.load(*id), Ok(Some(id * 10)));
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // cached from now on, only unknown keys cost another query
+        assert_eq!(loader.load(3), Ok(Some(30)));
+        assert_eq!(loader.load(0), Ok(None));
+        assert_eq!(loader.load(0), Ok(None));
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn primed_keys_need_no_query() {
+        let (loader, queries) = counting_loader();
+        loader.prime(7, 70);
+        loader.defer(vec![7]);
+
+        assert_eq!(loader.load(7), Ok(Some(70)));
+        assert_eq!(queries.load(Ordering::SeqCst), 0);
+    }
+}
@@ -8,6 +8,7 @@ use crate::handlers::register;
 
 mod db;
 mod handlers;
+mod loader;
 mod schemas;
 
 #[loony::main]
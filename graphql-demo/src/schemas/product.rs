@@ -1,10 +1,14 @@
-use mysql::{from_row, params, Error as DBError, Row};
+use std::collections::HashMap;
 
+use juniper::FieldResult;
+use mysql::{from_row, Error as DBError};
+
+use crate::db::{in_list, Pool};
 use crate::schemas::root::Context;
 use crate::schemas::user::User;
 
 /// Product
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct Product {
     pub id: String,
     pub user_id: String,
@@ -27,19 +31,34 @@ impl Product {
         self.price
     }
 
-    fn user(&self, context: &Context) -> Option<User> {
-        let mut conn = context.dbpool.get().unwrap();
-        let user: Result<Option<Row>, DBError> = conn.first_exec(
-            "SELECT * FROM user WHERE id=:id",
-            params! {"id" => &self.user_id},
-        );
-        if let Err(err) = user {
-            None
-        } else {
-            let (id, name, email) = from_row(user.unwrap().unwrap());
-            Some(User { id, name, email })
-        }
+    fn user(&self, context: &Context) -> FieldResult<Option<User>> {
+        Ok(context.users.load(self.user_id.clone())?)
+    }
+}
+
+/// The products of the given users by user id, in one query.
+pub fn products_by_user(
+    pool: &Pool,
+    user_ids: &[String],
+) -> Result<HashMap<String, Vec<Product>>, DBError> {
+    let mut conn = pool.get().unwrap();
+    let (in_ids, params) = in_list(user_ids);
+    let result = conn.prep_exec(
+        format!("SELECT * FROM product WHERE user_id IN {}", in_ids),
+        params,
+    )?;
+
+    let mut products: HashMap<String, Vec<Product>> = HashMap::new();
+    for row in result {
+        let (id, user_id, name, price): (_, String, _, _) = from_row(row?);
+        products.entry(user_id.clone()).or_default().push(Product {
+            id,
+            user_id,
+            name,
+            price,
+        });
     }
+    Ok(products)
 }
 
 #[derive(GraphQLInputObject)]
@@ -1,13 +1,56 @@
+use std::collections::HashMap;
+
 use juniper::{FieldError, FieldResult, RootNode};
 use mysql::{from_row, params, Error as DBError, Row};
 
 use crate::db::Pool;
+use crate::loader::Loader;
 
-use super::product::{Product, ProductInput};
-use super::user::{User, UserInput};
+use super::product::{products_by_user, Product, ProductInput};
+use super::user::{users_by_id, User, UserInput};
 
+/// Made anew for every request, so the loaders only cache for one.
 pub struct Context {
     pub dbpool: Pool,
+    /// users by id
+    pub users: Loader<String, User, DBError>,
+    /// products by user id
+    pub products: Loader<String, Vec<Product>, DBError>,
+}
+
+impl Context {
+    pub fn new(dbpool: Pool) -> Self {
+        let (users_pool, products_pool) = (dbpool.clone(), dbpool.clone());
+        Context::with_batches(
+            dbpool,
+            move |ids: &[String]| users_by_id(&users_pool, ids),
+            move |ids: &[String]| products_by_user(&products_pool, ids),
+        )
+    }
+
+    /// A context whose loaders fetch with the given batch functions instead
+    /// of querying `dbpool`.
+    pub fn with_batches<U, P>(dbpool: Pool, users: U, products: P) -> Self
+    where
+        U: Fn(&[String]) -> Result<HashMap<String, User>, DBError> + Send + 'static,
+        P: Fn(&[String]) -> Result<HashMap<String, Vec<Product>>, DBError>
+            + Send
+            + 'static,
+    {
+        Context {
+            dbpool,
+            users: Loader::new(users),
+            products: Loader::new(products),
+        }
+    }
+
+    /// Queues what the fields of these products will ask for, their users
+    /// and the users' products: `products { user { products } }`.
+    pub fn products_loaded(&self, products: &[Product]) {
+        let user_ids = products.iter().map(|product| product.user_id.clone());
+        self.users.defer(user_ids.clone());
+        self.products.defer(user_ids);
+    }
 }
 
 impl juniper::Context for Context {}
@@ -28,9 +71,16 @@ impl QueryRoot {
                         let (id, name, email) = from_row(row);
                         User { id, name, email }
                     })
-                    .collect()
+                    .collect::<Vec<_>>()
             })
             .unwrap();
+        // `users { products }` asks for the products of all of them
+        context
+            .products
+            .defer(users.iter().map(|user| user.id.clone()));
+        for user in &users {
+            context.users.prime(user.id.clone(), user.clone());
+        }
         Ok(users)
     }
 
@@ -69,9 +119,10 @@ impl QueryRoot {
                             price,
                         }
                     })
-                    .collect()
+                    .collect::<Vec<_>>()
             })
             .unwrap();
+        context.products_loaded(&products);
         Ok(products)
     }
 
@@ -173,3 +224,98 @@ pub type Schema = RootNode<'static, QueryRoot, MutationRoot>;
 pub fn create_schema() -> Schema {
     Schema::new(QueryRoot, MutationRoot)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use r2d2_mysql::mysql::OptsBuilder;
+    use r2d2_mysql::MysqlConnectionManager;
+
+    use super::*;
+
+    /// 100 products of 10 users.
+    fn catalog() -> Vec<Product> {
+        (0..100)
+            .map(|i| Product {
+                id: i.to_string(),
+                user_id: format!("user{}", i % 10),
+                name: format!("product{}", i),
+                price: 1.0,
+            })
+            .collect()
+    }
+
+    /// `QueryRoot` with the products of `catalog` instead of the database.
+    struct TestRoot;
+
+    #[juniper::object(Context = Context)]
+    impl TestRoot {
+        fn products(context: &Context) -> Vec<Product> {
+            let products = catalog();
+            context.products_loaded(&products);
+            products
+        }
+    }
+
+    #[test]
+    fn nested_lists_take_one_query_per_level() {
+        let user_queries = Arc::new(AtomicUsize::new(0));
+        let product_queries = Arc::new(AtomicUsize::new(0));
+        let (users, products) = (user_queries.clone(), product_queries.clone());
+        // never connects, only the batch functions are used
+        let pool = r2d2::Pool::builder()
+            .build_unchecked(MysqlConnectionManager::new(OptsBuilder::new()));
+        let context = Context::with_batches(
+            pool,
+            move |ids: &[String]| {
+                users.fetch_add(1, Ordering::SeqCst);
+                Ok(ids
+                    .iter()
+                    .map(|id| {
+                        let user = User {
+                            id: id.clone(),
+                            name: id.clone(),
+                            email: format!("{}@example.com", id),
+                        };
+                        (id.clone(), user)
+                    })
+                    .collect())
+            },
+            move |ids: &[String]| {
+                products.fetch_add(1, Ordering::SeqCst);
+                let mut by_user: HashMap<String, Vec<Product>> = HashMap::new();
+                for product in catalog() {
+                    if ids.contains(&product.user_id) {
+                        by_user
+                            .entry(product.user_id.clone())
+                            .or_default()
+                            .push(product);
+                    }
+                }
+                Ok(by_user)
+            },
+        );
+        let schema = RootNode::new(TestRoot, MutationRoot);
+
+        let (result, errors) = juniper::execute(
+            "{ products { name user { name products { name user { name } } } } }",
+            None,
+            &schema,
+            &juniper::Variables::new(),
+            &context,
+        )
+        .unwrap();
+
+        assert!(errors.is_empty());
+        let products = result
+            .as_object_value()
+            .and_then(|root| root.get_field_value("products"))
+            .and_then(|products| products.as_list_value())
+            .unwrap();
+        assert_eq!(products.len(), 100);
+        assert_eq!(user_queries.load(Ordering::SeqCst), 1);
+        assert_eq!(product_queries.load(Ordering::SeqCst), 1);
+    }
+}
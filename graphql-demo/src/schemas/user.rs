@@ -1,10 +1,14 @@
-use mysql::{from_row, params};
+use std::collections::HashMap;
 
+use juniper::FieldResult;
+use mysql::{from_row, Error as DBError};
+
+use crate::db::{in_list, Pool};
 use crate::schemas::product::Product;
 use crate::schemas::root::Context;
 
 /// User
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct User {
     pub id: String,
     pub name: String,
@@ -30,29 +34,29 @@ impl User {
         &self.email
     }
 
-    fn products(&self, context: &Context) -> Vec<Product> {
-        let mut conn = context.dbpool.get().unwrap();
-
-        conn.prep_exec(
-            "select * from product where user_id=:user_id",
-            params! {
-                "user_id" => &self.id
-            },
-        )
-        .map(|result| {
-            result
-                .map(|x| x.unwrap())
-                .map(|mut row| {
-                    let (id, user_id, name, price) = from_row(row);
-                    Product {
-                        id,
-                        user_id,
-                        name,
-                        price,
-                    }
-                })
-                .collect()
-        })
-        .unwrap()
+    fn products(&self, context: &Context) -> FieldResult<Vec<Product>> {
+        // the products' `user` is this one
+        context.users.prime(self.id.clone(), self.clone());
+        let products = context.products.load(self.id.clone())?.unwrap_or_default();
+        context.products_loaded(&products);
+        Ok(products)
+    }
+}
+
+/// The users with the given ids, in one query.
+pub fn users_by_id(
+    pool: &Pool,
+    ids: &[String],
+) -> Result<HashMap<String, User>, DBError> {
+    let mut conn = pool.get().unwrap();
+    let (in_ids, params) = in_list(ids);
+    let result =
+        conn.prep_exec(format!("SELECT * FROM user WHERE id IN {}", in_ids), params)?;
+
+    let mut users = HashMap::new();
+    for row in result {
+        let (id, name, email): (String, _, _) = from_row(row?);
+        users.insert(id.clone(), User { id, name, email });
     }
+    Ok(users)
 }
@@ -10,6 +10,7 @@ use loony::util::Bytes;
 use futures::{Future, FutureExt};
 use loony::rt::time_driver::sleep;
 use loony::web::{self, middleware, App, Error, HttpResponse};
+use request_limits::{ConcurrencyLimit, Timeout};
 use serde_json::Value;
 
 #[allow(dead_code)]
@@ -135,12 +136,20 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let app_state = web::types::Data::new(AppState::new(RwLock::new(ObjNetwork::new())));
+    let limit = ConcurrencyLimit::new(64).queue(256);
 
     web::server(move || {
         App::new()
             .app_data(app_state.clone())
             .wrap(middleware::Logger::default())
-            .service(web::resource("/").route(web::post().to(rpc_handler)))
+            .service(
+                web::resource("/")
+                    // `wait` sleeps as long as the caller asks for, it is
+                    // async so the timeout cancels it and frees its slot
+                    .wrap(Timeout::new(Duration::from_secs(30)))
+                    .wrap(limit.clone())
+                    .route(web::post().to(rpc_handler)),
+            )
     })
     .bind("127.0.0.1:8080")
     .unwrap()
@@ -0,0 +1,350 @@
+use std::cell::{Cell, RefCell};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use futures::stream::StreamExt;
+use loony::http::body::{Body, BodySize, MessageBody, ResponseBody};
+use loony::http::header::{HeaderName, HeaderValue};
+use loony::http::Payload;
+use loony::util::Bytes;
+use loony::web::{Error, WebRequest, WebResponse};
+use loony::{Service, Transform};
+use loony_identity::RequestIdentity;
+use serde_json::json;
+
+const REQUEST_ID: &str = "x-request-id";
+
+/// Destination for access log lines, one JSON object per line.
+pub trait Sink: Send + Sync {
+    fn write(&self, line: &str);
+}
+
+/// Writes access log lines through the `log` crate, target `access_log`.
+pub struct LogSink;
+
+impl Sink for LogSink {
+    fn write(&self, line: &str) {
+        log::info!(target: "access_log", "{}", line);
+    }
+}
+
+/// Appends access log lines to a file and rotates it once it grows past
+/// `max_bytes`, keeping `keep` old files as `<path>.1` .. `<path>.<keep>`.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: Mutex<(File, u64)>,
+}
+
+impl RotatingFile {
+    pub fn new<P: Into<PathBuf>>(
+        path: P,
+        max_bytes: u64,
+        keep: usize,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_bytes,
+            keep,
+            file: Mutex::new((file, len)),
+        })
+    }
+
+    fn rotate(&self) -> io::Result<File> {
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+}
+
+impl Sink for RotatingFile {
+    fn write(&self, line: &str) {
+        let mut guard = self.file.lock().unwrap();
+        let len = line.len() as u64 + 1;
+
+        if guard.1 > 0 && guard.1 + len > self.max_bytes {
+            match self.rotate() {
+                Ok(file) => *guard = (file, 0),
+                Err(e) => log::error!("Can not rotate access log: {}", e),
+            }
+        }
+        if writeln!(guard.0, "{}", line).is_ok() {
+            guard.1 += len;
+        }
+    }
+}
+
+/// Id of the current request, available from the request extensions.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Lets inner middlewares such as `read_response_body::Logging` attach a
+/// sample of the response body to the access log entry.
+#[derive(Clone, Default)]
+pub struct BodySample(Rc<RefCell<Option<String>>>);
+
+impl BodySample {
+    pub fn set(&self, sample: String) {
+        *self.0.borrow_mut() = Some(sample);
+    }
+}
+
+/// Assigns or propagates an `X-Request-Id` and writes one JSON line per
+/// request to a `Sink`.
+///
+/// Register it inside `IdentityService` so the identity can be logged, and
+/// outside `read_response_body::Logging` so body samples end up in the entry.
+pub struct AccessLog {
+    sink: Arc<dyn Sink>,
+}
+
+impl AccessLog {
+    pub fn new(sink: Arc<dyn Sink>) -> Self {
+        AccessLog { sink }
+    }
+
+    /// Log through `env_logger` or whatever `log` backend is installed.
+    pub fn log() -> Self {
+        AccessLog::new(Arc::new(LogSink))
+    }
+}
+
+impl<S, Err> Transform<S> for AccessLog
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+{
+    type Service = AccessLogMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        AccessLogMiddleware {
+            service,
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+    sink: Arc<dyn Sink>,
+}
+
+impl<S, Err> Service for AccessLogMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = AccessLogResponse<S>;
+
+    fn poll_ready(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: Self::Request) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid_id(id))
+            .map(String::from)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        // count the request body as the handler reads it
+        let received = Rc::new(Cell::new(0));
+        let counter = received.clone();
+        let payload = req.take_payload().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                counter.set(counter.get() + chunk.len() as u64);
+            }
+        });
+        req.set_payload(Payload::Stream(Box::pin(payload)));
+
+        let sample = BodySample::default();
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+        req.extensions_mut().insert(sample.clone());
+
+        let entry = Entry {
+            sink: self.sink.clone(),
+            start: Instant::now(),
+            request_id,
+            method: req.method().to_string(),
+            path: req.path().to_owned(),
+            peer: req.peer_addr().map(|addr| addr.to_string()),
+            identity: None,
+            received,
+            sample,
+        };
+
+        AccessLogResponse {
+            fut: self.service.call(req),
+            entry: Some(entry),
+        }
+    }
+}
+
+#[pin_project::pin_project]
+pub struct AccessLogResponse<S>
+where
+    S: Service,
+{
+    #[pin]
+    fut: S::Future,
+    entry: Option<Entry>,
+}
+
+impl<S, Err> Future for AccessLogResponse<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+{
+    type Output = Result<WebResponse, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let res = futures::ready!(this.fut.poll(cx));
+        let mut entry = this.entry.take().expect("polled after completion");
+
+        let mut res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                entry.emit(e.as_response_error().status_code().as_u16(), 0);
+                return Poll::Ready(Err(e));
+            }
+        };
+
+        // read late, the handler may just have logged the user in
+        entry.identity = res.request().get_identity();
+        if let Ok(value) = HeaderValue::from_str(&entry.request_id) {
+            res.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID), value);
+        }
+
+        let status = res.status().as_u16();
+        Poll::Ready(Ok(res.map_body(move |_, body| {
+            Body::from_message(AccessBody {
+                body,
+                status,
+                sent: 0,
+                entry: Some(entry),
+            })
+            .into()
+        })))
+    }
+}
+
+struct Entry {
+    sink: Arc<dyn Sink>,
+    start: Instant,
+    request_id: String,
+    method: String,
+    path: String,
+    peer: Option<String>,
+    identity: Option<String>,
+    received: Rc<Cell<u64>>,
+    sample: BodySample,
+}
+
+impl Entry {
+    fn emit(self, status: u16, sent: u64) {
+        let mut line = json!({
+            "request_id": self.request_id,
+            "method": self.method,
+            "path": self.path,
+            "status": status,
+            "latency_ms": self.start.elapsed().as_secs_f64() * 1000.0,
+            "request_bytes": self.received.get(),
+            "response_bytes": sent,
+            "peer": self.peer,
+            "identity": self.identity,
+        });
+        if let Some(sample) = self.sample.0.borrow_mut().take() {
+            line["response_sample"] = sample.into();
+        }
+        self.sink.write(&line.to_string());
+    }
+}
+
+/// Counts the response bytes and emits the entry once the body is done.
+struct AccessBody {
+    body: ResponseBody<Body>,
+    status: u16,
+    sent: u64,
+    entry: Option<Entry>,
+}
+
+impl AccessBody {
+    fn finish(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            entry.emit(self.status, self.sent);
+        }
+    }
+}
+
+// the client went away before the body was sent completely, drop the inner
+// body first so its logger can still hand over the sample
+impl Drop for AccessBody {
+    fn drop(&mut self) {
+        self.body = ResponseBody::Other(Body::None);
+        self.finish();
+    }
+}
+
+impl MessageBody for AccessBody {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next_chunk(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Box<dyn std::error::Error>>>> {
+        match self.body.poll_next_chunk(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.sent += chunk.len() as u64;
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(None) => {
+                self.finish();
+                Poll::Ready(None)
+            }
+            other => other,
+        }
+    }
+}
+
+// Incoming ids are copied into logs and headers, keep them short and plain.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
@@ -0,0 +1,387 @@
+use std::future::Future;
+use std::io::{self, Write};
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use loony::http::body::{Body, BodySize, MessageBody, ResponseBody};
+use loony::http::header::{self, HeaderValue};
+use loony::http::StatusCode;
+use loony::util::Bytes;
+use loony::web::{Error, WebRequest, WebResponse};
+use loony::{Service, Transform};
+
+/// Compresses response bodies with gzip, deflate or brotli, whichever the
+/// client prefers in `Accept-Encoding`.
+pub struct Compress {
+    min_size: u64,
+}
+
+impl Compress {
+    pub fn new() -> Self {
+        Compress { min_size: 1024 }
+    }
+
+    /// Bodies with a known size below this are sent as is, 1kb by default.
+    /// Streaming bodies are always compressed.
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+}
+
+impl Default for Compress {
+    fn default() -> Self {
+        Compress::new()
+    }
+}
+
+impl<S, Err> Transform<S> for Compress
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+{
+    type Service = CompressMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        CompressMiddleware {
+            service,
+            min_size: self.min_size,
+        }
+    }
+}
+
+pub struct CompressMiddleware<S> {
+    service: S,
+    min_size: u64,
+}
+
+impl<S, Err> Service for CompressMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = CompressResponse<S>;
+
+    fn poll_ready(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let encoding = req
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Encoding::negotiate);
+
+        CompressResponse {
+            fut: self.service.call(req),
+            encoding,
+            min_size: self.min_size,
+        }
+    }
+}
+
+#[pin_project::pin_project]
+pub struct CompressResponse<S>
+where
+    S: Service,
+{
+    #[pin]
+    fut: S::Future,
+    encoding: Option<Encoding>,
+    min_size: u64,
+}
+
+impl<S, Err> Future for CompressResponse<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+{
+    type Output = Result<WebResponse, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut res = futures::ready!(this.fut.poll(cx))?;
+
+        // the response depends on Accept-Encoding, even when it isn't compressed
+        if !varies_on_encoding(&res) {
+            res.headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+
+        let encoding = match *this.encoding {
+            Some(encoding) if should_compress(&res, *this.min_size) => encoding,
+            _ => return Poll::Ready(Ok(res)),
+        };
+
+        let headers = res.headers_mut();
+        headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        headers.remove(header::CONTENT_LENGTH);
+
+        Poll::Ready(Ok(res.map_body(move |_, body| {
+            Body::from_message(CompressedBody {
+                body,
+                encoder: Some(Encoder::new(encoding)),
+            })
+            .into()
+        })))
+    }
+}
+
+fn varies_on_encoding(res: &WebResponse) -> bool {
+    res.headers()
+        .get_all(header::VARY)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|name| name == "*" || name.eq_ignore_ascii_case("accept-encoding"))
+}
+
+fn should_compress(res: &WebResponse, min_size: u64) -> bool {
+    if res.headers().contains_key(header::CONTENT_ENCODING)
+        || res.status() == StatusCode::NO_CONTENT
+        || res.status() == StatusCode::NOT_MODIFIED
+    {
+        return false;
+    }
+
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    if is_compressed_type(content_type) {
+        return false;
+    }
+
+    match res.response().body().size() {
+        BodySize::Sized(size) => size >= min_size,
+        BodySize::Stream => true,
+        _ => false,
+    }
+}
+
+// formats that are compressed already and won't get any smaller
+fn is_compressed_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    (mime.starts_with("image/") && mime != "image/svg+xml")
+        || mime.starts_with("audio/")
+        || mime.starts_with("video/")
+        || mime.starts_with("font/woff")
+        || matches!(
+            mime,
+            "application/zip"
+                | "application/gzip"
+                | "application/x-gzip"
+                | "application/x-bzip2"
+                | "application/x-7z-compressed"
+                | "application/x-rar-compressed"
+        )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Picks the supported encoding with the highest q-value, preferring
+    /// brotli over gzip over deflate on ties. `*` stands for gzip unless
+    /// gzip is listed itself, so `gzip;q=0, *` rules it out.
+    fn negotiate(accept: &str) -> Option<Encoding> {
+        let mut listed: Vec<(Encoding, f32)> = Vec::new();
+        let mut wildcard = None;
+
+        for item in accept.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .filter_map(|q| q.trim().parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+
+            match name.as_str() {
+                "br" => listed.push((Encoding::Brotli, q)),
+                "gzip" => listed.push((Encoding::Gzip, q)),
+                "deflate" => listed.push((Encoding::Deflate, q)),
+                "*" => wildcard = Some(q),
+                _ => {}
+            }
+        }
+        if let Some(q) = wildcard {
+            if !listed
+                .iter()
+                .any(|(encoding, _)| *encoding == Encoding::Gzip)
+            {
+                listed.push((Encoding::Gzip, q));
+            }
+        }
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for (encoding, q) in listed {
+            if q <= 0.0 {
+                continue;
+            }
+            let better = match best {
+                Some((current, best_q)) => {
+                    q > best_q || (q == best_q && encoding.rank() < current.rank())
+                }
+                None => true,
+            };
+            if better {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn rank(self) -> u8 {
+        match self {
+            Encoding::Brotli => 0,
+            Encoding::Gzip => 1,
+            Encoding::Deflate => 2,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    // HTTP "deflate" is the zlib format
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(
+                brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22),
+            )),
+            Encoding::Gzip => {
+                Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default()))
+            }
+            Encoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default()))
+            }
+        }
+    }
+
+    /// Compresses a chunk and flushes, so streams such as server-sent
+    /// events reach the client right away.
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let buf = match self {
+            Encoder::Brotli(w) => {
+                w.write_all(chunk)?;
+                w.flush()?;
+                w.get_mut()
+            }
+            Encoder::Gzip(w) => {
+                w.write_all(chunk)?;
+                w.flush()?;
+                w.get_mut()
+            }
+            Encoder::Deflate(w) => {
+                w.write_all(chunk)?;
+                w.flush()?;
+                w.get_mut()
+            }
+        };
+        Ok(Bytes::from(mem::take(buf)))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let buf = match self {
+            Encoder::Brotli(w) => w.into_inner(),
+            Encoder::Gzip(w) => w.finish()?,
+            Encoder::Deflate(w) => w.finish()?,
+        };
+        Ok(Bytes::from(buf))
+    }
+}
+
+pub struct CompressedBody {
+    body: ResponseBody<Body>,
+    encoder: Option<Encoder>,
+}
+
+impl MessageBody for CompressedBody {
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next_chunk(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Box<dyn std::error::Error>>>> {
+        loop {
+            match self.body.poll_next_chunk(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    let encoder = match self.encoder.as_mut() {
+                        Some(encoder) => encoder,
+                        None => return Poll::Ready(None),
+                    };
+                    match encoder.write(&chunk) {
+                        Ok(out) if out.is_empty() => continue,
+                        Ok(out) => return Poll::Ready(Some(Ok(out))),
+                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    return match self.encoder.take().map(Encoder::finish) {
+                        Some(Ok(out)) if !out.is_empty() => Poll::Ready(Some(Ok(out))),
+                        Some(Err(e)) => Poll::Ready(Some(Err(e.into()))),
+                        _ => Poll::Ready(None),
+                    };
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_encoding() {
+        assert_eq!(
+            Encoding::negotiate("gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            Encoding::negotiate("gzip;q=1.0, br;q=0.5"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            Encoding::negotiate("deflate, br;q=0"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(Encoding::negotiate("identity"), None);
+        assert_eq!(Encoding::negotiate("*"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("gzip;q=0, *"), None);
+        assert_eq!(Encoding::negotiate("*, gzip;q=0"), None);
+        assert_eq!(
+            Encoding::negotiate("gzip;q=0, *, deflate;q=0.5"),
+            Some(Encoding::Deflate)
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::future::ok;
+use loony::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use loony::http::Method;
+use loony::web::{Error, HttpResponse, WebRequest, WebResponse};
+use loony::{Service, Transform};
+
+enum AllowedOrigin {
+    Exact(String),
+    /// `https://*.example.com`, the `*` stands for one or more subdomains.
+    Wildcard(String, String),
+    Predicate(Rc<dyn Fn(&str) -> bool>),
+}
+
+impl AllowedOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Exact(allowed) => allowed == origin,
+            AllowedOrigin::Wildcard(prefix, suffix) => {
+                origin.len() > prefix.len() + suffix.len()
+                    && origin.starts_with(prefix.as_str())
+                    && origin.ends_with(suffix.as_str())
+                    && origin[prefix.len()..origin.len() - suffix.len()]
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            }
+            AllowedOrigin::Predicate(f) => f(origin),
+        }
+    }
+}
+
+/// Cross-origin resource sharing. Answers preflight requests and adds the
+/// `Access-Control-*` headers to responses for allowed origins. Preflights
+/// for other origins, methods or headers get a `403`. Other requests from
+/// other origins, which includes same-origin `POST`s from browsers, are
+/// passed on without the headers, so browsers keep their scripts from
+/// reading the response.
+pub struct Cors {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    origins: Vec<AllowedOrigin>,
+    any_origin: bool,
+    methods: Vec<Method>,
+    headers: Option<Vec<HeaderName>>,
+    expose: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<u32>,
+}
+
+impl Cors {
+    /// Allows no origins and `GET`, `HEAD` and `POST` with any request header.
+    pub fn new() -> Self {
+        Cors {
+            inner: Rc::new(Inner {
+                origins: Vec::new(),
+                any_origin: false,
+                methods: vec![Method::GET, Method::HEAD, Method::POST],
+                headers: None,
+                expose: Vec::new(),
+                credentials: false,
+                max_age: None,
+            }),
+        }
+    }
+
+    /// Allow an exact origin such as `https://app.example.com`, or every
+    /// subdomain with `https://*.example.com`.
+    pub fn allowed_origin(mut self, origin: &str) -> Self {
+        let allowed = match origin.split_once('*') {
+            Some((prefix, suffix)) => {
+                AllowedOrigin::Wildcard(prefix.to_owned(), suffix.to_owned())
+            }
+            None => AllowedOrigin::Exact(origin.trim_end_matches('/').to_owned()),
+        };
+        self.inner_mut().origins.push(allowed);
+        self
+    }
+
+    pub fn allowed_origin_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> bool + 'static,
+    {
+        self.inner_mut()
+            .origins
+            .push(AllowedOrigin::Predicate(Rc::new(f)));
+        self
+    }
+
+    /// Can't be combined with `supports_credentials`.
+    pub fn allow_any_origin(mut self) -> Self {
+        self.inner_mut().any_origin = true;
+        self
+    }
+
+    pub fn allowed_methods(mut self, methods: Vec<Method>) -> Self {
+        self.inner_mut().methods = methods;
+        self
+    }
+
+    /// Request headers a client may send, any header is allowed by default.
+    pub fn allowed_headers(mut self, headers: Vec<HeaderName>) -> Self {
+        self.inner_mut().headers = Some(headers);
+        self
+    }
+
+    /// Response headers scripts may read besides the simple ones.
+    pub fn expose_headers(mut self, headers: Vec<HeaderName>) -> Self {
+        self.inner_mut().expose = headers;
+        self
+    }
+
+    pub fn supports_credentials(mut self) -> Self {
+        self.inner_mut().credentials = true;
+        self
+    }
+
+    /// How many seconds browsers may cache a preflight response.
+    pub fn max_age(mut self, seconds: u32) -> Self {
+        self.inner_mut().max_age = Some(seconds);
+        self
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Rc::get_mut(&mut self.inner).expect("Cors is configured before use")
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors::new()
+    }
+}
+
+impl<S, Err> Transform<S> for Cors
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Service = CorsMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        // any site could read responses with the user's cookies attached
+        assert!(
+            !(self.inner.any_origin && self.inner.credentials),
+            "Cors: allow_any_origin can't be combined with supports_credentials"
+        );
+        CorsMiddleware {
+            service,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    inner: Rc<Inner>,
+}
+
+impl<S, Err> Service for CorsMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let inner = self.inner.clone();
+        let origin = req
+            .headers()
+            .get(header::ORIGIN)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+
+        let is_preflight = req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+        // non-browser requests don't send an Origin, and other origins just
+        // don't get the headers that would let them read the response
+        let origin = match origin {
+            Some(origin) if inner.origin_allowed(&origin) => origin,
+            // preflights are answered here whatever the origin, the
+            // application never sees them
+            Some(_) if is_preflight => {
+                let res = inner.forbidden("CORS: origin is not allowed");
+                return Box::pin(ok(req.into_response(res)));
+            }
+            _ => {
+                let fut = self.service.call(req);
+                return Box::pin(async move {
+                    let mut res = fut.await?;
+                    inner.vary(res.headers_mut());
+                    Ok(res)
+                });
+            }
+        };
+
+        if is_preflight {
+            let res = inner.preflight(&origin, req.headers());
+            return Box::pin(ok(req.into_response(res)));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let headers = res.headers_mut();
+            inner.allow_origin(&origin, headers);
+            if !inner.expose.is_empty() {
+                headers.insert(
+                    header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                    join(inner.expose.iter().map(HeaderName::as_str)),
+                );
+            }
+            Ok(res)
+        })
+    }
+}
+
+impl Inner {
+    fn origin_allowed(&self, origin: &str) -> bool {
+        self.any_origin || self.origins.iter().any(|allowed| allowed.matches(origin))
+    }
+
+    fn preflight(&self, origin: &str, req_headers: &HeaderMap) -> HttpResponse {
+        let method = req_headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|value| Method::from_bytes(value.as_bytes()).ok());
+        if !matches!(method, Some(ref method) if self.methods.contains(method)) {
+            return self.forbidden("CORS: method is not allowed");
+        }
+
+        let requested = req_headers
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        let requested: Vec<&str> = requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        if let Some(ref allowed) = self.headers {
+            let all_allowed = requested.iter().all(|name| {
+                allowed
+                    .iter()
+                    .any(|allowed| allowed.as_str().eq_ignore_ascii_case(name))
+            });
+            if !all_allowed {
+                return self.forbidden("CORS: header is not allowed");
+            }
+        }
+
+        let mut res = HttpResponse::NoContent().finish();
+        let headers = res.headers_mut();
+        self.allow_origin(origin, headers);
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            join(self.methods.iter().map(Method::as_str)),
+        );
+        if !requested.is_empty() {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                join(requested.into_iter()),
+            );
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        res
+    }
+
+    fn forbidden(&self, reason: &'static str) -> HttpResponse {
+        let mut res = HttpResponse::Forbidden().body(reason);
+        self.vary(res.headers_mut());
+        res
+    }
+
+    fn allow_origin(&self, origin: &str, headers: &mut HeaderMap) {
+        if self.any_origin {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                HeaderValue::from_static("*"),
+            );
+        } else if let Ok(value) = HeaderValue::from_str(origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        }
+        if self.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        self.vary(headers);
+    }
+
+    // The response depends on the Origin unless every origin gets the same
+    // `*`, caches must not hand one origin's answer to another.
+    fn vary(&self, headers: &mut HeaderMap) {
+        if !self.any_origin {
+            headers.append(header::VARY, HeaderValue::from_static("origin"));
+        }
+    }
+}
+
+fn join<'a>(items: impl Iterator<Item = &'a str>) -> HeaderValue {
+    let joined = items.collect::<Vec<_>>().join(", ");
+    HeaderValue::from_str(&joined).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use loony::http::StatusCode;
+    use loony::web::{self, test, App};
+
+    use super::*;
+
+    #[test]
+    fn origins() {
+        let cors = Cors::new()
+            .allowed_origin("http://localhost:3000")
+            .allowed_origin("https://*.example.com")
+            .allowed_origin_fn(|origin| origin.ends_with(".test"));
+
+        assert!(cors.inner.origin_allowed("http://localhost:3000"));
+        assert!(cors.inner.origin_allowed("https://app.example.com"));
+        assert!(cors.inner.origin_allowed("https://a.b.example.com"));
+        assert!(cors.inner.origin_allowed("http://site.test"));
+        assert!(!cors.inner.origin_allowed("https://example.com"));
+        assert!(!cors.inner.origin_allowed("https://evil.com/.example.com"));
+        assert!(!cors.inner.origin_allowed("http://localhost:3001"));
+    }
+
+    #[loony::test]
+    async fn rejected_preflights() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    Cors::new()
+                        .allowed_origin("http://localhost:3000")
+                        .allowed_methods(vec![Method::GET]),
+                )
+                .service(web::resource("/").to(|| async { "app" })),
+        )
+        .await;
+        let preflight = |origin: &str, method: &str| {
+            test::TestRequest::with_uri("/")
+                .method(Method::OPTIONS)
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+                .to_request()
+        };
+
+        // an origin that isn't allowed doesn't reach the application
+        let res =
+            test::call_service(&mut app, preflight("http://evil.com", "GET")).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "origin");
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // neither does an allowed origin asking for a method that isn't
+        let res =
+            test::call_service(&mut app, preflight("http://localhost:3000", "DELETE"))
+                .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "origin");
+
+        let res =
+            test::call_service(&mut app, preflight("http://localhost:3000", "GET"))
+                .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "http://localhost:3000"
+        );
+
+        // plain requests from other origins still go through, without the headers
+        let req = test::TestRequest::with_uri("/")
+            .header(header::ORIGIN, "http://evil.com")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -1,24 +1,106 @@
 #![allow(dead_code, clippy::type_complexity)]
 
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
 use futures::future::FutureExt;
-use loony::{web, Service};
+use loony::http::{header, Method};
+use loony::rt::time_driver::sleep;
+use loony::web::HttpResponse;
+use loony::{http, web, Service};
+use loony_identity::{CookieIdentityPolicy, Identity, IdentityService};
+use loony_session::Session;
 
+mod access_log;
+mod compress;
+mod cors;
+mod rate_limit;
 mod read_request_body;
 mod read_response_body;
 mod redirect;
 mod simple;
 
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const SESSION_ABSOLUTE_TIMEOUT: Duration = Duration::from_secs(8 * 60 * 60);
+
 #[loony::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=debug");
+    std::env::set_var(
+        "RUST_LOG",
+        "actix_web=debug,access_log=info,middleware_example=info",
+    );
     env_logger::init();
 
-    web::server(|| {
+    let keys = cookie_keys::Keyring::from_env().expect("Failed to load cookie keys");
+
+    // created once, so the workers share the counters
+    let limits: Arc<dyn rate_limit::Store> = match std::env::var("RATE_LIMIT_DB") {
+        Ok(path) => {
+            let pool = r2d2::Pool::new(r2d2_sqlite::SqliteConnectionManager::file(path))
+                .expect("Failed to open the rate limit database");
+            Arc::new(
+                rate_limit::SqliteStore::new(pool)
+                    .expect("Failed to create the rate limit table"),
+            )
+        }
+        Err(_) => Arc::new(rate_limit::MemoryStore::default()),
+    };
+    let sessions: Arc<dyn session_store::SessionStore> =
+        Arc::new(session_store::MemoryStore::default());
+
+    let (store, counters) = (sessions.clone(), limits.clone());
+    loony::rt::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(60)).await;
+            let counters = counters.clone();
+            let removed =
+                web::block(move || rate_limit::remove_expired(&*counters)).await;
+            if let Err(e) = removed {
+                log::error!("Removing expired rate limits failed: {:?}", e);
+            }
+            let removed = session_store::remove_expired(
+                &*store,
+                SESSION_IDLE_TIMEOUT,
+                SESSION_ABSOLUTE_TIMEOUT,
+            )
+            .await;
+            if let Err(e) = removed {
+                log::error!("Removing expired sessions failed: {:?}", e);
+            }
+        }
+    });
+
+    web::server(move || {
         web::App::new()
-            .wrap(redirect::CheckLogin)
-            .wrap(read_request_body::Logging)
+            .app_data(web::types::Data::new(sessions.clone()))
+            .wrap(redirect::RequireAuth::new("/login").public("/logout"))
+            .wrap(read_request_body::Logging::new().redact("password"))
             .wrap(read_response_body::Logging)
             .wrap(simple::SayHi)
+            .wrap(compress::Compress::new())
+            .wrap(access_log::AccessLog::log())
+            .wrap(IdentityService::new(
+                CookieIdentityPolicy::new(keys.active())
+                    .name("auth-example")
+                    .secure(false),
+            ))
+            .wrap(
+                session_store::ServerSession::new(sessions.clone())
+                    .idle_timeout(SESSION_IDLE_TIMEOUT)
+                    .absolute_timeout(SESSION_ABSOLUTE_TIMEOUT)
+                    .secure(false),
+            )
+            .wrap(keys.rotation().private("auth-example"))
+            // outermost, preflight requests carry no cookies
+            .wrap(
+                cors::Cors::new()
+                    .allowed_origin("http://localhost:3000")
+                    .allowed_methods(vec![Method::GET, Method::POST, Method::DELETE])
+                    .allowed_headers(vec![header::CONTENT_TYPE, header::ACCEPT])
+                    .supports_credentials()
+                    .max_age(3600),
+            )
             // .wrap_fn(|req, srv| {
             //     println!("Hi from start. You requested: {}", req.path());
 
@@ -27,9 +109,21 @@ async fn main() -> std::io::Result<()> {
             //         res
             //     })
             // })
-            .service(web::resource("/login").to(|| async {
-                "You are on /login. Go to src/redirect.rs to change this behavior."
-            }))
+            .service(
+                web::resource("/login")
+                    // slow down password guessing
+                    .wrap(
+                        rate_limit::RateLimit::new(limits.clone())
+                            .name("login")
+                            .quota(5, Duration::from_secs(60)),
+                    )
+                    .route(web::get().to(|| async {
+                        "You are on /login. POST here to log in, see src/redirect.rs."
+                    }))
+                    .route(web::post().to(login)),
+            )
+            .service(web::resource("/logout").to(logout))
+            .service(web::resource("/logout-everywhere").to(logout_everywhere))
             .service(web::resource("/").to(|| async {
                 "Hello, middleware! Check the console where the server is run."
             }))
@@ -38,3 +132,48 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+async fn login(
+    id: Identity,
+    session: Session,
+    query: web::types::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, web::Error> {
+    id.remember("user1".to_owned());
+    session.set("user_id", "user1")?;
+    // a new id on login, so a session id planted before can't be taken over
+    session.renew();
+
+    // never redirect to whatever `next` says without checking it first
+    let next = redirect::safe_next(query.get("next").map(String::as_str)).unwrap_or("/");
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, next)
+        .finish())
+}
+
+async fn logout(id: Identity, session: Session) -> HttpResponse {
+    id.forget();
+    session.purge();
+    HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish()
+}
+
+/// Ends the sessions of the user in every browser.
+async fn logout_everywhere(
+    id: Identity,
+    session: Session,
+    sessions: web::types::Data<Arc<dyn session_store::SessionStore>>,
+) -> Result<HttpResponse, web::Error> {
+    if let Some(user) = id.identity() {
+        sessions
+            .delete_user(&user)
+            .await
+            .map_err(|e| web::error::ErrorInternalServerError(e.to_string()))?;
+    }
+    id.forget();
+    // removes the cookie, and keeps this response from saving the session again
+    session.purge();
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
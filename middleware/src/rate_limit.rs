@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use loony::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use loony::http::RequestHead;
+use loony::web::{self, Error, ErrorRenderer, HttpResponse, WebRequest, WebResponse};
+use loony::{Service, Transform};
+use loony_identity::RequestIdentity;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What a store keeps per key. The meaning depends on the algorithm: the
+/// hit count and window start, or the tokens left and the last refill.
+#[derive(Clone, Copy, Debug)]
+pub struct State {
+    pub value: f64,
+    pub stamp: f64,
+    /// From then on the state is the same as none at all and can go.
+    pub expires: f64,
+}
+
+/// Keeps rate limit state. `update` has to be atomic per key, the state may
+/// be shared by all workers.
+pub trait Store: Send + Sync {
+    fn update(
+        &self,
+        key: &str,
+        f: &dyn Fn(Option<State>) -> (State, Decision),
+    ) -> Result<Decision, StoreError>;
+
+    /// Deletes the state that expired before `now`, returns how much.
+    fn remove_expired(&self, now: f64) -> Result<u64, StoreError>;
+}
+
+/// Deletes expired state, run it periodically.
+pub fn remove_expired(store: &dyn Store) -> Result<u64, StoreError> {
+    store.remove_expired(now())
+}
+
+/// Keeps the state in process memory, shared between the workers.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<HashMap<String, State>>,
+}
+
+impl Store for MemoryStore {
+    fn update(
+        &self,
+        key: &str,
+        f: &dyn Fn(Option<State>) -> (State, Decision),
+    ) -> Result<Decision, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let (new, decision) = f(state.get(key).copied());
+        state.insert(key.to_owned(), new);
+        Ok(decision)
+    }
+
+    fn remove_expired(&self, now: f64) -> Result<u64, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.len();
+        state.retain(|_, state| state.expires > now);
+        Ok((before - state.len()) as u64)
+    }
+}
+
+/// Keeps the state in SQLite, so it survives restarts and can be shared by
+/// several server processes.
+pub struct SqliteStore {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+}
+
+impl SqliteStore {
+    pub fn new(pool: r2d2::Pool<SqliteConnectionManager>) -> Result<Self, StoreError> {
+        pool.get()?.execute_batch(
+            "CREATE TABLE IF NOT EXISTS rate_limits (
+                key TEXT PRIMARY KEY,
+                value REAL NOT NULL,
+                stamp REAL NOT NULL,
+                expires REAL NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS rate_limits_expires ON rate_limits (expires);",
+        )?;
+        Ok(SqliteStore { pool })
+    }
+}
+
+impl Store for SqliteStore {
+    fn update(
+        &self,
+        key: &str,
+        f: &dyn Fn(Option<State>) -> (State, Decision),
+    ) -> Result<Decision, StoreError> {
+        let mut conn = self.pool.get()?;
+        // take the write lock up front so concurrent hits can't both read the old state
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let old = tx
+            .query_row(
+                "SELECT value, stamp, expires FROM rate_limits WHERE key = ?1",
+                params![key],
+                |row| {
+                    Ok(State {
+                        value: row.get(0)?,
+                        stamp: row.get(1)?,
+                        expires: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        let (new, decision) = f(old);
+        tx.execute(
+            "INSERT OR REPLACE INTO rate_limits (key, value, stamp, expires)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![key, new.value, new.stamp, new.expires],
+        )?;
+        tx.commit()?;
+
+        Ok(decision)
+    }
+
+    fn remove_expired(&self, now: f64) -> Result<u64, StoreError> {
+        let removed = self
+            .pool
+            .get()?
+            .execute("DELETE FROM rate_limits WHERE expires <= ?1", params![now])?;
+        Ok(removed as u64)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Algorithm {
+    /// At most `limit` requests per `period`, counted in fixed windows.
+    FixedWindow,
+    /// Bursts of up to `limit` requests, refilled evenly over `period`.
+    TokenBucket,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the limit resets, or until the next request is allowed.
+    pub reset: u64,
+}
+
+impl Algorithm {
+    fn apply(
+        self,
+        state: Option<State>,
+        limit: u32,
+        period: Duration,
+        now: f64,
+    ) -> (State, Decision) {
+        let max = f64::from(limit);
+        let period = period.as_secs_f64();
+
+        match self {
+            Algorithm::FixedWindow => {
+                let (start, count) = match state {
+                    Some(s) if now - s.stamp < period => (s.stamp, s.value + 1.0),
+                    _ => (now, 1.0),
+                };
+                let decision = Decision {
+                    allowed: count <= max,
+                    limit,
+                    remaining: (max - count).max(0.0) as u32,
+                    reset: (start + period - now).ceil() as u64,
+                };
+                (
+                    State {
+                        value: count,
+                        stamp: start,
+                        expires: start + period,
+                    },
+                    decision,
+                )
+            }
+            Algorithm::TokenBucket => {
+                let rate = max / period;
+                let tokens = match state {
+                    Some(s) => (s.value + (now - s.stamp) * rate).min(max),
+                    None => max,
+                };
+                let (allowed, tokens, reset) = if tokens >= 1.0 {
+                    (true, tokens - 1.0, (max - tokens + 1.0) / rate)
+                } else {
+                    (false, tokens, (1.0 - tokens) / rate)
+                };
+                let decision = Decision {
+                    allowed,
+                    limit,
+                    remaining: tokens as u32,
+                    reset: reset.ceil() as u64,
+                };
+                (
+                    State {
+                        value: tokens,
+                        stamp: now,
+                        // the bucket is full again
+                        expires: now + (max - tokens) / rate,
+                    },
+                    decision,
+                )
+            }
+        }
+    }
+}
+
+impl Decision {
+    fn set_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(
+            HeaderName::from_static("ratelimit-limit"),
+            HeaderValue::from(self.limit),
+        );
+        headers.insert(
+            HeaderName::from_static("ratelimit-remaining"),
+            HeaderValue::from(self.remaining),
+        );
+        headers.insert(
+            HeaderName::from_static("ratelimit-reset"),
+            HeaderValue::from(self.reset),
+        );
+    }
+}
+
+/// What a client is identified by.
+#[derive(Clone)]
+pub enum Key {
+    PeerIp,
+    /// The `loony_identity` identity, anonymous callers fall back to the peer ip.
+    Identity,
+    /// Requests the function returns `None` for are not limited.
+    Custom(Rc<dyn Fn(&RequestHead) -> Option<String>>),
+}
+
+/// Rejects clients that go over their quota with `429 Too Many Requests`.
+///
+/// Wrap a `web::scope` or resource to give it its own limit, the `name`
+/// keeps the counters of different limits apart.
+pub struct RateLimit {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    store: Arc<dyn Store>,
+    name: String,
+    limit: u32,
+    period: Duration,
+    algorithm: Algorithm,
+    key: Key,
+}
+
+impl RateLimit {
+    /// 60 requests a minute per peer ip, counted in fixed windows.
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        RateLimit {
+            inner: Rc::new(Inner {
+                store,
+                name: "default".to_owned(),
+                limit: 60,
+                period: Duration::from_secs(60),
+                algorithm: Algorithm::FixedWindow,
+                key: Key::PeerIp,
+            }),
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.inner_mut().name = name.to_owned();
+        self
+    }
+
+    pub fn quota(mut self, limit: u32, period: Duration) -> Self {
+        let inner = self.inner_mut();
+        inner.limit = limit;
+        inner.period = period;
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.inner_mut().algorithm = algorithm;
+        self
+    }
+
+    pub fn key(mut self, key: Key) -> Self {
+        self.inner_mut().key = key;
+        self
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Rc::get_mut(&mut self.inner).expect("RateLimit is configured before use")
+    }
+}
+
+impl<S, Err> Transform<S> for RateLimit
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Service = RateLimitMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        RateLimitMiddleware {
+            service: Rc::new(service),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    inner: Rc<Inner>,
+}
+
+impl<S, Err> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let svc = self.service.clone();
+        let inner = self.inner.clone();
+
+        Box::pin(async move {
+            let key = match inner.client_key(&req) {
+                Some(key) => format!("{}:{}", inner.name, key),
+                None => return svc.call(req).await,
+            };
+
+            let store = inner.store.clone();
+            let (limit, period, algorithm) =
+                (inner.limit, inner.period, inner.algorithm);
+            let decision = web::block(move || {
+                store.update(&key, &|state| algorithm.apply(state, limit, period, now()))
+            })
+            .await;
+
+            let decision = match decision {
+                Ok(decision) => decision,
+                Err(e) => {
+                    // a broken store shouldn't take the whole site down
+                    log::error!("Rate limit store failed: {:?}", e);
+                    return svc.call(req).await;
+                }
+            };
+
+            let mut res = if decision.allowed {
+                svc.call(req).await?
+            } else {
+                req.into_response(
+                    HttpResponse::TooManyRequests()
+                        .header(header::RETRY_AFTER, decision.reset)
+                        .json(&serde_json::json!({ "error": "too many requests" })),
+                )
+            };
+            decision.set_headers(res.headers_mut());
+            Ok(res)
+        })
+    }
+}
+
+impl Inner {
+    fn client_key<Err>(&self, req: &WebRequest<Err>) -> Option<String> {
+        let peer_ip = || req.peer_addr().map(|addr| addr.ip().to_string());
+
+        match self.key {
+            Key::PeerIp => peer_ip(),
+            Key::Identity => req
+                .get_identity()
+                .map(|id| format!("id:{}", id))
+                .or_else(|| peer_ip().map(|ip| format!("ip:{}", ip))),
+            Key::Custom(ref f) => f(req.head()),
+        }
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_window() {
+        let period = Duration::from_secs(60);
+        let (state, first) = Algorithm::FixedWindow.apply(None, 2, period, 100.0);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+
+        let (state, second) =
+            Algorithm::FixedWindow.apply(Some(state), 2, period, 110.0);
+        assert!(second.allowed);
+        let (state, third) = Algorithm::FixedWindow.apply(Some(state), 2, period, 120.0);
+        assert!(!third.allowed);
+        assert_eq!(third.reset, 40);
+
+        let (_, next_window) =
+            Algorithm::FixedWindow.apply(Some(state), 2, period, 160.0);
+        assert!(next_window.allowed);
+    }
+
+    #[test]
+    fn token_bucket() {
+        let period = Duration::from_secs(10);
+        let (state, _) = Algorithm::TokenBucket.apply(None, 1, period, 0.0);
+        let (state, denied) = Algorithm::TokenBucket.apply(Some(state), 1, period, 5.0);
+        assert!(!denied.allowed);
+        assert_eq!(denied.reset, 5);
+
+        let (_, refilled) = Algorithm::TokenBucket.apply(Some(state), 1, period, 10.0);
+        assert!(refilled.allowed);
+    }
+
+    #[test]
+    fn memory_store_forgets_expired_state() {
+        let store = MemoryStore::default();
+        let period = Duration::from_secs(60);
+        let hit = |key: &str, now: f64| {
+            store
+                .update(key, &|state| {
+                    Algorithm::FixedWindow.apply(state, 5, period, now)
+                })
+                .unwrap()
+        };
+        hit("a", 0.0);
+        hit("b", 30.0);
+
+        assert_eq!(store.remove_expired(60.0).unwrap(), 1);
+        assert_eq!(hit("b", 40.0).remaining, 3);
+        assert_eq!(store.remove_expired(90.0).unwrap(), 1);
+    }
+}
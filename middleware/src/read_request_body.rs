@@ -2,14 +2,62 @@ use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll};
 
-use bytes::BytesMut;
-use futures::future::{ok, Future, Ready};
-use futures::stream::StreamExt;
-use loony::web::{WebRequest, WebResponse};
+use futures::future::Future;
+use futures::stream::{self, StreamExt};
+use loony::http::error::PayloadError;
+use loony::http::{header, HeaderMap, Payload};
+use loony::util::BytesMut;
 use loony::web::{Error, ErrorRenderer};
+use loony::web::{WebRequest, WebResponse};
 use loony::{Service, Transform};
+use serde_json::Value;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Captures and logs up to `limit` bytes of text request bodies, then hands
+/// the complete body on to the inner service.
+pub struct Logging {
+    config: Rc<Config>,
+}
+
+struct Config {
+    limit: usize,
+    redact: Vec<String>,
+}
+
+impl Logging {
+    pub fn new() -> Self {
+        Logging {
+            config: Rc::new(Config {
+                limit: 4096,
+                redact: Vec::new(),
+            }),
+        }
+    }
+
+    /// Maximum number of bytes to capture, 4kb by default. Larger bodies are
+    /// still forwarded in full, only the log is cut short.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.config_mut().limit = limit;
+        self
+    }
+
+    /// Replace the value of this JSON or form field before logging.
+    pub fn redact(mut self, field: &str) -> Self {
+        self.config_mut().redact.push(field.to_owned());
+        self
+    }
 
-pub struct Logging;
+    fn config_mut(&mut self) -> &mut Config {
+        Rc::get_mut(&mut self.config).expect("Logging is configured before use")
+    }
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging::new()
+    }
+}
 
 impl<S: 'static, Err> Transform<S> for Logging
 where
@@ -28,6 +76,7 @@ where
     fn new_transform(&self, service: S) -> Self::Service {
         LoggingMiddleware {
             service: Rc::new(service),
+            config: self.config.clone(),
         }
     }
 }
@@ -35,6 +84,7 @@ where
 pub struct LoggingMiddleware<S> {
     // This is special: We need this to avoid lifetime issues.
     service: Rc<S>,
+    config: Rc<Config>,
 }
 
 impl<S, Err> Service for LoggingMiddleware<S>
@@ -55,19 +105,151 @@ where
 
     fn call(&self, mut req: WebRequest<Err>) -> Self::Future {
         let svc = self.service.clone();
+        let config = self.config.clone();
 
         Box::pin(async move {
-            let mut body = BytesMut::new();
-            let mut stream = req.take_payload();
-            while let Some(chunk) = stream.next().await {
-                body.extend_from_slice(&chunk?);
+            // binary and multipart bodies are passed through untouched
+            if let Some(mime) = text_mime(req.headers()) {
+                let mut body = BytesMut::new();
+                let mut truncated = false;
+                let mut stream = req.take_payload();
+                loop {
+                    if body.len() > config.limit {
+                        truncated = true;
+                        break;
+                    }
+                    match stream.next().await {
+                        Some(chunk) => body.extend_from_slice(&chunk?),
+                        None => break,
+                    }
+                }
+
+                if truncated {
+                    // a partial body can't be parsed, so it can't be redacted either
+                    log::info!(
+                        "request body: {} bytes or more, not captured",
+                        body.len()
+                    );
+                } else {
+                    log::info!("request body: {}", config.render(&mime, &body));
+                }
+
+                // replay the captured bytes, followed by whatever is left
+                let captured = body.freeze();
+                let replay =
+                    stream::once(async move { Ok::<_, PayloadError>(captured) });
+                req.set_payload(Payload::Stream(Box::pin(replay.chain(stream))));
             }
 
-            println!("request body: {:?}", body);
             let res = svc.call(req).await?;
 
-            println!("response: {:?}", res.headers());
+            log::debug!("response: {:?}", res.headers());
             Ok(res)
         })
     }
 }
+
+impl Config {
+    fn render(&self, mime: &str, body: &[u8]) -> String {
+        if mime == "application/json" || mime.ends_with("+json") {
+            // malformed JSON can't be redacted, so it isn't logged at all
+            return match serde_json::from_slice::<Value>(body) {
+                Ok(mut value) => {
+                    self.redact_json(&mut value);
+                    value.to_string()
+                }
+                Err(_) => format!("<unparseable json, {} bytes>", body.len()),
+            };
+        } else if mime == "application/x-www-form-urlencoded" {
+            return self.redact_form(&String::from_utf8_lossy(body));
+        }
+        String::from_utf8_lossy(body).into_owned()
+    }
+
+    fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.redact.contains(key) {
+                        *value = Value::from(REDACTED);
+                    } else {
+                        self.redact_json(value);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|v| self.redact_json(v)),
+            _ => (),
+        }
+    }
+
+    fn redact_form(&self, body: &str) -> String {
+        body.split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((key, _)) if self.redact.iter().any(|f| f == key) => {
+                    format!("{}={}", key, REDACTED)
+                }
+                _ => pair.to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+/// Lower-cased mime type of the body if it is worth capturing.
+fn text_mime(headers: &HeaderMap) -> Option<String> {
+    let mime = headers
+        .get(header::CONTENT_TYPE)?
+        .to_str()
+        .ok()?
+        .split(';')
+        .next()?
+        .trim()
+        .to_ascii_lowercase();
+
+    let is_text = mime.starts_with("text/")
+        || mime == "application/json"
+        || mime.ends_with("+json")
+        || mime == "application/xml"
+        || mime == "application/x-www-form-urlencoded";
+    if is_text {
+        Some(mime)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_configured_fields() {
+        let logging = Logging::new().redact("password");
+
+        let json =
+            br#"{"email":"a@b.c","password":"hunter2","nested":[{"password":"x"}]}"#;
+        let rendered = logging.config.render("application/json", json);
+        assert!(!rendered.contains("hunter2"));
+        assert!(!rendered.contains("\"x\""));
+        assert!(rendered.contains("a@b.c"));
+
+        let form = b"email=a%40b.c&password=hunter2";
+        assert_eq!(
+            logging
+                .config
+                .render("application/x-www-form-urlencoded", form),
+            "email=a%40b.c&password=[REDACTED]"
+        );
+    }
+
+    #[test]
+    fn hides_malformed_json() {
+        let logging = Logging::new().redact("password");
+
+        let json = br#"{"email":"a@b.c","password":"hunter2""#;
+        assert_eq!(
+            logging.config.render("application/json", json),
+            "<unparseable json, 37 bytes>"
+        );
+    }
+}
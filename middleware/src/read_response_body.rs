@@ -9,6 +9,8 @@ use loony::web::{WebRequest, WebResponse};
 use loony::web::Error;
 use loony::{Service, Transform};
 
+use crate::access_log::BodySample;
+
 pub struct Logging;
 
 impl<S: 'static, Err> Transform<S> for Logging
@@ -71,10 +73,14 @@ where
         let res = futures::ready!(self.project().fut.poll(cx));
 
         Poll::Ready(res.map(|res| {
+            // report through the access log if there is one
+            let sample = res.request().extensions().get::<BodySample>().cloned();
+
             res.map_body(move |_, body| {
                 Body::from_message(BodyLogger {
                     body,
                     body_accum: BytesMut::new(),
+                    sample,
                 })
                 .into()
             })
@@ -82,14 +88,31 @@ where
     }
 }
 
+/// How much of the body goes into an access log entry.
+const SAMPLE_SIZE: usize = 512;
+
 pub struct BodyLogger {
     body: ResponseBody<Body>,
     body_accum: BytesMut,
+    sample: Option<BodySample>,
+}
+
+impl BodyLogger {
+    fn report(&mut self) {
+        if let Some(sample) = self.sample.take() {
+            let end = self.body_accum.len().min(SAMPLE_SIZE);
+            sample.set(String::from_utf8_lossy(&self.body_accum[..end]).into_owned());
+        }
+    }
 }
 
 impl Drop for BodyLogger {
     fn drop(&mut self) {
-        println!("response body: {:?}", self.body_accum);
+        if self.sample.is_some() {
+            self.report();
+        } else {
+            println!("response body: {:?}", self.body_accum);
+        }
     }
 }
 
@@ -108,7 +131,11 @@ impl MessageBody for BodyLogger {
                 Poll::Ready(Some(Ok(chunk)))
             }
             Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
-            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Ready(None) => {
+                // before the access log entry is written
+                self.report();
+                Poll::Ready(None)
+            }
             Poll::Pending => Poll::Pending,
         }
     }
@@ -1,34 +1,84 @@
+use std::rc::Rc;
 use std::task::{Context, Poll};
 
 use futures::future::{ok, Either, Ready};
-use loony::web::{WebRequest, WebResponse};
 use loony::web::{Error, HttpResponse};
+use loony::web::{WebRequest, WebResponse};
 use loony::{http, Service, Transform};
+use loony_identity::RequestIdentity;
+use loony_session::UserSession;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+/// Lets a request through if `loony_identity` knows the caller or the
+/// `loony_session` holds the configured key, otherwise sends browsers to the
+/// login page and API clients a 401.
+pub struct RequireAuth {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    login_url: String,
+    public: Vec<String>,
+    session_key: String,
+}
+
+impl RequireAuth {
+    /// `login_url` must be a local path, it is used as the redirect target.
+    pub fn new(login_url: &str) -> Self {
+        assert!(
+            is_local_path(login_url),
+            "login url must be a local path: {}",
+            login_url
+        );
+        RequireAuth {
+            inner: Rc::new(Inner {
+                login_url: login_url.to_owned(),
+                public: vec![login_url.to_owned()],
+                session_key: "user_id".to_owned(),
+            }),
+        }
+    }
 
-pub struct CheckLogin;
+    /// Path that is reachable without logging in. A trailing `*` matches
+    /// every path with that prefix, e.g. `/static/*`.
+    pub fn public(mut self, pattern: &str) -> Self {
+        Rc::get_mut(&mut self.inner)
+            .expect("RequireAuth is configured before use")
+            .public
+            .push(pattern.to_owned());
+        self
+    }
 
-impl<S, Err> Transform<S> for CheckLogin
+    /// Session key that marks a logged in session, `user_id` by default.
+    pub fn session_key(mut self, key: &str) -> Self {
+        Rc::get_mut(&mut self.inner)
+            .expect("RequireAuth is configured before use")
+            .session_key = key.to_owned();
+        self
+    }
+}
+
+impl<S, Err> Transform<S> for RequireAuth
 where
     S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
     S::Future: 'static,
 {
-    // type Request = WebRequest<Err>;
-    // type Response = WebResponse;
-    // type Error = Error;
-    // type InitError = ();
-    // type Transform = CheckLoginMiddleware<S>;
-    // type Future = Ready<Result<Self::Transform, Self::InitError>>;
-    type Service = CheckLoginMiddleware<S>;
+    type Service = RequireAuthMiddleware<S>;
 
     fn new_transform(&self, service: S) -> Self::Service {
-        CheckLoginMiddleware { service }
+        RequireAuthMiddleware {
+            service,
+            inner: self.inner.clone(),
+        }
     }
 }
-pub struct CheckLoginMiddleware<S> {
+
+pub struct RequireAuthMiddleware<S> {
     service: S,
+    inner: Rc<Inner>,
 }
 
-impl<S, Err> Service for CheckLoginMiddleware<S>
+impl<S, Err> Service for RequireAuthMiddleware<S>
 where
     S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
 {
@@ -42,24 +92,101 @@ where
     }
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        // We only need to hook into the `start` for this middleware.
-
-        let is_logged_in = false; // Change this to see the change in outcome in the browser
+        if self.inner.is_public(req.path()) || self.inner.is_logged_in(&req) {
+            return Either::Left(self.service.call(req));
+        }
 
-        if is_logged_in {
-            Either::Left(self.service.call(req))
+        let res = if wants_json(&req) {
+            HttpResponse::Unauthorized().json(&serde_json::json!({
+                "error": "unauthorized",
+                "login": self.inner.login_url,
+            }))
         } else {
-            // Don't forward to /login if we are already on /login
-            if req.path() == "/login" {
-                Either::Left(self.service.call(req))
-            } else {
-                Either::Right(ok(req.into_response(
-                    HttpResponse::Found()
-                        .header(http::header::LOCATION, "/login")
-                        .finish()
-                        .into_body(),
-                )))
-            }
+            let next = req
+                .uri()
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/");
+            let location = format!(
+                "{}?next={}",
+                self.inner.login_url,
+                utf8_percent_encode(next, NON_ALPHANUMERIC)
+            );
+            HttpResponse::Found()
+                .header(http::header::LOCATION, location)
+                .finish()
+        };
+        Either::Right(ok(req.into_response(res)))
+    }
+}
+
+impl Inner {
+    fn is_public(&self, path: &str) -> bool {
+        self.public
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == pattern.as_str(),
+            })
+    }
+
+    fn is_logged_in<Err>(&self, req: &WebRequest<Err>) -> bool {
+        if req.get_identity().is_some() {
+            return true;
         }
+        matches!(
+            req.get_session()
+                .get::<serde_json::Value>(&self.session_key),
+            Ok(Some(_))
+        )
+    }
+}
+
+/// API clients ask for JSON and don't accept HTML.
+fn wants_json<Err>(req: &WebRequest<Err>) -> bool {
+    let accept = req
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+/// Returns the `next=` value if it is safe to redirect to after login.
+pub fn safe_next(next: Option<&str>) -> Option<&str> {
+    next.filter(|next| is_local_path(next))
+}
+
+// Only same-origin absolute paths. `//host` and `/\host` are read by
+// browsers as another origin, control characters can smuggle those in.
+fn is_local_path(url: &str) -> bool {
+    url.starts_with('/')
+        && !url.starts_with("//")
+        && !url.contains('\\')
+        && !url.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_must_stay_on_site() {
+        assert_eq!(safe_next(Some("/account?tab=1")), Some("/account?tab=1"));
+        assert_eq!(safe_next(Some("//evil.example")), None);
+        assert_eq!(safe_next(Some("/\\evil.example")), None);
+        assert_eq!(safe_next(Some("https://evil.example")), None);
+        assert_eq!(safe_next(Some("/\t/evil.example")), None);
+        assert_eq!(safe_next(None), None);
+    }
+
+    #[test]
+    fn public_patterns() {
+        let auth = RequireAuth::new("/login").public("/static/*");
+
+        assert!(auth.inner.is_public("/login"));
+        assert!(auth.inner.is_public("/static/app.css"));
+        assert!(!auth.inner.is_public("/login/extra"));
+        assert!(!auth.inner.is_public("/"));
     }
 }
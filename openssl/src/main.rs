@@ -2,13 +2,18 @@ use std::io;
 
 use loony::web::{self, middleware, App, Error, HttpRequest, HttpResponse};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use secure_headers::{CspNonce, SecureHeaders};
 
 /// simple handle
-async fn index(req: HttpRequest) -> Result<HttpResponse, Error> {
+async fn index(req: HttpRequest, nonce: CspNonce) -> Result<HttpResponse, Error> {
     println!("{:?}", req);
     Ok(HttpResponse::Ok()
-        .content_type("text/plain")
-        .body("Welcome!"))
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            r#"<h1>Welcome!</h1>
+<script nonce="{}">console.log("inline scripts need the nonce");</script>"#,
+            nonce
+        )))
 }
 
 #[loony::main]
@@ -17,6 +22,7 @@ async fn main() -> io::Result<()> {
     env_logger::init();
 
     println!("Started http server: 127.0.0.1:8443");
+    println!("Redirecting http://127.0.0.1:8080 to https");
 
     // load ssl keys
     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
@@ -27,6 +33,7 @@ async fn main() -> io::Result<()> {
 
     web::server(|| {
         App::new()
+            .wrap(SecureHeaders::new().redirect_https(8443))
             // enable logger
             .wrap(middleware::Logger::default())
             // register simple handler, handle all methods
@@ -39,6 +46,8 @@ async fn main() -> io::Result<()> {
             })))
     })
     .bind_openssl("127.0.0.1:8443", builder)?
+    // plain http, only redirects to the tls listener
+    .bind("127.0.0.1:8080")?
     .run()
     .await
 }
@@ -0,0 +1,326 @@
+//! Keeps slow or numerous requests from tying up the workers.
+//!
+//! `Timeout` drops the handler future once its deadline passes. Work that
+//! was moved to the thread pool with `web::block` can't be interrupted and
+//! runs to completion, but the client gets its answer and the worker is free.
+//!
+//! `ConcurrencyLimit` lets a number of requests run at once, queues a few
+//! more and turns the rest away. Create it outside the `App` factory and
+//! clone it in, so every worker shares the same limit. Register `Timeout`
+//! first so it runs inside the limit, waiting for a slot doesn't use up the
+//! deadline then.
+//!
+//! A request gives its slot back when it is answered, which after a timeout
+//! can be long before its `web::block` work is done. Handlers take the
+//! `Slot` extractor and move it into the closure to keep the slot taken
+//! until then, so the limit caps the real load on the thread pool.
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::{ready, Ready};
+use futures::future::{select, Either};
+use loony::http::{header, HttpMessage, Payload};
+use loony::rt::time_driver::sleep;
+use loony::web::{
+    error, Error, ErrorRenderer, FromRequest, HttpRequest, HttpResponse, WebRequest,
+    WebResponse, WebResponseError,
+};
+use loony::{Service, Transform};
+
+#[derive(Debug)]
+pub enum LimitError {
+    /// The handler didn't finish within the deadline, answered with
+    /// `504 Gateway Timeout`.
+    TimedOut(Duration),
+    /// Too many requests are running or queued already, answered with
+    /// `503 Service Unavailable`.
+    Overloaded,
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::TimedOut(deadline) => {
+                write!(f, "request timed out after {:?}", deadline)
+            }
+            LimitError::Overloaded => f.write_str("server is busy, try again later"),
+        }
+    }
+}
+
+impl WebResponseError for LimitError {
+    fn error_response(&self, _: &HttpRequest) -> HttpResponse {
+        let body = serde_json::json!({ "error": self.to_string() });
+        match self {
+            LimitError::TimedOut(_) => HttpResponse::GatewayTimeout().json(&body),
+            LimitError::Overloaded => HttpResponse::ServiceUnavailable()
+                .header(header::RETRY_AFTER, 1)
+                .json(&body),
+        }
+    }
+}
+
+/// Fails requests that take longer than the deadline with
+/// `LimitError::TimedOut`.
+pub struct Timeout {
+    deadline: Duration,
+}
+
+impl Timeout {
+    pub fn new(deadline: Duration) -> Self {
+        Timeout { deadline }
+    }
+}
+
+impl<S, Err> Transform<S> for Timeout
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Service = TimeoutMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        TimeoutMiddleware {
+            service,
+            deadline: self.deadline,
+        }
+    }
+}
+
+pub struct TimeoutMiddleware<S> {
+    service: S,
+    deadline: Duration,
+}
+
+impl<S, Err> Service for TimeoutMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let deadline = self.deadline;
+        let fut = Box::pin(self.service.call(req));
+
+        Box::pin(async move {
+            match select(fut, Box::pin(sleep(deadline))).await {
+                Either::Left((res, _)) => res,
+                // dropping the handler future cancels it
+                Either::Right(_) => Err(LimitError::TimedOut(deadline).into()),
+            }
+        })
+    }
+}
+
+/// Runs at most `max` requests at once and queues up to `queue` more, the
+/// rest fail with `LimitError::Overloaded`.
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    limiter: Arc<Limiter>,
+}
+
+struct Limiter {
+    max: usize,
+    queue: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    running: usize,
+    waiting: VecDeque<oneshot::Sender<()>>,
+}
+
+impl ConcurrencyLimit {
+    /// Sheds everything over `max` right away, see `queue`.
+    pub fn new(max: usize) -> Self {
+        ConcurrencyLimit {
+            limiter: Arc::new(Limiter {
+                max,
+                queue: 0,
+                state: Mutex::new(State {
+                    running: 0,
+                    waiting: VecDeque::new(),
+                }),
+            }),
+        }
+    }
+
+    /// How many requests may wait for a free slot.
+    pub fn queue(mut self, queue: usize) -> Self {
+        Arc::get_mut(&mut self.limiter)
+            .expect("ConcurrencyLimit is configured before use")
+            .queue = queue;
+        self
+    }
+}
+
+impl Limiter {
+    async fn acquire(self: &Arc<Self>) -> Result<Permit, LimitError> {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            if state.running < self.max {
+                state.running += 1;
+                return Ok(Permit(self.clone()));
+            }
+            if state.waiting.len() >= self.queue {
+                return Err(LimitError::Overloaded);
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back(tx);
+            rx
+        };
+
+        let mut waiting = Waiting {
+            rx: Some(rx),
+            limiter: self.clone(),
+        };
+        match waiting.rx.as_mut().unwrap().await {
+            Ok(()) => {
+                waiting.rx = None;
+                Ok(Permit(self.clone()))
+            }
+            Err(_) => Err(LimitError::Overloaded),
+        }
+    }
+
+    /// Hands the slot to the next waiter that is still around.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(tx) = state.waiting.pop_front() {
+            if tx.send(()).is_ok() {
+                return;
+            }
+        }
+        state.running -= 1;
+    }
+}
+
+struct Permit(Arc<Limiter>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// The slot of the current request in its `ConcurrencyLimit`. It is given
+/// back once the request and every clone of the `Slot` are gone.
+#[derive(Clone)]
+pub struct Slot(Arc<Permit>);
+
+impl<Err> FromRequest<Err> for Slot {
+    type Error = Error;
+    type Future = Ready<Result<Slot, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Slot>().cloned().ok_or_else(|| {
+            error::ErrorInternalServerError(
+                "ConcurrencyLimit middleware is not registered",
+            )
+            .into()
+        }))
+    }
+}
+
+/// A queued request, gives back a slot it was handed after it went away.
+struct Waiting {
+    rx: Option<oneshot::Receiver<()>>,
+    limiter: Arc<Limiter>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            if let Ok(Some(())) = rx.try_recv() {
+                self.limiter.release();
+            }
+        }
+    }
+}
+
+impl<S, Err> Transform<S> for ConcurrencyLimit
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Service = ConcurrencyLimitMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        ConcurrencyLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+pub struct ConcurrencyLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: Arc<Limiter>,
+}
+
+impl<S, Err> Service for ConcurrencyLimitMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: Self::Request) -> Self::Future {
+        let svc = self.service.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let slot = Slot(Arc::new(limiter.acquire().await?));
+            req.extensions_mut().insert(slot.clone());
+            svc.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[loony::test]
+    async fn concurrency_limit() {
+        let limiter = ConcurrencyLimit::new(1).queue(1).limiter;
+
+        let first = limiter.acquire().await.unwrap();
+        let mut queued = Box::pin(limiter.acquire());
+        assert!(futures::poll!(queued.as_mut()).is_pending());
+        assert!(matches!(
+            limiter.acquire().await,
+            Err(LimitError::Overloaded)
+        ));
+
+        drop(first);
+        let second = queued.await.unwrap();
+        assert_eq!(limiter.state.lock().unwrap().running, 1);
+        drop(second);
+        assert_eq!(limiter.state.lock().unwrap().running, 0);
+    }
+}
@@ -5,13 +5,18 @@ use loony::web::{self, middleware, App, HttpRequest, HttpResponse};
 use loony_files::Files;
 use rustls::internal::pemfile::{certs, rsa_private_keys};
 use rustls::{NoClientAuth, ServerConfig};
+use secure_headers::{CspNonce, SecureHeaders};
 
 /// simple handle
-async fn index(req: HttpRequest) -> HttpResponse {
+async fn index(req: HttpRequest, nonce: CspNonce) -> HttpResponse {
     println!("{:?}", req);
     HttpResponse::Ok()
-        .content_type("text/plain")
-        .body("Welcome!")
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            r#"<h1>Welcome!</h1>
+<script nonce="{}">console.log("inline scripts need the nonce");</script>"#,
+            nonce
+        ))
 }
 
 #[loony::main]
@@ -31,6 +36,7 @@ async fn main() -> std::io::Result<()> {
 
     web::server(|| {
         App::new()
+            .wrap(SecureHeaders::new().redirect_https(8443))
             // enable logger
             .wrap(middleware::Logger::default())
             // register simple handler, handle all methods
@@ -44,6 +50,8 @@ async fn main() -> std::io::Result<()> {
             .service(Files::new("/static", "static"))
     })
     .bind_rustls("127.0.0.1:8443", config)?
+    // plain http, only redirects to the tls listener
+    .bind("127.0.0.1:8080")?
     .run()
     .await
 }
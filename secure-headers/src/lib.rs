@@ -0,0 +1,410 @@
+//! Security headers for every response, and an optional redirect from the
+//! plain HTTP listener to the TLS one.
+//!
+//! Whether a request came in over TLS is told by the listener it arrived
+//! on. `X-Forwarded-Proto` is only believed from the proxies named with
+//! `trusted_proxy`, anyone else could send it to skip the redirect.
+//!
+//! The CSP may contain `{nonce}`, it is replaced by a fresh random value for
+//! every response. Handlers get the value with the `CspNonce` extractor and
+//! put it on their inline scripts and styles:
+//!
+//! ```text
+//! <script nonce="{{ nonce }}">...</script>
+//! ```
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::future::{ok, ready, Ready};
+use loony::http::header::{self, HeaderName, HeaderValue};
+use loony::http::{HttpMessage, Method, Payload};
+use loony::web::{
+    error, Error, FromRequest, HttpRequest, HttpResponse, WebRequest, WebResponse,
+};
+use loony::{Service, Transform};
+
+/// The CSP nonce of the current response.
+#[derive(Clone, Debug)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    pub fn value(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CspNonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<Err> FromRequest<Err> for CspNonce {
+    type Error = Error;
+    type Future = Ready<Result<CspNonce, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<CspNonce>().cloned().ok_or_else(|| {
+            error::ErrorInternalServerError("SecureHeaders middleware is not registered")
+                .into()
+        }))
+    }
+}
+
+/// Adds `Strict-Transport-Security`, `Content-Security-Policy`,
+/// `X-Content-Type-Options`, `Referrer-Policy` and `X-Frame-Options` to
+/// responses that don't set them already.
+pub struct SecureHeaders {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    headers: Vec<(HeaderName, String)>,
+    https_port: Option<u16>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl SecureHeaders {
+    pub fn new() -> Self {
+        SecureHeaders {
+            inner: Rc::new(Inner {
+                headers: vec![
+                    (
+                        header::STRICT_TRANSPORT_SECURITY,
+                        "max-age=31536000; includeSubDomains".to_owned(),
+                    ),
+                    (
+                        header::CONTENT_SECURITY_POLICY,
+                        "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+                         style-src 'self' 'nonce-{nonce}'; object-src 'none'; \
+                         base-uri 'self'; frame-ancestors 'none'"
+                            .to_owned(),
+                    ),
+                    (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+                    (
+                        header::REFERRER_POLICY,
+                        "strict-origin-when-cross-origin".to_owned(),
+                    ),
+                    (header::X_FRAME_OPTIONS, "DENY".to_owned()),
+                ],
+                https_port: None,
+                trusted_proxies: Vec::new(),
+            }),
+        }
+    }
+
+    /// Only sent over HTTPS, browsers ignore it on plain HTTP anyway.
+    pub fn hsts(self, value: &str) -> Self {
+        self.set(header::STRICT_TRANSPORT_SECURITY, value)
+    }
+
+    /// `{nonce}` is replaced by the response's `CspNonce`.
+    pub fn content_security_policy(self, value: &str) -> Self {
+        self.set(header::CONTENT_SECURITY_POLICY, value)
+    }
+
+    pub fn content_type_options(self, value: &str) -> Self {
+        self.set(header::X_CONTENT_TYPE_OPTIONS, value)
+    }
+
+    pub fn referrer_policy(self, value: &str) -> Self {
+        self.set(header::REFERRER_POLICY, value)
+    }
+
+    pub fn frame_options(self, value: &str) -> Self {
+        self.set(header::X_FRAME_OPTIONS, value)
+    }
+
+    /// Don't send one of the headers at all.
+    pub fn without(mut self, name: HeaderName) -> Self {
+        self.inner_mut().headers.retain(|(n, _)| *n != name);
+        self
+    }
+
+    /// Redirect requests that came in over plain HTTP to the TLS listener on
+    /// `port`.
+    pub fn redirect_https(mut self, port: u16) -> Self {
+        self.inner_mut().https_port = Some(port);
+        self
+    }
+
+    /// Believe `X-Forwarded-Proto` and `X-Forwarded-Host` in requests from
+    /// this address, for a TLS terminating proxy in front of the server.
+    pub fn trusted_proxy(mut self, addr: IpAddr) -> Self {
+        self.inner_mut().trusted_proxies.push(addr);
+        self
+    }
+
+    fn set(mut self, name: HeaderName, value: &str) -> Self {
+        let headers = &mut self.inner_mut().headers;
+        match headers.iter_mut().find(|(n, _)| *n == name) {
+            Some(header) => header.1 = value.to_owned(),
+            None => headers.push((name, value.to_owned())),
+        }
+        self
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Rc::get_mut(&mut self.inner).expect("SecureHeaders is configured before use")
+    }
+}
+
+impl Default for SecureHeaders {
+    fn default() -> Self {
+        SecureHeaders::new()
+    }
+}
+
+impl<S, Err> Transform<S> for SecureHeaders
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Service = SecureHeadersMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        SecureHeadersMiddleware {
+            service,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+pub struct SecureHeadersMiddleware<S> {
+    service: S,
+    inner: Rc<Inner>,
+}
+
+impl<S, Err> Service for SecureHeadersMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: Self::Request) -> Self::Future {
+        let proxied = self.inner.from_trusted_proxy(&req);
+        let https = if proxied {
+            req.connection_info().scheme() == "https"
+        } else {
+            req.app_config().secure()
+        };
+
+        if let (false, Some(port)) = (https, self.inner.https_port) {
+            let location = https_url(&req, port, proxied);
+            let mut res = if matches!(*req.method(), Method::GET | Method::HEAD) {
+                HttpResponse::MovedPermanently()
+            } else {
+                // keeps the method and body, unlike 301
+                HttpResponse::PermanentRedirect()
+            };
+            let res = res.header(header::LOCATION, location).finish();
+            return Box::pin(ok(req.into_response(res)));
+        }
+
+        let nonce = new_nonce();
+        req.extensions_mut().insert(CspNonce(nonce.clone()));
+
+        let inner = self.inner.clone();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let headers = res.headers_mut();
+            for (name, value) in &inner.headers {
+                if headers.contains_key(name)
+                    || (!https && *name == header::STRICT_TRANSPORT_SECURITY)
+                {
+                    continue;
+                }
+                if let Ok(value) =
+                    HeaderValue::from_str(&value.replace("{nonce}", &nonce))
+                {
+                    headers.insert(name.clone(), value);
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+impl Inner {
+    fn from_trusted_proxy<Err>(&self, req: &WebRequest<Err>) -> bool {
+        req.peer_addr()
+            .map_or(false, |addr| self.trusted_proxies.contains(&addr.ip()))
+    }
+}
+
+fn https_url<Err>(req: &WebRequest<Err>, port: u16, proxied: bool) -> String {
+    let info = req.connection_info();
+    let host = if proxied {
+        info.host()
+    } else {
+        req.headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or_else(|| req.app_config().host())
+    };
+    // drop the port of the plain listener, keep IPv6 brackets intact
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+
+    if port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, port, path)
+    }
+}
+
+fn new_nonce() -> String {
+    let bytes: [u8; 16] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use loony::http::StatusCode;
+    use loony::web::{self, test, App};
+
+    use super::*;
+
+    const PROXY: &str = "10.0.0.1:4000";
+
+    async fn location(host: &str, port: u16, method: Method) -> (StatusCode, String) {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(SecureHeaders::new().redirect_https(port))
+                .service(web::resource("/path").to(|| async { "plain" })),
+        )
+        .await;
+        let req = test::TestRequest::with_uri("/path?q=1")
+            .method(method)
+            .header(header::HOST, host)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        let location = res.headers().get(header::LOCATION).unwrap();
+        (res.status(), location.to_str().unwrap().to_owned())
+    }
+
+    #[loony::test]
+    async fn redirects_to_https() {
+        assert_eq!(
+            location("example.com:8080", 8443, Method::GET).await,
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                "https://example.com:8443/path?q=1".to_owned()
+            )
+        );
+        assert_eq!(
+            location("example.com:8080", 443, Method::GET).await.1,
+            "https://example.com/path?q=1"
+        );
+        assert_eq!(
+            location("[::1]:8080", 8443, Method::GET).await.1,
+            "https://[::1]:8443/path?q=1"
+        );
+        assert_eq!(
+            location("[::1]", 443, Method::GET).await.1,
+            "https://[::1]/path?q=1"
+        );
+        // a 301 would turn the POST into a GET
+        assert_eq!(
+            location("example.com", 8443, Method::POST).await.0,
+            StatusCode::PERMANENT_REDIRECT
+        );
+    }
+
+    #[loony::test]
+    async fn forwarded_proto_only_from_trusted_proxies() {
+        let proxy: SocketAddr = PROXY.parse().unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    SecureHeaders::new()
+                        .redirect_https(443)
+                        .trusted_proxy(proxy.ip()),
+                )
+                .service(web::resource("/").to(|| async { "secure" })),
+        )
+        .await;
+        let forwarded = |peer: &str| {
+            test::TestRequest::with_uri("/")
+                .peer_addr(peer.parse().unwrap())
+                .header(header::HOST, "example.com")
+                .header("x-forwarded-proto", "https")
+                .to_request()
+        };
+
+        let res = test::call_service(&mut app, forwarded("192.0.2.7:5000")).await;
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+
+        let res = test::call_service(&mut app, forwarded(PROXY)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res
+            .headers()
+            .contains_key(header::STRICT_TRANSPORT_SECURITY));
+    }
+
+    #[loony::test]
+    async fn adds_headers() {
+        let mut app =
+            test::init_service(App::new().wrap(SecureHeaders::new()).service((
+                web::resource("/").to(|nonce: CspNonce| async move {
+                    HttpResponse::Ok().body(nonce.value().to_owned())
+                }),
+                web::resource("/framed").to(|| async {
+                    HttpResponse::Ok()
+                        .header(header::X_FRAME_OPTIONS, "SAMEORIGIN")
+                        .finish()
+                }),
+            )))
+            .await;
+
+        let mut nonces = Vec::new();
+        for _ in 0..2 {
+            let req = test::TestRequest::with_uri("/").to_request();
+            let res = test::call_service(&mut app, req).await;
+            let headers = res.headers();
+            // plain HTTP, browsers would ignore it
+            assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+            assert_eq!(
+                headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+                "nosniff"
+            );
+            assert_eq!(headers.get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+            let csp = headers
+                .get(header::CONTENT_SECURITY_POLICY)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned();
+            let nonce = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            assert!(!csp.contains("{nonce}"));
+            assert!(csp.contains(&format!("script-src 'self' 'nonce-{}'", nonce)));
+            nonces.push(nonce);
+        }
+        assert_ne!(nonces[0], nonces[1]);
+
+        let req = test::TestRequest::with_uri("/framed").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(
+            res.headers().get(header::X_FRAME_OPTIONS).unwrap(),
+            "SAMEORIGIN"
+        );
+    }
+}
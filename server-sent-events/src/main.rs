@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Mutex;
 use std::task::{Context, Poll};
@@ -5,21 +6,38 @@ use std::time::Duration;
 
 use loony::util::Bytes;
 use futures::Stream;
-use loony::web::{self, App, Error, HttpResponse};
+use loony::http::header;
+use loony::web::{self, App, Error, HttpRequest, HttpResponse};
+use loony_identity::{CookieIdentityPolicy, Identity, IdentityService, RequestIdentity};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::time::{interval_at, Instant};
 
+/// Bearer tokens handed out on login, mapped to the identity they stand for.
+type Tokens = Mutex<HashMap<String, String>>;
+
 #[loony::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
     let data = Broadcaster::create();
+    let tokens = web::types::Data::new(Tokens::default());
+    let keys = cookie_keys::Keyring::from_env().expect("Failed to load cookie keys");
 
     web::server(move || {
         App::new()
             .app_data(data.clone())
+            .app_data(tokens.clone())
+            .wrap(IdentityService::new(
+                CookieIdentityPolicy::new(keys.active())
+                    .name("auth-example")
+                    .secure(false),
+            ))
+            .wrap(keys.rotation().private("auth-example"))
             .route("/", web::get().to(index))
+            .route("/login/{user}", web::post().to(login))
+            .route("/logout", web::post().to(logout))
             .route("/events", web::get().to(new_client))
             .route("/broadcast/{msg}", web::get().to(broadcast))
+            .route("/send/{user}/{msg}", web::post().to(send_to))
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -34,8 +52,48 @@ async fn index() -> HttpResponse {
         .body(content)
 }
 
-async fn new_client(broadcaster: web::types::Data<Mutex<Broadcaster>>) -> HttpResponse {
-    let rx = broadcaster.lock().unwrap().new_client();
+/// Remember the user in the identity cookie and hand out a bearer token
+/// for clients that can't keep cookies.
+async fn login(
+    user: web::types::Path<String>,
+    id: Identity,
+    tokens: web::types::Data<Tokens>,
+) -> HttpResponse {
+    let user = user.into_inner();
+    let token = uuid::Uuid::new_v4().to_simple().to_string();
+
+    tokens.lock().unwrap().insert(token.clone(), user.clone());
+    id.remember(user);
+
+    HttpResponse::Ok().body(token)
+}
+
+/// Forget the identity, revoke its tokens and close all of its open streams.
+async fn logout(
+    req: HttpRequest,
+    id: Identity,
+    tokens: web::types::Data<Tokens>,
+    broadcaster: web::types::Data<Mutex<Broadcaster>>,
+) -> HttpResponse {
+    if let Some(user) = identity(&req, &tokens) {
+        tokens.lock().unwrap().retain(|_, owner| *owner != user);
+        broadcaster.lock().unwrap().forget(&user);
+    }
+    id.forget();
+
+    HttpResponse::Ok().body("logged out")
+}
+
+async fn new_client(
+    req: HttpRequest,
+    tokens: web::types::Data<Tokens>,
+    broadcaster: web::types::Data<Mutex<Broadcaster>>,
+) -> HttpResponse {
+    let user = match identity(&req, &tokens) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let rx = broadcaster.lock().unwrap().new_client(user);
 
     HttpResponse::Ok()
         .header("content-type", "text/event-stream")
@@ -52,8 +110,44 @@ async fn broadcast(
     HttpResponse::Ok().body("msg sent")
 }
 
+/// Send a private event that only the streams of `user` receive, on behalf
+/// of the logged in caller.
+async fn send_to(
+    req: HttpRequest,
+    path: web::types::Path<(String, String)>,
+    tokens: web::types::Data<Tokens>,
+    broadcaster: web::types::Data<Mutex<Broadcaster>>,
+) -> HttpResponse {
+    let sender = match identity(&req, &tokens) {
+        Some(sender) => sender,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let (user, msg) = path.into_inner();
+    broadcaster
+        .lock()
+        .unwrap()
+        .send_to(&user, &[&sender, ": ", &msg].concat());
+
+    HttpResponse::Ok().body("msg sent")
+}
+
+/// Resolve the caller from an `Authorization: Bearer` token, falling back
+/// to the identity cookie.
+fn identity(req: &HttpRequest, tokens: &Tokens) -> Option<String> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match bearer {
+        Some(token) => tokens.lock().unwrap().get(token.trim()).cloned(),
+        None => req.get_identity(),
+    }
+}
+
 struct Broadcaster {
-    clients: Vec<Sender<Bytes>>,
+    clients: Vec<(String, Sender<Bytes>)>,
 }
 
 impl Broadcaster {
@@ -83,34 +177,47 @@ impl Broadcaster {
 
     fn remove_stale_clients(&mut self) {
         let mut ok_clients = Vec::new();
-        for client in self.clients.iter() {
+        for (user, client) in self.clients.iter() {
             let result = client.clone().try_send(Bytes::from("data: ping\n\n"));
 
             if let Ok(()) = result {
-                ok_clients.push(client.clone());
+                ok_clients.push((user.clone(), client.clone()));
             }
         }
         self.clients = ok_clients;
     }
 
-    fn new_client(&mut self) -> Client {
+    fn new_client(&mut self, user: String) -> Client {
         let (tx, rx) = channel(100);
 
         tx.clone()
             .try_send(Bytes::from("data: connected\n\n"))
             .unwrap();
 
-        self.clients.push(tx);
+        self.clients.push((user, tx));
         Client(rx)
     }
 
     fn send(&self, msg: &str) {
         let msg = Bytes::from(["data: ", msg, "\n\n"].concat());
 
-        for client in self.clients.iter() {
+        for (_, client) in self.clients.iter() {
             client.clone().try_send(msg.clone()).unwrap_or(());
         }
     }
+
+    fn send_to(&self, user: &str, msg: &str) {
+        let msg = Bytes::from(["event: private\ndata: ", msg, "\n\n"].concat());
+
+        for (_, client) in self.clients.iter().filter(|(owner, _)| owner == user) {
+            client.clone().try_send(msg.clone()).unwrap_or(());
+        }
+    }
+
+    // dropping the senders ends the matching `Client` streams
+    fn forget(&mut self, user: &str) {
+        self.clients.retain(|(owner, _)| owner != user);
+    }
 }
 
 // wrap Receiver in own type, with correct error type
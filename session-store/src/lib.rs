@@ -0,0 +1,681 @@
+//! Server-side sessions for `loony_session`.
+//!
+//! `CookieSession` keeps the whole state in the cookie, limited to about 4kb
+//! and impossible to revoke. `ServerSession` puts a random id in the cookie
+//! and keeps the state in a `SessionStore`. Handlers use the usual `Session`
+//! extractor; `session.renew()` moves the state to a new id, which should
+//! happen whenever the user logs in or gains privileges, and
+//! `session.purge()` deletes it.
+//!
+//! Used by the `basics`, `cookie-session`, `middleware` and `todo` examples.
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use deadpool_postgres::Pool as PgPool;
+use loony::http::cookie::{Cookie, SameSite};
+use loony::web::{
+    self, error::BlockingError, Error, ErrorRenderer, WebRequest, WebResponse,
+};
+use loony::{Service, Transform};
+use loony_session::{Session, SessionStatus};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, OptionalExtension};
+use uuid::Uuid;
+
+/// How long a session lasts without requests, unless configured otherwise.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// How long a session lasts at most, unless configured otherwise.
+pub const DEFAULT_ABSOLUTE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+pub type StoreFuture<T> = Pin<Box<dyn Future<Output = Result<T, StoreError>>>>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionRecord {
+    /// the session state, values are JSON like in `CookieSession`
+    pub state: HashMap<String, String>,
+    /// the user the session belongs to, see `ServerSession::user_key`
+    pub user: Option<String>,
+    /// unix timestamps in seconds
+    pub created: i64,
+    pub last_seen: i64,
+}
+
+/// Keeps session state. Shared by all workers, so create it once outside
+/// the server factory.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> StoreFuture<Option<SessionRecord>>;
+
+    /// Inserts or replaces the session.
+    fn save(&self, id: &str, record: SessionRecord) -> StoreFuture<()>;
+
+    /// Updates `last_seen` of the session if it still exists. Unlike `save`
+    /// it never brings back a session deleted in the meantime.
+    fn touch(&self, id: &str, last_seen: i64) -> StoreFuture<()>;
+
+    fn delete(&self, id: &str) -> StoreFuture<()>;
+
+    /// Ends every session of a user, e.g. after a password change.
+    fn delete_user(&self, user: &str) -> StoreFuture<()>;
+
+    /// Deletes sessions last seen before `idle_before` or created before
+    /// `created_before`, returns how many.
+    fn remove_expired(&self, idle_before: i64, created_before: i64) -> StoreFuture<u64>;
+}
+
+/// Keeps the sessions in process memory, they are lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> StoreFuture<Option<SessionRecord>> {
+        let record = self.sessions.lock().unwrap().get(id).cloned();
+        Box::pin(async move { Ok(record) })
+    }
+
+    fn save(&self, id: &str, record: SessionRecord) -> StoreFuture<()> {
+        self.sessions.lock().unwrap().insert(id.to_owned(), record);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn touch(&self, id: &str, last_seen: i64) -> StoreFuture<()> {
+        if let Some(record) = self.sessions.lock().unwrap().get_mut(id) {
+            record.last_seen = last_seen;
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn delete(&self, id: &str) -> StoreFuture<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn delete_user(&self, user: &str) -> StoreFuture<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, record| record.user.as_deref() != Some(user));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove_expired(&self, idle_before: i64, created_before: i64) -> StoreFuture<u64> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, record| {
+            record.last_seen >= idle_before && record.created >= created_before
+        });
+        let removed = (before - sessions.len()) as u64;
+        Box::pin(async move { Ok(removed) })
+    }
+}
+
+/// Keeps the sessions in SQLite. The queries run on the thread pool.
+pub struct SqliteStore {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+}
+
+impl SqliteStore {
+    pub fn new(pool: r2d2::Pool<SqliteConnectionManager>) -> Result<Self, StoreError> {
+        pool.get()?.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                user_id TEXT,
+                created INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions (user_id);",
+        )?;
+        Ok(SqliteStore { pool })
+    }
+
+    fn run<T, F>(&self, f: F) -> StoreFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&rusqlite::Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            web::block(move || f(&*pool.get()?))
+                .await
+                .map_err(|e| match e {
+                    BlockingError::Error(e) => e,
+                    BlockingError::Canceled => "session store query canceled".into(),
+                })
+        })
+    }
+}
+
+impl SessionStore for SqliteStore {
+    fn load(&self, id: &str) -> StoreFuture<Option<SessionRecord>> {
+        let id = id.to_owned();
+        self.run(move |conn| {
+            let row = conn
+                .query_row(
+                    "SELECT state, user_id, created, last_seen FROM sessions WHERE id = ?1",
+                    params![id],
+                    |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                        ))
+                    },
+                )
+                .optional()?;
+            match row {
+                Some((state, user, created, last_seen)) => Ok(Some(SessionRecord {
+                    state: serde_json::from_str(&state)?,
+                    user,
+                    created,
+                    last_seen,
+                })),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn save(&self, id: &str, record: SessionRecord) -> StoreFuture<()> {
+        let id = id.to_owned();
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO sessions (id, state, user_id, created, last_seen)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    id,
+                    serde_json::to_string(&record.state)?,
+                    record.user,
+                    record.created,
+                    record.last_seen
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn touch(&self, id: &str, last_seen: i64) -> StoreFuture<()> {
+        let id = id.to_owned();
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE sessions SET last_seen = ?2 WHERE id = ?1",
+                params![id, last_seen],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn delete(&self, id: &str) -> StoreFuture<()> {
+        let id = id.to_owned();
+        self.run(move |conn| {
+            conn.execute("DELETE FROM sessions WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+    }
+
+    fn delete_user(&self, user: &str) -> StoreFuture<()> {
+        let user = user.to_owned();
+        self.run(move |conn| {
+            conn.execute("DELETE FROM sessions WHERE user_id = ?1", params![user])?;
+            Ok(())
+        })
+    }
+
+    fn remove_expired(&self, idle_before: i64, created_before: i64) -> StoreFuture<u64> {
+        self.run(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM sessions WHERE last_seen < ?1 OR created < ?2",
+                params![idle_before, created_before],
+            )?;
+            Ok(removed as u64)
+        })
+    }
+}
+
+/// Keeps the sessions in Postgres through a `deadpool_postgres` pool.
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub async fn new(pool: PgPool) -> Result<Self, StoreError> {
+        pool.get()
+            .await?
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS sessions (
+                    id TEXT PRIMARY KEY,
+                    state TEXT NOT NULL,
+                    user_id TEXT,
+                    created BIGINT NOT NULL,
+                    last_seen BIGINT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions (user_id);",
+            )
+            .await?;
+        Ok(PostgresStore { pool })
+    }
+}
+
+impl SessionStore for PostgresStore {
+    fn load(&self, id: &str) -> StoreFuture<Option<SessionRecord>> {
+        let (pool, id) = (self.pool.clone(), id.to_owned());
+        Box::pin(async move {
+            let client = pool.get().await?;
+            let row = client
+                .query_opt(
+                    "SELECT state, user_id, created, last_seen FROM sessions WHERE id = $1",
+                    &[&id],
+                )
+                .await?;
+            match row {
+                Some(row) => Ok(Some(SessionRecord {
+                    state: serde_json::from_str(row.get(0))?,
+                    user: row.get(1),
+                    created: row.get(2),
+                    last_seen: row.get(3),
+                })),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn save(&self, id: &str, record: SessionRecord) -> StoreFuture<()> {
+        let (pool, id) = (self.pool.clone(), id.to_owned());
+        Box::pin(async move {
+            let state = serde_json::to_string(&record.state)?;
+            pool.get()
+                .await?
+                .execute(
+                    "INSERT INTO sessions (id, state, user_id, created, last_seen)
+                     VALUES ($1, $2, $3, $4, $5)
+                     ON CONFLICT (id) DO UPDATE SET
+                        state = $2, user_id = $3, created = $4, last_seen = $5",
+                    &[
+                        &id,
+                        &state,
+                        &record.user,
+                        &record.created,
+                        &record.last_seen,
+                    ],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn touch(&self, id: &str, last_seen: i64) -> StoreFuture<()> {
+        let (pool, id) = (self.pool.clone(), id.to_owned());
+        Box::pin(async move {
+            pool.get()
+                .await?
+                .execute(
+                    "UPDATE sessions SET last_seen = $2 WHERE id = $1",
+                    &[&id, &last_seen],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn delete(&self, id: &str) -> StoreFuture<()> {
+        let (pool, id) = (self.pool.clone(), id.to_owned());
+        Box::pin(async move {
+            pool.get()
+                .await?
+                .execute("DELETE FROM sessions WHERE id = $1", &[&id])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn delete_user(&self, user: &str) -> StoreFuture<()> {
+        let (pool, user) = (self.pool.clone(), user.to_owned());
+        Box::pin(async move {
+            pool.get()
+                .await?
+                .execute("DELETE FROM sessions WHERE user_id = $1", &[&user])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn remove_expired(&self, idle_before: i64, created_before: i64) -> StoreFuture<u64> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let removed = pool
+                .get()
+                .await?
+                .execute(
+                    "DELETE FROM sessions WHERE last_seen < $1 OR created < $2",
+                    &[&idle_before, &created_before],
+                )
+                .await?;
+            Ok(removed)
+        })
+    }
+}
+
+/// Session middleware that keeps the state on the server.
+///
+/// Sessions expire after `idle_timeout` without requests (30 minutes by
+/// default) and `absolute_timeout` after they were created (a day), even if
+/// they are kept busy.
+pub struct ServerSession {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    store: Arc<dyn SessionStore>,
+    cookie_name: String,
+    secure: bool,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
+    user_key: String,
+}
+
+impl ServerSession {
+    pub fn new(store: Arc<dyn SessionStore>) -> Self {
+        ServerSession {
+            inner: Rc::new(Inner {
+                store,
+                cookie_name: "session".to_owned(),
+                secure: true,
+                idle_timeout: DEFAULT_IDLE_TIMEOUT,
+                absolute_timeout: DEFAULT_ABSOLUTE_TIMEOUT,
+                user_key: "user_id".to_owned(),
+            }),
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.inner_mut().cookie_name = name.to_owned();
+        self
+    }
+
+    /// Only send the cookie over https, on by default.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.inner_mut().secure = secure;
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.inner_mut().idle_timeout = timeout;
+        self
+    }
+
+    pub fn absolute_timeout(mut self, timeout: Duration) -> Self {
+        self.inner_mut().absolute_timeout = timeout;
+        self
+    }
+
+    /// Session key holding the user a session belongs to, `user_id` by
+    /// default. `SessionStore::delete_user` finds sessions by its value.
+    pub fn user_key(mut self, key: &str) -> Self {
+        self.inner_mut().user_key = key.to_owned();
+        self
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Rc::get_mut(&mut self.inner).expect("ServerSession is configured before use")
+    }
+}
+
+/// Deletes the sessions that expired under the given timeouts, run it
+/// periodically.
+pub async fn remove_expired(
+    store: &dyn SessionStore,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
+) -> Result<u64, StoreError> {
+    let now = now();
+    store
+        .remove_expired(
+            now - idle_timeout.as_secs() as i64,
+            now - absolute_timeout.as_secs() as i64,
+        )
+        .await
+}
+
+impl<S, Err> Transform<S> for ServerSession
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Service = ServerSessionMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        ServerSessionMiddleware {
+            service: Rc::new(service),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+pub struct ServerSessionMiddleware<S> {
+    service: Rc<S>,
+    inner: Rc<Inner>,
+}
+
+impl<S, Err> Service for ServerSessionMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let svc = self.service.clone();
+        let inner = self.inner.clone();
+
+        Box::pin(async move {
+            let now = now();
+            let id = req
+                .cookie(&inner.cookie_name)
+                .map(|cookie| cookie.value().to_owned());
+            let current = match id {
+                Some(id) => inner.load(id, now).await,
+                None => None,
+            };
+
+            let state = current
+                .as_ref()
+                .map(|(_, record)| record.state.clone())
+                .unwrap_or_default();
+            Session::set_session(state.into_iter(), &req);
+
+            let mut res = svc.call(req).await?;
+            let (status, state) = Session::get_changes(&mut res);
+            let state: HashMap<_, _> = state.map(|s| s.collect()).unwrap_or_default();
+            let created = current.as_ref().map_or(now, |(_, record)| record.created);
+
+            let result = match (status, current) {
+                (SessionStatus::Purged, Some((id, _))) => {
+                    let mut cookie = inner.cookie(String::new());
+                    cookie.make_removal();
+                    res.response_mut().add_cookie(&cookie)?;
+                    inner.store.delete(&id).await
+                }
+                (SessionStatus::Purged, None) => Ok(()),
+                (SessionStatus::Changed, Some((id, _))) => {
+                    inner.save(&id, state, created, now).await
+                }
+                (SessionStatus::Unchanged, Some((id, record))) => {
+                    // keeps the session from going idle without a write on every request,
+                    // and without undoing a `delete_user` that ran since it was loaded
+                    if now - record.last_seen >= 60 {
+                        inner.store.touch(&id, now).await
+                    } else {
+                        Ok(())
+                    }
+                }
+                (SessionStatus::Unchanged, None) => Ok(()),
+                // new sessions and renewed ones get a fresh id
+                (_, current) => {
+                    let id = new_id();
+                    res.response_mut().add_cookie(&inner.cookie(id.clone()))?;
+                    match current {
+                        Some((old_id, _)) => inner.store.delete(&old_id).await,
+                        None => Ok(()),
+                    }
+                    .and(inner.save(&id, state, created, now).await)
+                }
+            };
+            if let Err(e) = result {
+                log::error!("Session store failed: {:?}", e);
+            }
+            Ok(res)
+        })
+    }
+}
+
+impl Inner {
+    /// The session for the cookie id, unless it is unknown or expired.
+    async fn load(&self, id: String, now: i64) -> Option<(String, SessionRecord)> {
+        let record = match self.store.load(&id).await {
+            Ok(record) => record?,
+            Err(e) => {
+                // carries on without a session rather than failing the request
+                log::error!("Session store failed: {:?}", e);
+                return None;
+            }
+        };
+
+        let idle = now - record.last_seen >= self.idle_timeout.as_secs() as i64;
+        let too_old = now - record.created >= self.absolute_timeout.as_secs() as i64;
+        if idle || too_old {
+            if let Err(e) = self.store.delete(&id).await {
+                log::error!("Session store failed: {:?}", e);
+            }
+            return None;
+        }
+        Some((id, record))
+    }
+
+    async fn save(
+        &self,
+        id: &str,
+        state: HashMap<String, String>,
+        created: i64,
+        now: i64,
+    ) -> Result<(), StoreError> {
+        let user = user_of(&state, &self.user_key);
+        let record = SessionRecord {
+            state,
+            user,
+            created,
+            last_seen: now,
+        };
+        self.store.save(id, record).await
+    }
+
+    fn cookie(&self, id: String) -> Cookie<'static> {
+        Cookie::build(self.cookie_name.clone(), id)
+            .path("/")
+            .secure(self.secure)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish()
+    }
+}
+
+/// The user key's value, without the JSON quotes around strings.
+fn user_of(state: &HashMap<String, String>, key: &str) -> Option<String> {
+    let raw = state.get(key)?;
+    match serde_json::from_str(raw) {
+        Ok(serde_json::Value::String(user)) => Some(user),
+        _ => Some(raw.clone()),
+    }
+}
+
+fn new_id() -> String {
+    format!(
+        "{}{}",
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    )
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user: &str, created: i64, last_seen: i64) -> SessionRecord {
+        SessionRecord {
+            state: HashMap::new(),
+            user: Some(user.to_owned()),
+            created,
+            last_seen,
+        }
+    }
+
+    #[loony::test]
+    async fn memory_store() {
+        let store = MemoryStore::default();
+        store.save("a", record("alice", 0, 100)).await.unwrap();
+        store.save("b", record("alice", 50, 50)).await.unwrap();
+        store.save("c", record("bob", 100, 100)).await.unwrap();
+
+        // "b" went idle, "a" is too old
+        assert_eq!(store.remove_expired(60, 10).await.unwrap(), 2);
+        assert!(store.load("c").await.unwrap().is_some());
+
+        store.save("d", record("bob", 100, 100)).await.unwrap();
+        store.delete_user("bob").await.unwrap();
+        assert!(store.load("c").await.unwrap().is_none());
+        assert!(store.load("d").await.unwrap().is_none());
+    }
+
+    async fn touch_keeps_deleted_sessions_deleted(store: &dyn SessionStore) {
+        store.save("a", record("alice", 0, 100)).await.unwrap();
+        store.touch("a", 200).await.unwrap();
+        assert_eq!(store.load("a").await.unwrap().unwrap().last_seen, 200);
+
+        store.delete_user("alice").await.unwrap();
+        store.touch("a", 300).await.unwrap();
+        assert!(store.load("a").await.unwrap().is_none());
+    }
+
+    #[loony::test]
+    async fn touch_does_not_insert() {
+        touch_keeps_deleted_sessions_deleted(&MemoryStore::default()).await;
+
+        // one connection, every in-memory connection has its own database
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        touch_keeps_deleted_sessions_deleted(&SqliteStore::new(pool).unwrap()).await;
+    }
+
+    #[test]
+    fn user_of_strips_json_quotes() {
+        let mut state = HashMap::new();
+        state.insert("user_id".to_owned(), "\"alice\"".to_owned());
+        assert_eq!(user_of(&state, "user_id"), Some("alice".to_owned()));
+        state.insert("user_id".to_owned(), "42".to_owned());
+        assert_eq!(user_of(&state, "user_id"), Some("42".to_owned()));
+    }
+}
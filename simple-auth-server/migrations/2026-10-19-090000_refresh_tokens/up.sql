@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE refresh_tokens (
+  id UUID NOT NULL PRIMARY KEY,
+  user_email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  token_hash VARCHAR(64) NOT NULL UNIQUE, --sha256, the token itself is never stored
+  family UUID NOT NULL, --every token rotated from the same login
+  expires_at TIMESTAMP NOT NULL,
+  revoked_at TIMESTAMP,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX refresh_tokens_family ON refresh_tokens (family);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE password_resets (
+  id UUID NOT NULL UNIQUE PRIMARY KEY,
+  email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  expires_at TIMESTAMP NOT NULL
+);
+
+-- sessions started before the password changed are no longer valid. The app
+-- sets it with its own clock, the one it compares sessions with, so there is
+-- no default; existing accounts count as never changed.
+ALTER TABLE users ADD COLUMN password_changed_at TIMESTAMP NOT NULL DEFAULT '1970-01-01';
+ALTER TABLE users ALTER COLUMN password_changed_at DROP DEFAULT;
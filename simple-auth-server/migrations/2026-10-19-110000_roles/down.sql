@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users DROP COLUMN disabled;
+DROP TABLE user_roles;
+DROP TABLE role_permissions;
+DROP TABLE roles;
@@ -0,0 +1,26 @@
+-- Your SQL goes here
+CREATE TABLE roles (
+  name VARCHAR(50) NOT NULL PRIMARY KEY
+);
+
+CREATE TABLE role_permissions (
+  role VARCHAR(50) NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+  permission VARCHAR(50) NOT NULL,
+  PRIMARY KEY (role, permission)
+);
+
+CREATE TABLE user_roles (
+  user_email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  role VARCHAR(50) NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+  PRIMARY KEY (user_email, role)
+);
+
+ALTER TABLE users ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT false;
+
+INSERT INTO roles (name) VALUES ('admin'), ('user');
+INSERT INTO role_permissions (role, permission) VALUES
+  ('admin', 'invite'),
+  ('admin', 'manage_users');
+
+-- everybody registered so far is a plain user
+INSERT INTO user_roles (user_email, role) SELECT email, 'user' FROM users;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE auth_events;
+DROP FUNCTION auth_events_append_only();
@@ -0,0 +1,23 @@
+-- Your SQL goes here
+CREATE TABLE auth_events (
+  id BIGSERIAL PRIMARY KEY,
+  kind VARCHAR(50) NOT NULL,
+  -- the account the event is about, also for attempts on unknown addresses
+  user_email VARCHAR(100),
+  ip VARCHAR(45),
+  detail VARCHAR(200),
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX auth_events_user_email ON auth_events (user_email, created_at);
+CREATE INDEX auth_events_ip ON auth_events (ip, created_at);
+
+CREATE FUNCTION auth_events_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'auth_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER auth_events_append_only
+  BEFORE UPDATE OR DELETE ON auth_events
+  FOR EACH ROW EXECUTE PROCEDURE auth_events_append_only();
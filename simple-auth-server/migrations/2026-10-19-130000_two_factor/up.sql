@@ -0,0 +1,24 @@
+-- Your SQL goes here
+CREATE TABLE totp_credentials (
+  user_email VARCHAR(100) NOT NULL PRIMARY KEY REFERENCES users (email) ON DELETE CASCADE,
+  secret VARCHAR(64) NOT NULL, --base32
+  -- unconfirmed secrets are not asked for at login
+  confirmed_at TIMESTAMP,
+  -- a code can't be used twice
+  last_used_step BIGINT
+);
+
+CREATE TABLE recovery_codes (
+  id UUID NOT NULL PRIMARY KEY,
+  user_email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  hash VARCHAR(122) NOT NULL, --argon hash
+  used_at TIMESTAMP
+);
+
+-- logins that passed the password check and wait for the second factor
+CREATE TABLE login_challenges (
+  id UUID NOT NULL PRIMARY KEY,
+  user_email VARCHAR(100) NOT NULL REFERENCES users (email) ON DELETE CASCADE,
+  mode VARCHAR(10) NOT NULL,
+  expires_at TIMESTAMP NOT NULL
+);
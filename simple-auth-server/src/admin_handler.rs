@@ -0,0 +1,142 @@
+use diesel::{prelude::*, PgConnection};
+use loony::web::{self, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::auth_handler::LoggedUser;
+use crate::errors::ServiceError;
+use crate::models::{Pool, User};
+use crate::roles::{self, Permission};
+use crate::token;
+
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    pub email: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub disabled: bool,
+    pub roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RolesData {
+    pub roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DisabledData {
+    pub disabled: bool,
+}
+
+pub async fn list_users(
+    logged_user: LoggedUser,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    logged_user.require(Permission::ManageUsers)?;
+
+    let users = web::block(move || {
+        let conn: &PgConnection = &pool.get().unwrap();
+        all_users(conn)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(&users))
+}
+
+/// Replaces the roles of a user. Admins can't take the admin role away from
+/// themselves, so there is always somebody left to manage users.
+pub async fn set_roles(
+    logged_user: LoggedUser,
+    user_email: web::types::Path<String>,
+    roles_data: web::types::Json<RolesData>,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    logged_user.require(Permission::ManageUsers)?;
+
+    let user_email = user_email.into_inner();
+    let new_roles = roles_data.into_inner().roles;
+    if user_email == logged_user.email
+        && logged_user.has_role(roles::ADMIN)
+        && !new_roles.iter().any(|role| role == roles::ADMIN)
+    {
+        return Err(ServiceError::BadRequest(
+            "You can't remove your own admin role".into(),
+        ));
+    }
+
+    web::block(move || {
+        let conn: &PgConnection = &pool.get().unwrap();
+        find_user(&user_email, conn)?;
+        roles::set_roles(&user_email, &new_roles, conn)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Disabling an account rejects its cookie and ends its token logins.
+pub async fn set_disabled(
+    logged_user: LoggedUser,
+    user_email: web::types::Path<String>,
+    disabled_data: web::types::Json<DisabledData>,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    logged_user.require(Permission::ManageUsers)?;
+
+    let user_email = user_email.into_inner();
+    let new_disabled = disabled_data.into_inner().disabled;
+    if user_email == logged_user.email && new_disabled {
+        return Err(ServiceError::BadRequest(
+            "You can't disable your own account".into(),
+        ));
+    }
+
+    web::block(move || {
+        use crate::schema::users::dsl::{disabled, email, users};
+
+        let conn: &PgConnection = &pool.get().unwrap();
+        conn.transaction(|| {
+            find_user(&user_email, conn)?;
+            diesel::update(users.filter(email.eq(&user_email)))
+                .set(disabled.eq(new_disabled))
+                .execute(conn)?;
+            if new_disabled {
+                token::revoke_user(&user_email, conn)?;
+            }
+            Ok::<_, ServiceError>(())
+        })
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+fn all_users(conn: &PgConnection) -> Result<Vec<UserSummary>, ServiceError> {
+    use crate::schema::user_roles::dsl::{role, user_email, user_roles};
+    use crate::schema::users::dsl::{email, users};
+
+    let all = users.order(email).load::<User>(conn)?;
+    let granted = user_roles
+        .select((user_email, role))
+        .order(role)
+        .load::<(String, String)>(conn)?;
+
+    Ok(all
+        .into_iter()
+        .map(|user| UserSummary {
+            roles: granted
+                .iter()
+                .filter(|(eml, _)| *eml == user.email)
+                .map(|(_, r)| r.clone())
+                .collect(),
+            email: user.email,
+            created_at: user.created_at,
+            disabled: user.disabled,
+        })
+        .collect())
+}
+
+fn find_user(eml: &str, conn: &PgConnection) -> Result<User, ServiceError> {
+    use crate::schema::users::dsl::{email, users};
+
+    users
+        .filter(email.eq(eml))
+        .first::<User>(conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::BadRequest("Unknown user".into()))
+}
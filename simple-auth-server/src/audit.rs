@@ -0,0 +1,80 @@
+//! Append-only trail of what happened to accounts, in `auth_events`. The
+//! table refuses updates and deletes, and the login throttle counts failed
+//! attempts from it.
+use diesel::prelude::*;
+use diesel::PgConnection;
+
+use crate::errors::ServiceError;
+use crate::models::{AuthEvent, NewAuthEvent};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Login,
+    LoginFailed,
+    /// an attempt turned away by the throttle, the password wasn't checked
+    LoginLocked,
+    Logout,
+    Register,
+    Invite,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Login => "login",
+            EventKind::LoginFailed => "login_failed",
+            EventKind::LoginLocked => "login_locked",
+            EventKind::Logout => "logout",
+            EventKind::Register => "register",
+            EventKind::Invite => "invite",
+        }
+    }
+}
+
+/// What `/api/auth/events` shows a user about their account.
+const SIGN_IN_EVENTS: [EventKind; 4] = [
+    EventKind::Login,
+    EventKind::LoginFailed,
+    EventKind::LoginLocked,
+    EventKind::Logout,
+];
+
+pub fn record(
+    kind: EventKind,
+    user_email: Option<&str>,
+    ip: Option<&str>,
+    detail: Option<&str>,
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::auth_events::dsl::auth_events;
+
+    diesel::insert_into(auth_events)
+        .values(&NewAuthEvent {
+            kind: kind.as_str(),
+            user_email,
+            ip,
+            detail,
+            created_at: chrono::Local::now().naive_local(),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The latest sign-in related events of an account, newest first.
+pub fn recent_sign_ins(
+    eml: &str,
+    limit: i64,
+    conn: &PgConnection,
+) -> Result<Vec<AuthEvent>, ServiceError> {
+    use crate::schema::auth_events::dsl::{
+        auth_events, created_at, id, kind, user_email,
+    };
+
+    let kinds: Vec<&str> = SIGN_IN_EVENTS.iter().map(|k| k.as_str()).collect();
+    Ok(auth_events
+        .filter(user_email.eq(eml))
+        .filter(kind.eq_any(kinds))
+        .order((created_at.desc(), id.desc()))
+        .limit(limit)
+        .load(conn)?)
+}
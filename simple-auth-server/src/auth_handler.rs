@@ -1,87 +1,362 @@
+use std::future::Future;
+use std::pin::Pin;
+
 use diesel::prelude::*;
 use diesel::PgConnection;
-use futures::future::{ready, Ready};
-use loony::http::Payload;
-use loony::web::{
-    self, error::BlockingError, Error, FromRequest, HttpRequest, HttpResponse,
-};
+use futures::future::ready;
+use loony::http::{header, Payload};
+use loony::web::{self, Error, FromRequest, HttpRequest, HttpResponse};
 use loony_identity::{Identity, RequestIdentity};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use crate::audit::{self, EventKind};
 use crate::errors::ServiceError;
 use crate::models::{Pool, SlimUser, User};
+use crate::roles::{self, Permission};
+use crate::throttle;
+use crate::token;
+use crate::two_factor_handler;
 use crate::utils::verify;
 
 #[derive(Debug, Deserialize)]
 pub struct AuthData {
     pub email: String,
     pub password: String,
+    #[serde(default)]
+    pub mode: AuthMode,
+}
+
+/// `cookie` logs in with the identity cookie, `token` returns an access and
+/// a refresh token instead.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    Cookie,
+    Token,
+}
+
+impl Default for AuthMode {
+    fn default() -> Self {
+        AuthMode::Cookie
+    }
+}
+
+impl AuthMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthMode::Cookie => "cookie",
+            AuthMode::Token => "token",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "token" => AuthMode::Token,
+            _ => AuthMode::Cookie,
+        }
+    }
+}
+
+/// How far a login got: done, or waiting for the code from the
+/// authenticator app.
+enum LoginStep {
+    Done(SlimUser),
+    SecondFactor(uuid::Uuid),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshData {
+    pub refresh_token: String,
+}
+
+/// What the identity cookie holds. Cookies from before the user's last
+/// password change are rejected.
+#[derive(Debug, Serialize, Deserialize)]
+struct CookieIdentity {
+    email: String,
+    #[serde(default)]
+    since: i64,
 }
 
-// we need the same data
-// simple aliasing makes the intentions clear and its more readable
-pub type LoggedUser = SlimUser;
+/// The authenticated user with the roles and permissions granted to it.
+#[derive(Debug, Serialize)]
+pub struct LoggedUser {
+    pub email: String,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+impl LoggedUser {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    /// Fails with `403 Forbidden` unless one of the user's roles grants
+    /// `permission`.
+    pub fn require(&self, permission: Permission) -> Result<(), ServiceError> {
+        if self.permissions.iter().any(|p| p == permission.as_str()) {
+            Ok(())
+        } else {
+            Err(ServiceError::Forbidden)
+        }
+    }
+}
 
+/// Accepts either the identity cookie or an `Authorization: Bearer` access
+/// token whose login hasn't been revoked. Disabled users are rejected.
 impl<Err> FromRequest<Err> for LoggedUser {
     type Error = Error;
-    type Future = Ready<Result<LoggedUser, Error>>;
+    type Future = Pin<Box<dyn Future<Output = Result<LoggedUser, Error>>>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let id = req.get_identity();
+        let pool = req.app_data::<web::types::Data<Pool>>().cloned();
 
-        ready(if let Some(identity) = id {
-            serde_json::from_str::<LoggedUser>(&identity).map_err(From::from)
-        } else {
-            Err(ServiceError::Unauthorized.into())
+        if let Some(access_token) = bearer_token(req) {
+            let claims = match token::decode_access(access_token, false) {
+                Ok(claims) => claims,
+                Err(e) => return Box::pin(ready(Err(e.into()))),
+            };
+
+            return Box::pin(async move {
+                let pool = pool.ok_or(ServiceError::InternalServerError)?;
+                let user = web::block(move || {
+                    let conn: &PgConnection = &pool.get().unwrap();
+                    if !token::family_active(claims.fam, conn)? {
+                        return Ok(None);
+                    }
+                    load_logged_user(&claims.sub, conn)
+                })
+                .await
+                .map_err(ServiceError::from)?;
+
+                match user {
+                    Some((user, _)) => Ok(user),
+                    None => Err(ServiceError::Unauthorized.into()),
+                }
+            });
+        }
+
+        let identity = req
+            .get_identity()
+            .and_then(|id| serde_json::from_str::<CookieIdentity>(&id).ok());
+        let identity = match identity {
+            Some(identity) => identity,
+            None => return Box::pin(ready(Err(ServiceError::Unauthorized.into()))),
+        };
+
+        Box::pin(async move {
+            let pool = pool.ok_or(ServiceError::InternalServerError)?;
+            let eml = identity.email;
+            let user = web::block(move || {
+                let conn: &PgConnection = &pool.get().unwrap();
+                load_logged_user(&eml, conn)
+            })
+            .await
+            .map_err(ServiceError::from)?;
+
+            match user {
+                Some((user, changed)) if changed.timestamp() <= identity.since => {
+                    Ok(user)
+                }
+                _ => Err(ServiceError::Unauthorized.into()),
+            }
         })
     }
 }
 
-pub async fn logout(id: Identity) -> HttpResponse {
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Forgets the cookie and revokes the token login, identified by the access
+/// token or a `refresh_token` in the body. An expired access token is
+/// enough to log out.
+pub async fn logout(
+    id: Identity,
+    req: HttpRequest,
+    refresh_data: Option<web::types::Json<RefreshData>>,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let cookie_email = id
+        .identity()
+        .and_then(|id| serde_json::from_str::<CookieIdentity>(&id).ok())
+        .map(|identity| identity.email);
     id.forget();
-    HttpResponse::Ok().finish()
+
+    let claims = bearer_token(&req)
+        .and_then(|access_token| token::decode_access(access_token, true).ok());
+    let refresh_token = refresh_data.map(|data| data.into_inner().refresh_token);
+    let ip = peer_ip(&req);
+
+    if cookie_email.is_some() || claims.is_some() || refresh_token.is_some() {
+        web::block(move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            if let Some(claims) = &claims {
+                token::revoke_family(claims.fam, conn)?;
+            }
+            if let Some(refresh_token) = refresh_token {
+                token::revoke(&refresh_token, conn)?;
+            }
+            let eml = cookie_email.or_else(|| claims.map(|claims| claims.sub));
+            if let Some(eml) = eml {
+                audit::record(EventKind::Logout, Some(&eml), ip.as_deref(), None, conn)?;
+            }
+            Ok::<_, ServiceError>(())
+        })
+        .await?;
+    }
+    Ok(HttpResponse::Ok().finish())
 }
 
+/// Failed attempts lock the account and the client's IP out for a while,
+/// see `throttle`. Users with two-factor authentication get a challenge to
+/// complete at `/api/auth/second-factor` instead.
 pub async fn login(
+    req: HttpRequest,
     auth_data: web::types::Json<AuthData>,
     id: Identity,
     pool: web::types::Data<Pool>,
 ) -> Result<HttpResponse, ServiceError> {
-    let res = web::block(move || query(auth_data.into_inner(), pool)).await;
+    let auth_data = auth_data.into_inner();
+    let mode = auth_data.mode;
+    let ip = peer_ip(&req);
+    let db = pool.clone();
+
+    match web::block(move || query(auth_data, ip, db)).await? {
+        LoginStep::Done(user) => finish_login(mode, user.email, id, pool).await,
+        LoginStep::SecondFactor(challenge) => {
+            Ok(HttpResponse::Ok().json(&serde_json::json!({
+                "second_factor_required": true,
+                "challenge": challenge,
+            })))
+        }
+    }
+}
 
-    match res {
-        Ok(user) => {
-            let user_string = serde_json::to_string(&user).unwrap();
-            id.remember(user_string);
+/// Hands out the cookie or the tokens once every factor has been checked.
+pub async fn finish_login(
+    mode: AuthMode,
+    eml: String,
+    id: Identity,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    match mode {
+        AuthMode::Cookie => {
+            let identity = CookieIdentity {
+                email: eml,
+                since: chrono::Local::now().naive_local().timestamp(),
+            };
+            id.remember(serde_json::to_string(&identity).unwrap());
             Ok(HttpResponse::Ok().finish())
         }
-        Err(err) => match err {
-            BlockingError::Error(service_error) => Err(service_error),
-            BlockingError::Canceled => Err(ServiceError::InternalServerError),
-        },
+        AuthMode::Token => {
+            let tokens = web::block(move || {
+                let conn: &PgConnection = &pool.get().unwrap();
+                token::login(&eml, conn)
+            })
+            .await?;
+            Ok(HttpResponse::Ok().json(&tokens))
+        }
     }
 }
 
+/// Exchanges a refresh token for a new access and refresh token.
+pub async fn refresh(
+    refresh_data: web::types::Json<RefreshData>,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let tokens = web::block(move || {
+        let conn: &PgConnection = &pool.get().unwrap();
+        token::refresh(&refresh_data.refresh_token, conn)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(&tokens))
+}
+
 pub async fn get_me(logged_user: LoggedUser) -> HttpResponse {
     HttpResponse::Ok().json(&logged_user)
 }
+
+/// The latest sign-ins, failed attempts and logouts of the logged in user.
+pub async fn get_events(
+    logged_user: LoggedUser,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let events = web::block(move || {
+        let conn: &PgConnection = &pool.get().unwrap();
+        audit::recent_sign_ins(&logged_user.email, 50, conn)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(&events))
+}
+
+fn peer_ip(req: &HttpRequest) -> Option<String> {
+    req.peer_addr().map(|addr| addr.ip().to_string())
+}
+
 /// Diesel query
 fn query(
     auth_data: AuthData,
+    ip: Option<String>,
     pool: web::types::Data<Pool>,
-) -> Result<SlimUser, ServiceError> {
+) -> Result<LoginStep, ServiceError> {
     use crate::schema::users::dsl::{email, users};
     let conn: &PgConnection = &pool.get().unwrap();
-    let mut items = users
-        .filter(email.eq(&auth_data.email))
-        .load::<User>(conn)?;
+    let eml = auth_data.email.as_str();
+    let ip = ip.as_deref();
+
+    if let Err(locked) = throttle::check(eml, ip, conn) {
+        audit::record(EventKind::LoginLocked, Some(eml), ip, None, conn)?;
+        return Err(locked);
+    }
+
+    let mut items = users.filter(email.eq(eml)).load::<User>(conn)?;
 
-    if let Some(user) = items.pop() {
+    if let Some(user) = items.pop().filter(|user| !user.disabled) {
         if let Ok(matching) = verify(&user.hash, &auth_data.password) {
             if matching {
-                return Ok(user.into());
+                if two_factor_handler::enabled(eml, conn)? {
+                    let challenge =
+                        two_factor_handler::start_challenge(eml, auth_data.mode, conn)?;
+                    return Ok(LoginStep::SecondFactor(challenge));
+                }
+                audit::record(EventKind::Login, Some(eml), ip, None, conn)?;
+                return Ok(LoginStep::Done(user.into()));
             }
         }
     }
+    audit::record(EventKind::LoginFailed, Some(eml), ip, None, conn)?;
     Err(ServiceError::Unauthorized)
 }
+
+/// Loads an enabled user with its roles and permissions, along with when
+/// its password last changed.
+fn load_logged_user(
+    eml: &str,
+    conn: &PgConnection,
+) -> Result<Option<(LoggedUser, chrono::NaiveDateTime)>, ServiceError> {
+    use crate::schema::users::dsl::{disabled, email, password_changed_at, users};
+
+    let changed = users
+        .filter(email.eq(eml))
+        .filter(disabled.eq(false))
+        .select(password_changed_at)
+        .first::<chrono::NaiveDateTime>(conn)
+        .optional()?;
+    let changed = match changed {
+        Some(changed) => changed,
+        None => return Ok(None),
+    };
+
+    let granted = roles::roles_of(eml, conn)?;
+    let permissions = roles::permissions_of(&granted, conn)?;
+    let user = LoggedUser {
+        email: eml.to_owned(),
+        roles: granted,
+        permissions,
+    };
+    Ok(Some((user, changed)))
+}
@@ -1,70 +1,341 @@
 // email_service.rs
-use crate::errors::ServiceError;
-use crate::models::Invitation;
+use std::path::PathBuf;
+
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{FileTransport, SmtpTransport, Transport};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Serialize;
 use sparkpost::transmission::{
     EmailAddress, Message, Options, Recipient, Transmission, TransmissionResponse,
 };
+use tera::{Context, Tera};
 
-lazy_static::lazy_static! {
-static ref API_KEY: String = std::env::var("SPARKPOST_API_KEY").expect("SPARKPOST_API_KEY must be set");
-}
-
-pub fn send_invitation(invitation: &Invitation) -> Result<(), ServiceError> {
-    let tm = Transmission::new_eu(API_KEY.as_str());
-    let sending_email = std::env::var("SENDING_EMAIL_ADDRESS")
-        .expect("SENDING_EMAIL_ADDRESS must be set");
-    // new email message with sender name and email
-    let mut email = Message::new(EmailAddress::new(sending_email, "Let's Organise"));
-
-    let options = Options {
-        open_tracking: false,
-        click_tracking: false,
-        transactional: true,
-        sandbox: false,
-        inline_css: false,
-        start_time: None,
-    };
-
-    // recipient from the invitation email
-    let recipient: Recipient = invitation.email.as_str().into();
-
-    let email_body = format!(
-        "Please click on the link below to complete registration. <br/>
-         <a href=\"http://localhost:3000/register.html?id={}&email={}\">
-         http://localhost:3030/register</a> <br>
-         your Invitation expires on <strong>{}</strong>",
-        invitation.id,
-        invitation.email,
-        invitation
-            .expires_at
-            .format("%I:%M %p %A, %-d %B, %C%y")
-            .to_string()
-    );
-
-    // complete the email message with details
-    email
-        .add_recipient(recipient)
-        .options(options)
-        .subject("You have been invited to join Simple-Auth-Server Rust")
-        .html(email_body);
-
-    let result = tm.send(&email);
-
-    // Note that we only print out the error response from email api
-    match result {
-        Ok(res) => match res {
-            TransmissionResponse::ApiResponse(api_res) => {
-                println!("API Response: \n {:#?}", api_res);
-                Ok(())
-            }
-            TransmissionResponse::ApiError(errors) => {
-                println!("Response Errors: \n {:#?}", &errors);
+use crate::errors::ServiceError;
+use crate::models::{Invitation, PasswordReset};
+
+const SENDER_NAME: &str = "Let's Organise";
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Delivers emails, picked with `EMAIL_TRANSPORT`.
+pub trait EmailSender: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), ServiceError>;
+}
+
+pub struct SparkPostSender {
+    api_key: String,
+    from: String,
+}
+
+impl EmailSender for SparkPostSender {
+    fn send(&self, email: &Email) -> Result<(), ServiceError> {
+        let tm = Transmission::new_eu(self.api_key.as_str());
+        // new email message with sender name and email
+        let mut message =
+            Message::new(EmailAddress::new(self.from.as_str(), SENDER_NAME));
+
+        let options = Options {
+            open_tracking: false,
+            click_tracking: false,
+            transactional: true,
+            sandbox: false,
+            inline_css: false,
+            start_time: None,
+        };
+
+        let recipient: Recipient = email.to.as_str().into();
+
+        // complete the email message with details
+        message
+            .add_recipient(recipient)
+            .options(options)
+            .subject(email.subject.as_str())
+            .text(email.text.as_str())
+            .html(email.html.as_str());
+
+        let result = tm.send(&message);
+
+        // Note that we only print out the error response from email api
+        match result {
+            Ok(res) => match res {
+                TransmissionResponse::ApiResponse(api_res) => {
+                    println!("API Response: \n {:#?}", api_res);
+                    Ok(())
+                }
+                TransmissionResponse::ApiError(errors) => {
+                    println!("Response Errors: \n {:#?}", &errors);
+                    Err(ServiceError::InternalServerError)
+                }
+            },
+            Err(error) => {
+                println!("Send Email Error: \n {:#?}", error);
                 Err(ServiceError::InternalServerError)
             }
-        },
-        Err(error) => {
+        }
+    }
+}
+
+pub struct SmtpSender {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl EmailSender for SmtpSender {
+    fn send(&self, email: &Email) -> Result<(), ServiceError> {
+        let message = build_message(&self.from, email)?;
+        self.transport.send(&message).map(|_| ()).map_err(|error| {
             println!("Send Email Error: \n {:#?}", error);
-            Err(ServiceError::InternalServerError)
+            ServiceError::InternalServerError
+        })
+    }
+}
+
+/// Writes every email to `<dir>/<id>.eml` instead of sending it, for
+/// development and tests.
+pub struct FileSender {
+    transport: FileTransport,
+    from: Mailbox,
+}
+
+impl FileSender {
+    pub fn new<P: Into<PathBuf>>(dir: P, from: Mailbox) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(FileSender {
+            transport: FileTransport::new(dir),
+            from,
+        })
+    }
+}
+
+impl EmailSender for FileSender {
+    fn send(&self, email: &Email) -> Result<(), ServiceError> {
+        let message = build_message(&self.from, email)?;
+        self.transport.send(&message).map(|_| ()).map_err(|error| {
+            println!("Write Email Error: \n {:#?}", error);
+            ServiceError::InternalServerError
+        })
+    }
+}
+
+fn build_message(
+    from: &Mailbox,
+    email: &Email,
+) -> Result<lettre::Message, ServiceError> {
+    let to = email
+        .to
+        .parse::<Mailbox>()
+        .map_err(|_| ServiceError::BadRequest("Invalid email address".into()))?;
+
+    lettre::Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.subject.as_str())
+        .multipart(MultiPart::alternative_plain_html(
+            email.text.clone(),
+            email.html.clone(),
+        ))
+        .map_err(|error| {
+            println!("Build Email Error: \n {:#?}", error);
+            ServiceError::InternalServerError
+        })
+}
+
+/// Renders the emails from `templates/email` and hands them to the sender.
+pub struct Mailer {
+    sender: Box<dyn EmailSender>,
+    templates: Tera,
+    /// where links in emails point to, `APP_URL`
+    app_url: String,
+}
+
+impl Mailer {
+    pub fn new(sender: Box<dyn EmailSender>, app_url: &str) -> Result<Self, String> {
+        let templates = Tera::new("templates/email/**/*")
+            .map_err(|e| format!("email templates: {}", e))?;
+        Ok(Mailer {
+            sender,
+            templates,
+            app_url: app_url.trim_end_matches('/').to_owned(),
+        })
+    }
+
+    /// Configures the mailer from the environment:
+    ///
+    /// - `EMAIL_TRANSPORT`: `file` (default), `smtp` or `sparkpost`
+    /// - `EMAIL_DIR`: where `file` writes to, `./emails` by default
+    /// - `SMTP_HOST`, `SMTP_USERNAME`, `SMTP_PASSWORD`
+    /// - `SPARKPOST_API_KEY`
+    /// - `SENDING_EMAIL_ADDRESS`, `APP_URL`
+    pub fn from_env() -> Result<Self, String> {
+        let env = |name: &str| {
+            std::env::var(name).map_err(|_| format!("{} must be set", name))
+        };
+        let transport =
+            std::env::var("EMAIL_TRANSPORT").unwrap_or_else(|_| "file".into());
+        let from_address = std::env::var("SENDING_EMAIL_ADDRESS")
+            .unwrap_or_else(|_| "noreply@localhost".into());
+        let from = Mailbox::new(
+            Some(SENDER_NAME.to_owned()),
+            from_address.parse().map_err(|_| {
+                "SENDING_EMAIL_ADDRESS is not an email address".to_owned()
+            })?,
+        );
+
+        let sender: Box<dyn EmailSender> = match transport.as_str() {
+            "file" => {
+                let dir = std::env::var("EMAIL_DIR").unwrap_or_else(|_| "emails".into());
+                Box::new(FileSender::new(dir, from).map_err(|e| e.to_string())?)
+            }
+            "smtp" => {
+                let transport = SmtpTransport::relay(&env("SMTP_HOST")?)
+                    .map_err(|e| e.to_string())?
+                    .credentials(Credentials::new(
+                        env("SMTP_USERNAME")?,
+                        env("SMTP_PASSWORD")?,
+                    ))
+                    .build();
+                Box::new(SmtpSender { transport, from })
+            }
+            "sparkpost" => Box::new(SparkPostSender {
+                api_key: env("SPARKPOST_API_KEY")?,
+                from: from_address,
+            }),
+            other => return Err(format!("unknown EMAIL_TRANSPORT {}", other)),
+        };
+
+        let app_url =
+            std::env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".into());
+        Mailer::new(sender, &app_url)
+    }
+
+    pub fn send_invitation(&self, invitation: &Invitation) -> Result<(), ServiceError> {
+        // addresses may contain `+` or `&`, which mean something else in a query
+        let link = format!(
+            "{}/register.html?id={}&email={}",
+            self.app_url,
+            invitation.id,
+            utf8_percent_encode(&invitation.email, NON_ALPHANUMERIC)
+        );
+        self.send(
+            "invitation",
+            &invitation.email,
+            "You have been invited to join Simple-Auth-Server Rust",
+            &Link {
+                link,
+                expires_at: format_expiry(invitation.expires_at),
+            },
+        )
+    }
+
+    pub fn send_password_reset(
+        &self,
+        reset: &PasswordReset,
+    ) -> Result<(), ServiceError> {
+        let link = format!("{}/reset.html?id={}", self.app_url, reset.id);
+        self.send(
+            "password_reset",
+            &reset.email,
+            "Reset your Simple-Auth-Server Rust password",
+            &Link {
+                link,
+                expires_at: format_expiry(reset.expires_at),
+            },
+        )
+    }
+
+    fn send<T: Serialize>(
+        &self,
+        template: &str,
+        to: &str,
+        subject: &str,
+        data: &T,
+    ) -> Result<(), ServiceError> {
+        let context = Context::from_serialize(data).map_err(render_error)?;
+        let email = Email {
+            to: to.to_owned(),
+            subject: subject.to_owned(),
+            text: self
+                .templates
+                .render(&format!("{}.txt", template), &context)
+                .map_err(render_error)?,
+            html: self
+                .templates
+                .render(&format!("{}.html", template), &context)
+                .map_err(render_error)?,
+        };
+        self.sender.send(&email)
+    }
+}
+
+#[derive(Serialize)]
+struct Link {
+    link: String,
+    expires_at: String,
+}
+
+fn format_expiry(expires_at: chrono::NaiveDateTime) -> String {
+    expires_at.format("%I:%M %p %A, %-d %B, %C%y").to_string()
+}
+
+fn render_error(error: tera::Error) -> ServiceError {
+    println!("Render Email Error: \n {:#?}", error);
+    ServiceError::InternalServerError
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Keeps the emails instead of sending them.
+    #[derive(Clone, Default)]
+    struct Outbox(Arc<Mutex<Vec<Email>>>);
+
+    impl EmailSender for Outbox {
+        fn send(&self, email: &Email) -> Result<(), ServiceError> {
+            self.0.lock().unwrap().push(Email {
+                to: email.to.clone(),
+                subject: email.subject.clone(),
+                text: email.text.clone(),
+                html: email.html.clone(),
+            });
+            Ok(())
         }
     }
+
+    #[test]
+    fn invitation_link_encodes_the_email() {
+        let outbox = Outbox::default();
+        let mailer =
+            Mailer::new(Box::new(outbox.clone()), "http://localhost:3000/").unwrap();
+        let invitation = Invitation::from("jane+test&x=1@example.com");
+
+        mailer.send_invitation(&invitation).unwrap();
+
+        let sent = outbox.0.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "jane+test&x=1@example.com");
+        assert_eq!(
+            sent[0].subject,
+            "You have been invited to join Simple-Auth-Server Rust"
+        );
+        let link = format!(
+            "http://localhost:3000/register.html?id={}\
+             &email=jane%2Btest%26x%3D1%40example%2Ecom",
+            invitation.id
+        );
+        assert!(sent[0].text.contains(&link));
+        // html escaped, but the address can't end the parameter early either
+        assert!(sent[0]
+            .html
+            .contains("&amp;email=jane%2Btest%26x%3D1%40example%2Ecom\""));
+    }
 }
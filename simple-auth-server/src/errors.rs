@@ -1,5 +1,7 @@
 use derive_more::Display;
 use diesel::result::{DatabaseErrorKind, Error as DBError};
+use loony::http::header;
+use loony::web::error::BlockingError;
 use loony::web::{HttpRequest, HttpResponse, WebResponseError};
 use std::convert::From;
 use uuid::Error as ParseError;
@@ -14,6 +16,13 @@ pub enum ServiceError {
 
     #[display(fmt = "Unauthorized")]
     Unauthorized,
+
+    #[display(fmt = "Forbidden")]
+    Forbidden,
+
+    /// carries the seconds until the next attempt is allowed
+    #[display(fmt = "Too Many Requests")]
+    TooManyRequests(i64),
 }
 
 // impl ResponseError trait allows to convert our errors into http responses with appropriate data
@@ -28,6 +37,12 @@ impl WebResponseError for ServiceError {
             ServiceError::Unauthorized => {
                 HttpResponse::Unauthorized().json(&"Unauthorized")
             }
+            ServiceError::Forbidden => HttpResponse::Forbidden().json(&"Forbidden"),
+            ServiceError::TooManyRequests(retry_after) => {
+                HttpResponse::TooManyRequests()
+                    .header(header::RETRY_AFTER, retry_after.to_string())
+                    .json(&"Too many failed attempts, try again later")
+            }
         }
     }
 }
@@ -57,3 +72,12 @@ impl From<DBError> for ServiceError {
         }
     }
 }
+
+impl From<BlockingError<ServiceError>> for ServiceError {
+    fn from(error: BlockingError<ServiceError>) -> ServiceError {
+        match error {
+            BlockingError::Error(service_error) => service_error,
+            BlockingError::Canceled => ServiceError::InternalServerError,
+        }
+    }
+}
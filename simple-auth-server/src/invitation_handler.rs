@@ -2,9 +2,12 @@ use diesel::{prelude::*, PgConnection};
 use loony::web::{self, error::BlockingError, HttpResponse};
 use serde::Deserialize;
 
-use crate::email_service::send_invitation;
+use crate::audit::{self, EventKind};
+use crate::auth_handler::LoggedUser;
+use crate::email_service::Mailer;
 use crate::errors::ServiceError;
 use crate::models::{Invitation, Pool};
+use crate::roles::{self, Permission};
 
 #[derive(Deserialize)]
 pub struct InvitationData {
@@ -12,13 +15,23 @@ pub struct InvitationData {
 }
 
 pub async fn post_invitation(
+    logged_user: LoggedUser,
     invitation_data: web::types::Json<InvitationData>,
     pool: web::types::Data<Pool>,
+    mailer: web::types::Data<Mailer>,
 ) -> Result<HttpResponse, ServiceError> {
+    logged_user.require(Permission::Invite)?;
+
     // run diesel blocking code
-    let res =
-        web::block(move || create_invitation(invitation_data.into_inner().email, pool))
-            .await;
+    let res = web::block(move || {
+        create_invitation(
+            invitation_data.into_inner().email,
+            Some(&logged_user.email),
+            pool,
+            &mailer,
+        )
+    })
+    .await;
 
     match res {
         Ok(_) => Ok(HttpResponse::Ok().finish()),
@@ -31,10 +44,44 @@ pub async fn post_invitation(
 
 fn create_invitation(
     eml: String,
+    invited_by: Option<&str>,
     pool: web::types::Data<Pool>,
+    mailer: &Mailer,
 ) -> Result<(), crate::errors::ServiceError> {
-    let invitation = dbg!(query(eml, pool)?);
-    send_invitation(&invitation)
+    let invitation = query(eml, pool.clone())?;
+    let conn: &PgConnection = &pool.get().unwrap();
+    audit::record(
+        EventKind::Invite,
+        Some(&invitation.email),
+        None,
+        invited_by,
+        conn,
+    )?;
+    mailer.send_invitation(&invitation)
+}
+
+/// Makes sure there is a way to get an admin: while there is none,
+/// `ADMIN_EMAIL` is made admin if it has an account, or invited otherwise.
+pub fn bootstrap_admin(
+    admin_email: String,
+    pool: web::types::Data<Pool>,
+    mailer: &Mailer,
+) -> Result<(), ServiceError> {
+    use crate::schema::users::dsl::{email, users};
+
+    let conn: &PgConnection = &pool.get().unwrap();
+    if roles::admin_exists(conn)? {
+        return Ok(());
+    }
+    let registered = users
+        .filter(email.eq(&admin_email))
+        .count()
+        .get_result::<i64>(conn)?;
+    if registered > 0 {
+        roles::grant(&admin_email, roles::ADMIN, conn)
+    } else {
+        create_invitation(admin_email, None, pool.clone(), mailer)
+    }
 }
 
 /// Diesel query
@@ -6,13 +6,21 @@ use diesel::r2d2::{self, ConnectionManager};
 use loony::web::{self, middleware, App};
 use loony_identity::{CookieIdentityPolicy, IdentityService};
 
+mod admin_handler;
+mod audit;
 mod auth_handler;
 mod email_service;
 mod errors;
 mod invitation_handler;
 mod models;
+mod password_reset_handler;
 mod register_handler;
+mod roles;
 mod schema;
+mod throttle;
+mod token;
+mod totp;
+mod two_factor_handler;
 mod utils;
 
 #[loony::main]
@@ -20,10 +28,20 @@ async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
     std::env::set_var(
         "RUST_LOG",
-        "simple-auth-server=debug,actix_web=info,actix_server=info",
+        "simple_auth_server=debug,actix_web=info,actix_server=info",
     );
     env_logger::init();
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    // SECRET_KEY also hashes passwords and signs tokens, so check it up front
+    if let Some(problem) = cookie_keys::weakness(utils::SECRET_KEY.as_bytes()) {
+        if !cookie_keys::insecure_allowed() {
+            panic!(
+                "refusing a {} SECRET_KEY, set ALLOW_INSECURE_KEYS=1 to use it anyway",
+                problem
+            );
+        }
+    }
+    let keys = cookie_keys::Keyring::from_env().expect("Failed to load cookie keys");
 
     // create db connection pool
     let manager = ConnectionManager::<PgConnection>::new(database_url);
@@ -32,33 +50,64 @@ async fn main() -> std::io::Result<()> {
         .expect("Failed to create pool.");
     let domain: String =
         std::env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    let mailer = web::types::Data::new(
+        email_service::Mailer::from_env().expect("Failed to configure email"),
+    );
+    if let Ok(admin_email) = std::env::var("ADMIN_EMAIL") {
+        let pool = web::types::Data::new(pool.clone());
+        invitation_handler::bootstrap_admin(admin_email, pool, &mailer)
+            .expect("Failed to set up the admin account");
+    }
 
     // Start http server
     web::server(move || {
         App::new()
             .data(pool.clone())
+            .app_data(mailer.clone())
             // enable logger
             .wrap(middleware::Logger::default())
             .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(utils::SECRET_KEY.as_bytes())
+                CookieIdentityPolicy::new(keys.active())
                     .name("auth")
                     .path("/")
                     .domain(domain.as_str())
                     .max_age_time(time::Duration::days(1))
                     .secure(false), // this can only be true if you have https
             ))
+            .wrap(keys.rotation().private("auth"))
             .app_data(web::types::JsonConfig::default().limit(4096))
             // everything under '/api/' route
             .service(
                 web::scope("/api").service((
                     web::resource("/invitation")
                         .route(web::post().to(invitation_handler::post_invitation)),
+                    web::resource("/password-reset")
+                        .route(web::post().to(password_reset_handler::request_reset)),
+                    web::resource("/password-reset/{reset_id}")
+                        .route(web::post().to(password_reset_handler::reset_password)),
                     web::resource("/register/{invitation_id}")
                         .route(web::post().to(register_handler::register_user)),
                     web::resource("/auth")
                         .route(web::post().to(auth_handler::login))
                         .route(web::delete().to(auth_handler::logout))
                         .route(web::get().to(auth_handler::get_me)),
+                    web::resource("/auth/refresh")
+                        .route(web::post().to(auth_handler::refresh)),
+                    web::resource("/auth/second-factor")
+                        .route(web::post().to(two_factor_handler::second_factor)),
+                    web::resource("/auth/totp")
+                        .route(web::post().to(two_factor_handler::enroll))
+                        .route(web::delete().to(two_factor_handler::disable)),
+                    web::resource("/auth/totp/confirm")
+                        .route(web::post().to(two_factor_handler::confirm)),
+                    web::resource("/auth/events")
+                        .route(web::get().to(auth_handler::get_events)),
+                    web::resource("/admin/users")
+                        .route(web::get().to(admin_handler::list_users)),
+                    web::resource("/admin/users/{email}/roles")
+                        .route(web::put().to(admin_handler::set_roles)),
+                    web::resource("/admin/users/{email}/disabled")
+                        .route(web::put().to(admin_handler::set_disabled)),
                 )),
             )
     })
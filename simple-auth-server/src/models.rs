@@ -11,14 +11,19 @@ pub struct User {
     pub email: String,
     pub hash: String,
     pub created_at: chrono::NaiveDateTime,
+    pub password_changed_at: chrono::NaiveDateTime,
+    pub disabled: bool,
 }
 
 impl User {
     pub fn from_details<S: Into<String>, T: Into<String>>(email: S, pwd: T) -> Self {
+        let now = chrono::Local::now().naive_local();
         User {
             email: email.into(),
             hash: pwd.into(),
-            created_at: chrono::Local::now().naive_local(),
+            created_at: now,
+            password_changed_at: now,
+            disabled: false,
         }
     }
 }
@@ -45,6 +50,95 @@ where
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "password_resets"]
+pub struct PasswordReset {
+    pub id: uuid::Uuid,
+    pub email: String,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+impl<T> From<T> for PasswordReset
+where
+    T: Into<String>,
+{
+    fn from(email: T) -> Self {
+        PasswordReset {
+            id: uuid::Uuid::new_v4(),
+            email: email.into(),
+            expires_at: chrono::Local::now().naive_local() + chrono::Duration::hours(1),
+        }
+    }
+}
+
+#[derive(Debug, Queryable, Insertable)]
+#[table_name = "refresh_tokens"]
+pub struct RefreshToken {
+    pub id: uuid::Uuid,
+    pub user_email: String,
+    pub token_hash: String,
+    pub family: uuid::Uuid,
+    pub expires_at: chrono::NaiveDateTime,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "user_roles"]
+pub struct UserRole {
+    pub user_email: String,
+    pub role: String,
+}
+
+#[derive(Debug, Queryable, Insertable)]
+#[table_name = "totp_credentials"]
+pub struct TotpCredential {
+    pub user_email: String,
+    pub secret: String,
+    pub confirmed_at: Option<chrono::NaiveDateTime>,
+    pub last_used_step: Option<i64>,
+}
+
+#[derive(Debug, Queryable, Insertable)]
+#[table_name = "recovery_codes"]
+pub struct RecoveryCode {
+    pub id: uuid::Uuid,
+    pub user_email: String,
+    pub hash: String,
+    pub used_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Queryable, Insertable)]
+#[table_name = "login_challenges"]
+pub struct LoginChallenge {
+    pub id: uuid::Uuid,
+    pub user_email: String,
+    pub mode: String,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Queryable)]
+pub struct AuthEvent {
+    pub id: i64,
+    pub kind: String,
+    pub user_email: Option<String>,
+    pub ip: Option<String>,
+    pub detail: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "auth_events"]
+pub struct NewAuthEvent<'a> {
+    pub kind: &'a str,
+    pub user_email: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub detail: Option<&'a str>,
+    /// set here rather than by the database, the throttle compares it with
+    /// the app's clock
+    pub created_at: chrono::NaiveDateTime,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SlimUser {
     pub email: String,
@@ -0,0 +1,112 @@
+use diesel::{prelude::*, PgConnection};
+use loony::web::{self, HttpResponse};
+use serde::Deserialize;
+
+use crate::email_service::Mailer;
+use crate::errors::ServiceError;
+use crate::models::{PasswordReset, Pool};
+use crate::token;
+use crate::utils::hash_password;
+
+#[derive(Deserialize)]
+pub struct ResetRequestData {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetData {
+    pub password: String,
+}
+
+/// Always answers the same, and sends the email in the background, so the
+/// response doesn't tell whether the address has an account.
+pub async fn request_reset(
+    reset_data: web::types::Json<ResetRequestData>,
+    pool: web::types::Data<Pool>,
+    mailer: web::types::Data<Mailer>,
+) -> HttpResponse {
+    let email = reset_data.into_inner().email;
+    loony::rt::spawn(async move {
+        if let Err(err) = web::block(move || create_reset(email, pool, &mailer)).await {
+            log::error!("Password reset failed: {:?}", err);
+        }
+    });
+
+    HttpResponse::Ok().json(&"If the address has an account, a reset link is on its way")
+}
+
+pub async fn reset_password(
+    reset_id: web::types::Path<String>,
+    reset_data: web::types::Json<ResetData>,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    web::block(move || {
+        confirm_reset(
+            reset_id.into_inner(),
+            reset_data.into_inner().password,
+            pool,
+        )
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(&"Your password has been changed"))
+}
+
+fn create_reset(
+    eml: String,
+    pool: web::types::Data<Pool>,
+    mailer: &Mailer,
+) -> Result<(), ServiceError> {
+    use crate::schema::password_resets::dsl::password_resets;
+    use crate::schema::users::dsl::{email, users};
+
+    let conn: &PgConnection = &pool.get().unwrap();
+    let known = users
+        .filter(email.eq(&eml))
+        .count()
+        .get_result::<i64>(conn)?;
+    if known == 0 {
+        return Ok(());
+    }
+
+    let new_reset: PasswordReset = eml.into();
+    let reset: PasswordReset = diesel::insert_into(password_resets)
+        .values(&new_reset)
+        .get_result(conn)?;
+    mailer.send_password_reset(&reset)
+}
+
+/// Sets the new password, uses up every reset link of the user and ends
+/// all their sessions.
+fn confirm_reset(
+    reset_id: String,
+    password: String,
+    pool: web::types::Data<Pool>,
+) -> Result<(), ServiceError> {
+    use crate::schema::password_resets::dsl::{self as resets, password_resets};
+    use crate::schema::users::dsl::{email, hash, password_changed_at, users};
+
+    let invalid = || ServiceError::BadRequest("Invalid or expired reset link".into());
+    let reset_id = uuid::Uuid::parse_str(&reset_id).map_err(|_| invalid())?;
+    let conn: &PgConnection = &pool.get().unwrap();
+
+    conn.transaction(|| {
+        let reset = password_resets
+            .find(reset_id)
+            .for_update()
+            .first::<PasswordReset>(conn)
+            .optional()?
+            .filter(|reset| reset.expires_at > chrono::Local::now().naive_local())
+            .ok_or_else(invalid)?;
+
+        diesel::delete(password_resets.filter(resets::email.eq(&reset.email)))
+            .execute(conn)?;
+        diesel::update(users.filter(email.eq(&reset.email)))
+            .set((
+                hash.eq(hash_password(&password)?),
+                password_changed_at.eq(chrono::Local::now().naive_local()),
+            ))
+            .execute(conn)?;
+        token::revoke_user(&reset.email, conn)
+    })
+}
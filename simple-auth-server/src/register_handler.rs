@@ -1,9 +1,11 @@
 use diesel::prelude::*;
-use loony::web::{self, error::BlockingError, HttpResponse};
+use loony::web::{self, error::BlockingError, HttpRequest, HttpResponse};
 use serde::Deserialize;
 
+use crate::audit::{self, EventKind};
 use crate::errors::ServiceError;
 use crate::models::{Invitation, Pool, SlimUser, User};
+use crate::roles;
 use crate::utils::hash_password;
 // UserData is used to extract data from a post request by the client
 #[derive(Debug, Deserialize)]
@@ -12,14 +14,17 @@ pub struct UserData {
 }
 
 pub async fn register_user(
+    req: HttpRequest,
     invitation_id: web::types::Path<String>,
     user_data: web::types::Json<UserData>,
     pool: web::types::Data<Pool>,
 ) -> Result<HttpResponse, ServiceError> {
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string());
     let res = web::block(move || {
         query(
             invitation_id.into_inner(),
             user_data.into_inner().password,
+            ip,
             pool,
         )
     })
@@ -37,6 +42,7 @@ pub async fn register_user(
 fn query(
     invitation_id: String,
     password: String,
+    ip: Option<String>,
     pool: web::types::Data<Pool>,
 ) -> Result<SlimUser, crate::errors::ServiceError> {
     use crate::schema::invitations::dsl::{id, invitations};
@@ -54,14 +60,31 @@ fn query(
                 if invitation.expires_at > chrono::Local::now().naive_local() {
                     // try hashing the password, else return the error that will be converted to ServiceError
                     let password: String = hash_password(&password)?;
-                    dbg!(&password);
                     let user = User::from_details(invitation.email, password);
                     let inserted_user: User =
                         diesel::insert_into(users).values(&user).get_result(conn)?;
-                    dbg!(&inserted_user);
+                    roles::grant(&inserted_user.email, roles::USER, conn)?;
+                    if is_bootstrap_admin(&inserted_user.email, conn)? {
+                        roles::grant(&inserted_user.email, roles::ADMIN, conn)?;
+                    }
+                    audit::record(
+                        EventKind::Register,
+                        Some(&inserted_user.email),
+                        ip.as_deref(),
+                        None,
+                        conn,
+                    )?;
                     return Ok(inserted_user.into());
                 }
             }
             Err(ServiceError::BadRequest("Invalid Invitation".into()))
         })
 }
+
+/// `ADMIN_EMAIL` becomes the first admin when it registers.
+fn is_bootstrap_admin(eml: &str, conn: &PgConnection) -> Result<bool, ServiceError> {
+    match std::env::var("ADMIN_EMAIL") {
+        Ok(admin_email) if admin_email == eml => Ok(!roles::admin_exists(conn)?),
+        _ => Ok(false),
+    }
+}
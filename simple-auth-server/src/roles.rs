@@ -0,0 +1,102 @@
+// roles.rs
+use diesel::prelude::*;
+use diesel::PgConnection;
+
+use crate::errors::ServiceError;
+use crate::models::UserRole;
+
+pub const ADMIN: &str = "admin";
+pub const USER: &str = "user";
+
+/// What a role can allow, see the `role_permissions` table.
+#[derive(Clone, Copy, Debug)]
+pub enum Permission {
+    Invite,
+    ManageUsers,
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::Invite => "invite",
+            Permission::ManageUsers => "manage_users",
+        }
+    }
+}
+
+pub fn roles_of(eml: &str, conn: &PgConnection) -> Result<Vec<String>, ServiceError> {
+    use crate::schema::user_roles::dsl::{role, user_email, user_roles};
+
+    Ok(user_roles
+        .filter(user_email.eq(eml))
+        .select(role)
+        .order(role)
+        .load(conn)?)
+}
+
+pub fn permissions_of(
+    granted_roles: &[String],
+    conn: &PgConnection,
+) -> Result<Vec<String>, ServiceError> {
+    use crate::schema::role_permissions::dsl::{permission, role, role_permissions};
+
+    Ok(role_permissions
+        .filter(role.eq_any(granted_roles))
+        .select(permission)
+        .distinct()
+        .order(permission)
+        .load(conn)?)
+}
+
+pub fn grant(
+    eml: &str,
+    new_role: &str,
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::user_roles::dsl::user_roles;
+
+    diesel::insert_into(user_roles)
+        .values(&UserRole {
+            user_email: eml.to_owned(),
+            role: new_role.to_owned(),
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Replaces all roles of a user.
+pub fn set_roles(
+    eml: &str,
+    new_roles: &[String],
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::roles::dsl::{name, roles};
+    use crate::schema::user_roles::dsl::{user_email, user_roles};
+
+    let known = roles
+        .filter(name.eq_any(new_roles))
+        .count()
+        .get_result::<i64>(conn)?;
+    if known as usize != new_roles.len() {
+        return Err(ServiceError::BadRequest("Unknown role".into()));
+    }
+
+    conn.transaction(|| {
+        diesel::delete(user_roles.filter(user_email.eq(eml))).execute(conn)?;
+        for new_role in new_roles {
+            grant(eml, new_role, conn)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn admin_exists(conn: &PgConnection) -> Result<bool, ServiceError> {
+    use crate::schema::user_roles::dsl::{role, user_roles};
+
+    let admins = user_roles
+        .filter(role.eq(ADMIN))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(admins > 0)
+}
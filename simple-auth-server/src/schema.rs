@@ -1,3 +1,14 @@
+table! {
+    auth_events (id) {
+        id -> Int8,
+        kind -> Varchar,
+        user_email -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        detail -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     invitations (id) {
         id -> Uuid,
@@ -6,12 +17,102 @@ table! {
     }
 }
 
+table! {
+    login_challenges (id) {
+        id -> Uuid,
+        user_email -> Varchar,
+        mode -> Varchar,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Uuid,
+        email -> Varchar,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Uuid,
+        user_email -> Varchar,
+        hash -> Varchar,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Uuid,
+        user_email -> Varchar,
+        token_hash -> Varchar,
+        family -> Uuid,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    role_permissions (role, permission) {
+        role -> Varchar,
+        permission -> Varchar,
+    }
+}
+
+table! {
+    roles (name) {
+        name -> Varchar,
+    }
+}
+
+table! {
+    totp_credentials (user_email) {
+        user_email -> Varchar,
+        secret -> Varchar,
+        confirmed_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<Int8>,
+    }
+}
+
+table! {
+    user_roles (user_email, role) {
+        user_email -> Varchar,
+        role -> Varchar,
+    }
+}
+
 table! {
     users (email) {
         email -> Varchar,
         hash -> Varchar,
         created_at -> Timestamp,
+        password_changed_at -> Timestamp,
+        disabled -> Bool,
     }
 }
 
-allow_tables_to_appear_in_same_query!(invitations, users,);
+joinable!(login_challenges -> users (user_email));
+joinable!(password_resets -> users (email));
+joinable!(recovery_codes -> users (user_email));
+joinable!(refresh_tokens -> users (user_email));
+joinable!(role_permissions -> roles (role));
+joinable!(totp_credentials -> users (user_email));
+joinable!(user_roles -> roles (role));
+joinable!(user_roles -> users (user_email));
+
+allow_tables_to_appear_in_same_query!(
+    auth_events,
+    invitations,
+    login_challenges,
+    password_resets,
+    recovery_codes,
+    refresh_tokens,
+    role_permissions,
+    roles,
+    totp_credentials,
+    user_roles,
+    users,
+);
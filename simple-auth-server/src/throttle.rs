@@ -0,0 +1,112 @@
+//! Slows down password guessing. Failed logins are counted from
+//! `auth_events`, per account since its last successful login and per IP.
+//! Past a few free attempts every further failure doubles the lockout, up to
+//! a maximum.
+use chrono::{Duration, Local, NaiveDateTime};
+use diesel::dsl::max;
+use diesel::prelude::*;
+use diesel::PgConnection;
+
+use crate::audit::EventKind;
+use crate::errors::ServiceError;
+
+const ACCOUNT_FREE_ATTEMPTS: i64 = 5;
+/// higher than per account, several users can share an address
+const IP_FREE_ATTEMPTS: i64 = 20;
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 15 * 60;
+/// failures older than this are forgotten
+const WINDOW_HOURS: i64 = 24;
+
+/// Fails with `ServiceError::TooManyRequests` while the account or the IP is
+/// locked out.
+pub fn check(
+    eml: &str,
+    ip: Option<&str>,
+    conn: &PgConnection,
+) -> Result<(), ServiceError> {
+    use crate::schema::auth_events::dsl::{auth_events, created_at, kind, user_email};
+
+    let now = Local::now().naive_local();
+    let window_start = now - Duration::hours(WINDOW_HOURS);
+    let failed = EventKind::LoginFailed.as_str();
+
+    let last_login = auth_events
+        .filter(user_email.eq(eml))
+        .filter(kind.eq(EventKind::Login.as_str()))
+        .select(max(created_at))
+        .first::<Option<NaiveDateTime>>(conn)?;
+    let since = last_login.map_or(window_start, |login| login.max(window_start));
+    let account_failures = auth_events
+        .filter(user_email.eq(eml))
+        .filter(kind.eq(failed))
+        .filter(created_at.gt(since));
+    let count = account_failures.clone().count().get_result::<i64>(conn)?;
+    let last = account_failures
+        .select(max(created_at))
+        .first::<Option<NaiveDateTime>>(conn)?;
+    if let Some(until) = locked_until(count, last, ACCOUNT_FREE_ATTEMPTS) {
+        retry_after(until, now)?;
+    }
+
+    if let Some(addr) = ip {
+        use crate::schema::auth_events::dsl::ip as event_ip;
+
+        let ip_failures = auth_events
+            .filter(event_ip.eq(addr))
+            .filter(kind.eq(failed))
+            .filter(created_at.gt(window_start));
+        let count = ip_failures.clone().count().get_result::<i64>(conn)?;
+        let last = ip_failures
+            .select(max(created_at))
+            .first::<Option<NaiveDateTime>>(conn)?;
+        if let Some(until) = locked_until(count, last, IP_FREE_ATTEMPTS) {
+            retry_after(until, now)?;
+        }
+    }
+    Ok(())
+}
+
+fn locked_until(
+    failures: i64,
+    last_failure: Option<NaiveDateTime>,
+    free_attempts: i64,
+) -> Option<NaiveDateTime> {
+    let last_failure = last_failure?;
+    lockout_secs(failures, free_attempts)
+        .map(|secs| last_failure + Duration::seconds(secs))
+}
+
+/// How long to lock out after `failures` failed attempts in a row.
+fn lockout_secs(failures: i64, free_attempts: i64) -> Option<i64> {
+    if failures < free_attempts {
+        return None;
+    }
+    // capped before shifting, so many failures can't overflow
+    let doublings = (failures - free_attempts).min(16) as u32;
+    Some((BASE_LOCKOUT_SECS << doublings).min(MAX_LOCKOUT_SECS))
+}
+
+fn retry_after(until: NaiveDateTime, now: NaiveDateTime) -> Result<(), ServiceError> {
+    let remaining = (until - now).num_seconds();
+    if remaining > 0 {
+        Err(ServiceError::TooManyRequests(remaining))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_doubles_up_to_the_maximum() {
+        assert_eq!(lockout_secs(4, 5), None);
+        assert_eq!(lockout_secs(5, 5), Some(30));
+        assert_eq!(lockout_secs(6, 5), Some(60));
+        assert_eq!(lockout_secs(9, 5), Some(480));
+        assert_eq!(lockout_secs(10, 5), Some(MAX_LOCKOUT_SECS));
+        assert_eq!(lockout_secs(1000, 5), Some(MAX_LOCKOUT_SECS));
+    }
+}
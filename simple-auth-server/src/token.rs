@@ -0,0 +1,202 @@
+//! Bearer token mode: a short-lived signed access token plus an opaque
+//! refresh token that is stored server-side and replaced on every use.
+use chrono::{Duration, Local};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use jsonwebtoken::{
+    decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::RefreshToken;
+use crate::utils::SECRET_KEY;
+
+const ACCESS_TOKEN_MINUTES: i64 = 15;
+const REFRESH_TOKEN_DAYS: i64 = 30;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// the user's email
+    pub sub: String,
+    /// the refresh token family, revoking it also invalidates the access token
+    pub fam: Uuid,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+/// Starts a new token family for a fresh login.
+pub fn login(email: &str, conn: &PgConnection) -> Result<TokenPair, ServiceError> {
+    issue(email, Uuid::new_v4(), conn)
+}
+
+/// Exchanges a refresh token for a new pair. The old refresh token is
+/// revoked, presenting it again revokes the whole family because somebody
+/// else has a copy.
+pub fn refresh(token: &str, conn: &PgConnection) -> Result<TokenPair, ServiceError> {
+    use crate::schema::refresh_tokens::dsl::{
+        id, refresh_tokens, revoked_at, token_hash,
+    };
+
+    let pair = conn.transaction::<_, ServiceError, _>(|| {
+        let current = refresh_tokens
+            .filter(token_hash.eq(hash(token)))
+            .for_update()
+            .first::<RefreshToken>(conn)
+            .optional()?;
+        let current = match current {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+
+        if current.revoked_at.is_some() {
+            revoke_family(current.family, conn)?;
+            return Ok(None);
+        }
+        let now = Local::now().naive_local();
+        if current.expires_at <= now {
+            return Ok(None);
+        }
+
+        diesel::update(refresh_tokens.filter(id.eq(current.id)))
+            .set(revoked_at.eq(now))
+            .execute(conn)?;
+        issue(&current.user_email, current.family, conn).map(Some)
+    })?;
+
+    pair.ok_or(ServiceError::Unauthorized)
+}
+
+/// Logs out the login the refresh token belongs to.
+pub fn revoke(token: &str, conn: &PgConnection) -> Result<(), ServiceError> {
+    use crate::schema::refresh_tokens::dsl::{family, refresh_tokens, token_hash};
+
+    let fam = refresh_tokens
+        .filter(token_hash.eq(hash(token)))
+        .select(family)
+        .first::<Uuid>(conn)
+        .optional()?;
+    if let Some(fam) = fam {
+        revoke_family(fam, conn)?;
+    }
+    Ok(())
+}
+
+pub fn revoke_family(fam: Uuid, conn: &PgConnection) -> Result<(), ServiceError> {
+    use crate::schema::refresh_tokens::dsl::{family, refresh_tokens, revoked_at};
+
+    diesel::update(
+        refresh_tokens
+            .filter(family.eq(fam))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(Local::now().naive_local()))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Ends every token login of a user, e.g. after a password change.
+pub fn revoke_user(email: &str, conn: &PgConnection) -> Result<(), ServiceError> {
+    use crate::schema::refresh_tokens::dsl::{refresh_tokens, revoked_at, user_email};
+
+    diesel::update(
+        refresh_tokens
+            .filter(user_email.eq(email))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(Local::now().naive_local()))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// A family is active as long as its latest refresh token is neither
+/// revoked nor expired.
+pub fn family_active(fam: Uuid, conn: &PgConnection) -> Result<bool, ServiceError> {
+    use crate::schema::refresh_tokens::dsl::{
+        expires_at, family, refresh_tokens, revoked_at,
+    };
+
+    let active = refresh_tokens
+        .filter(family.eq(fam))
+        .filter(revoked_at.is_null())
+        .filter(expires_at.gt(Local::now().naive_local()))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(active > 0)
+}
+
+/// Checks the signature and, unless `allow_expired` is set, the expiry.
+pub fn decode_access(token: &str, allow_expired: bool) -> Result<Claims, ServiceError> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.validate_exp = !allow_expired;
+
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(SECRET_KEY.as_bytes()),
+        &validation,
+    )
+    .map(|data| data.claims)
+    .map_err(|_| ServiceError::Unauthorized)
+}
+
+fn issue(
+    email: &str,
+    fam: Uuid,
+    conn: &PgConnection,
+) -> Result<TokenPair, ServiceError> {
+    use crate::schema::refresh_tokens::dsl::refresh_tokens;
+
+    let now = Local::now();
+    let refresh_token = format!(
+        "{}{}",
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    );
+    let row = RefreshToken {
+        id: Uuid::new_v4(),
+        user_email: email.to_owned(),
+        token_hash: hash(&refresh_token),
+        family: fam,
+        expires_at: now.naive_local() + Duration::days(REFRESH_TOKEN_DAYS),
+        revoked_at: None,
+        created_at: now.naive_local(),
+    };
+    diesel::insert_into(refresh_tokens)
+        .values(&row)
+        .execute(conn)?;
+
+    let claims = Claims {
+        sub: email.to_owned(),
+        fam,
+        iat: now.timestamp(),
+        exp: (now + Duration::minutes(ACCESS_TOKEN_MINUTES)).timestamp(),
+    };
+    let access_token = encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(SECRET_KEY.as_bytes()),
+    )
+    .map_err(|_| ServiceError::InternalServerError)?;
+
+    Ok(TokenPair {
+        access_token,
+        token_type: "Bearer",
+        expires_in: ACCESS_TOKEN_MINUTES * 60,
+        refresh_token,
+    })
+}
+
+// only the hash is stored, a leaked table doesn't hand out sessions
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
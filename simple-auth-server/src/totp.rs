@@ -0,0 +1,107 @@
+//! Time-based one-time passwords (RFC 6238) as used by authenticator apps:
+//! HMAC-SHA1, 6 digits, 30 second steps.
+use base32::Alphabet;
+use hmac::{Hmac, Mac, NewMac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use sha1::Sha1;
+
+const DIGITS: usize = 6;
+const STEP_SECS: i64 = 30;
+/// steps before and after the current one that are accepted, for clock drift
+const SKEW: i64 = 1;
+const ISSUER: &str = "Simple-Auth-Server";
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// A random 160 bit secret, base32 encoded.
+pub fn generate_secret() -> String {
+    base32::encode(ALPHABET, &rand::random::<[u8; 20]>())
+}
+
+/// What the authenticator app scans from a QR code. The issuer and account
+/// are percent-encoded, an email like `a+b@example.com` would otherwise
+/// come out as `a b@example.com`.
+pub fn otpauth_uri(secret: &str, account: &str) -> String {
+    let issuer = utf8_percent_encode(ISSUER, NON_ALPHANUMERIC);
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}\
+         &algorithm=SHA1&digits={digits}&period={period}",
+        issuer = issuer,
+        account = utf8_percent_encode(account, NON_ALPHANUMERIC),
+        secret = secret,
+        digits = DIGITS,
+        period = STEP_SECS,
+    )
+}
+
+/// Checks `code` against the steps around `now` (a unix timestamp) and
+/// returns the matching step. Steps up to `last_used_step` are rejected so a
+/// code can't be replayed.
+pub fn verify(
+    secret: &str,
+    code: &str,
+    now: i64,
+    last_used_step: Option<i64>,
+) -> Option<i64> {
+    let key = base32::decode(ALPHABET, secret)?;
+    let current = now / STEP_SECS;
+
+    (current - SKEW..=current + SKEW)
+        .filter(|step| last_used_step.map_or(true, |last| *step > last))
+        .find(|step| {
+            let expected = format!("{:0width$}", code_at(&key, *step), width = DIGITS);
+            constant_time_eq(expected.as_bytes(), code.trim().as_bytes())
+        })
+}
+
+/// HOTP (RFC 4226) for the counter `step`.
+fn code_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes any key size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS as u32)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc6238_test_vectors() {
+        let secret = base32::encode(ALPHABET, b"12345678901234567890");
+
+        // the RFC lists 8 digits, authenticator apps use the last 6
+        assert_eq!(verify(&secret, "287082", 59, None), Some(1));
+        assert_eq!(
+            verify(&secret, "081804", 1_111_111_109, None),
+            Some(37_037_036)
+        );
+        assert_eq!(
+            verify(&secret, "050471", 1_111_111_111, None),
+            Some(37_037_037)
+        );
+        assert_eq!(verify(&secret, "000000", 59, None), None);
+        // used up
+        assert_eq!(verify(&secret, "287082", 59, Some(1)), None);
+    }
+
+    #[test]
+    fn encodes_the_label() {
+        let uri = otpauth_uri("JBSWY3DPEHPK3PXP", "a+b@example.com");
+        assert!(uri.starts_with(
+            "otpauth://totp/Simple%2DAuth%2DServer:a%2Bb%40example%2Ecom?"
+        ));
+        assert!(uri.contains("&issuer=Simple%2DAuth%2DServer&"));
+    }
+}
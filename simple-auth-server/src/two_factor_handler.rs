@@ -0,0 +1,332 @@
+use chrono::{Duration, Local};
+use diesel::{prelude::*, PgConnection};
+use loony::web::{self, HttpRequest, HttpResponse};
+use loony_identity::Identity;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::audit::{self, EventKind};
+use crate::auth_handler::{self, AuthMode, LoggedUser};
+use crate::errors::ServiceError;
+use crate::models::{LoginChallenge, Pool, RecoveryCode, TotpCredential};
+use crate::throttle;
+use crate::totp;
+use crate::utils::{hash_password, verify};
+
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const CHALLENGE_MINUTES: i64 = 5;
+
+#[derive(Deserialize)]
+pub struct CodeData {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct SecondFactorData {
+    pub challenge: String,
+    pub code: String,
+}
+
+#[derive(Serialize)]
+struct Enrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Starts enrolling an authenticator app. The secret is only asked for at
+/// login after it was confirmed with a code.
+pub async fn enroll(
+    logged_user: LoggedUser,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let eml = logged_user.email;
+    let enrollment = web::block(move || {
+        use crate::schema::totp_credentials::dsl::{
+            secret, totp_credentials, user_email,
+        };
+
+        let conn: &PgConnection = &pool.get().unwrap();
+        if enabled(&eml, conn)? {
+            return Err(ServiceError::BadRequest(
+                "Two-factor authentication is already enabled".into(),
+            ));
+        }
+
+        let credential = TotpCredential {
+            user_email: eml.clone(),
+            secret: totp::generate_secret(),
+            confirmed_at: None,
+            last_used_step: None,
+        };
+        diesel::insert_into(totp_credentials)
+            .values(&credential)
+            .on_conflict(user_email)
+            .do_update()
+            .set(secret.eq(&credential.secret))
+            .execute(conn)?;
+
+        Ok(Enrollment {
+            otpauth_uri: totp::otpauth_uri(&credential.secret, &eml),
+            secret: credential.secret,
+        })
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(&enrollment))
+}
+
+/// Turns two-factor authentication on and hands out the recovery codes,
+/// the only time they can be seen.
+pub async fn confirm(
+    logged_user: LoggedUser,
+    code_data: web::types::Json<CodeData>,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let eml = logged_user.email;
+    let codes = web::block(move || {
+        use crate::schema::totp_credentials::dsl::{
+            confirmed_at, last_used_step, totp_credentials, user_email,
+        };
+
+        let conn: &PgConnection = &pool.get().unwrap();
+        conn.transaction(|| {
+            let credential = totp_credentials
+                .filter(user_email.eq(&eml))
+                .filter(confirmed_at.is_null())
+                .for_update()
+                .first::<TotpCredential>(conn)
+                .optional()?
+                .ok_or_else(|| {
+                    ServiceError::BadRequest("Start the enrollment first".into())
+                })?;
+            let now = Local::now();
+            let step =
+                totp::verify(&credential.secret, &code_data.code, now.timestamp(), None)
+                    .ok_or_else(|| ServiceError::BadRequest("Invalid code".into()))?;
+
+            diesel::update(totp_credentials.filter(user_email.eq(&eml)))
+                .set((confirmed_at.eq(now.naive_local()), last_used_step.eq(step)))
+                .execute(conn)?;
+            replace_recovery_codes(&eml, conn)
+        })
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(&RecoveryCodes {
+        recovery_codes: codes,
+    }))
+}
+
+/// Turns two-factor authentication off, which needs a current code or a
+/// recovery code.
+pub async fn disable(
+    logged_user: LoggedUser,
+    code_data: web::types::Json<CodeData>,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let eml = logged_user.email;
+    web::block(move || {
+        use crate::schema::recovery_codes::dsl::{self as codes, recovery_codes};
+        use crate::schema::totp_credentials::dsl::{totp_credentials, user_email};
+
+        let conn: &PgConnection = &pool.get().unwrap();
+        conn.transaction(|| {
+            if !check_code(&eml, &code_data.code, conn)? {
+                return Err(ServiceError::Unauthorized);
+            }
+            diesel::delete(totp_credentials.filter(user_email.eq(&eml)))
+                .execute(conn)?;
+            diesel::delete(recovery_codes.filter(codes::user_email.eq(&eml)))
+                .execute(conn)?;
+            Ok(())
+        })
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Completes a login that `/api/auth` answered with a challenge. Wrong codes
+/// count as failed logins for the lockout.
+pub async fn second_factor(
+    req: HttpRequest,
+    second_factor_data: web::types::Json<SecondFactorData>,
+    id: Identity,
+    pool: web::types::Data<Pool>,
+) -> Result<HttpResponse, ServiceError> {
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+    let data = second_factor_data.into_inner();
+    let db = pool.clone();
+    let (eml, mode) =
+        web::block(move || complete_challenge(&data.challenge, &data.code, ip, db))
+            .await?;
+
+    auth_handler::finish_login(mode, eml, id, pool).await
+}
+
+/// Whether the user has a confirmed authenticator.
+pub fn enabled(eml: &str, conn: &PgConnection) -> Result<bool, ServiceError> {
+    use crate::schema::totp_credentials::dsl::{
+        confirmed_at, totp_credentials, user_email,
+    };
+
+    let confirmed = totp_credentials
+        .filter(user_email.eq(eml))
+        .filter(confirmed_at.is_not_null())
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(confirmed > 0)
+}
+
+/// Remembers a login that passed the password check, the id goes back to
+/// the client.
+pub fn start_challenge(
+    eml: &str,
+    mode: AuthMode,
+    conn: &PgConnection,
+) -> Result<Uuid, ServiceError> {
+    use crate::schema::login_challenges::dsl::login_challenges;
+
+    let challenge = LoginChallenge {
+        id: Uuid::new_v4(),
+        user_email: eml.to_owned(),
+        mode: mode.as_str().to_owned(),
+        expires_at: Local::now().naive_local() + Duration::minutes(CHALLENGE_MINUTES),
+    };
+    diesel::insert_into(login_challenges)
+        .values(&challenge)
+        .execute(conn)?;
+    Ok(challenge.id)
+}
+
+fn complete_challenge(
+    challenge_id: &str,
+    code: &str,
+    ip: Option<String>,
+    pool: web::types::Data<Pool>,
+) -> Result<(String, AuthMode), ServiceError> {
+    use crate::schema::login_challenges::dsl::{id, login_challenges};
+
+    let challenge_id =
+        Uuid::parse_str(challenge_id).map_err(|_| ServiceError::Unauthorized)?;
+    let conn: &PgConnection = &pool.get().unwrap();
+    let ip = ip.as_deref();
+
+    let challenge = login_challenges
+        .find(challenge_id)
+        .first::<LoginChallenge>(conn)
+        .optional()?
+        .filter(|challenge| challenge.expires_at > Local::now().naive_local())
+        .ok_or(ServiceError::Unauthorized)?;
+    let eml = challenge.user_email.as_str();
+
+    if let Err(locked) = throttle::check(eml, ip, conn) {
+        audit::record(EventKind::LoginLocked, Some(eml), ip, None, conn)?;
+        return Err(locked);
+    }
+
+    let passed = conn.transaction(|| {
+        if !check_code(eml, code, conn)? {
+            return Ok(false);
+        }
+        // a challenge completes only once, a concurrent use undoes this one
+        let taken = diesel::delete(login_challenges.filter(id.eq(challenge_id)))
+            .execute(conn)?;
+        if taken == 0 {
+            return Err(ServiceError::Unauthorized);
+        }
+        Ok(true)
+    })?;
+
+    if !passed {
+        audit::record(EventKind::LoginFailed, Some(eml), ip, None, conn)?;
+        return Err(ServiceError::Unauthorized);
+    }
+    audit::record(EventKind::Login, Some(eml), ip, None, conn)?;
+    Ok((
+        challenge.user_email.clone(),
+        AuthMode::from_name(&challenge.mode),
+    ))
+}
+
+/// Accepts a TOTP code, or else an unused recovery code which is then used
+/// up.
+fn check_code(eml: &str, code: &str, conn: &PgConnection) -> Result<bool, ServiceError> {
+    use crate::schema::recovery_codes::dsl::{self as codes, recovery_codes, used_at};
+    use crate::schema::totp_credentials::dsl::{
+        confirmed_at, last_used_step, totp_credentials, user_email,
+    };
+
+    let credential = totp_credentials
+        .filter(user_email.eq(eml))
+        .filter(confirmed_at.is_not_null())
+        .for_update()
+        .first::<TotpCredential>(conn)
+        .optional()?;
+    let credential = match credential {
+        Some(credential) => credential,
+        None => return Ok(false),
+    };
+
+    let now = Local::now();
+    if let Some(step) = totp::verify(
+        &credential.secret,
+        code,
+        now.timestamp(),
+        credential.last_used_step,
+    ) {
+        diesel::update(totp_credentials.filter(user_email.eq(eml)))
+            .set(last_used_step.eq(step))
+            .execute(conn)?;
+        return Ok(true);
+    }
+
+    let code = code.trim().to_lowercase();
+    let unused = recovery_codes
+        .filter(codes::user_email.eq(eml))
+        .filter(used_at.is_null())
+        .load::<RecoveryCode>(conn)?;
+    for recovery_code in unused {
+        if let Ok(true) = verify(&recovery_code.hash, &code) {
+            diesel::update(recovery_codes.filter(codes::id.eq(recovery_code.id)))
+                .set(used_at.eq(now.naive_local()))
+                .execute(conn)?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Generates a fresh set of recovery codes, only their hashes are stored.
+fn replace_recovery_codes(
+    eml: &str,
+    conn: &PgConnection,
+) -> Result<Vec<String>, ServiceError> {
+    use crate::schema::recovery_codes::dsl::{recovery_codes, user_email};
+
+    diesel::delete(recovery_codes.filter(user_email.eq(eml))).execute(conn)?;
+
+    let mut rng = rand::thread_rng();
+    let mut codes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        let chars: String = (0..10)
+            .map(|_| RECOVERY_CODE_CHARS[rng.gen_range(0..RECOVERY_CODE_CHARS.len())])
+            .map(char::from)
+            .collect();
+        let code = format!("{}-{}", &chars[..5], &chars[5..]);
+        diesel::insert_into(recovery_codes)
+            .values(&RecoveryCode {
+                id: Uuid::new_v4(),
+                user_email: eml.to_owned(),
+                hash: hash_password(&code)?,
+                used_at: None,
+            })
+            .execute(conn)?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
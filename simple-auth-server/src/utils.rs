@@ -2,7 +2,7 @@ use crate::errors::ServiceError;
 use argonautica::{Hasher, Verifier};
 
 lazy_static::lazy_static! {
-pub  static ref SECRET_KEY: String = std::env::var("SECRET_KEY").unwrap_or_else(|_| "0123".repeat(8));
+pub  static ref SECRET_KEY: String = std::env::var("SECRET_KEY").expect("SECRET_KEY must be set");
 }
 
 // WARNING THIS IS ONLY FOR DEMO PLEASE DO MORE RESEARCH FOR PRODUCTION USE
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use askama::Template;
+use csrf_middleware::{Csrf, CsrfToken};
 use loony::web::{self, App, Error, HttpResponse};
 
 #[derive(Template)]
@@ -12,13 +13,32 @@ struct UserTemplate<'a> {
 
 #[derive(Template)]
 #[template(path = "index.html")]
-struct Index;
+struct Index {
+    csrf_token: CsrfToken,
+}
 
 #[web::get("/")]
 async fn index(
     query: web::types::Query<HashMap<String, String>>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, Error> {
+    render(&query, csrf_token)
+}
+
+// the form posts here, `Csrf` has checked its token already
+#[web::post("/")]
+async fn submit(
+    form: web::types::Form<HashMap<String, String>>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, Error> {
+    render(&form, csrf_token)
+}
+
+fn render(
+    params: &HashMap<String, String>,
+    csrf_token: CsrfToken,
 ) -> Result<HttpResponse, Error> {
-    let s = if let Some(name) = query.get("name") {
+    let s = if let Some(name) = params.get("name") {
         UserTemplate {
             name,
             text: "Welcome!",
@@ -26,7 +46,7 @@ async fn index(
         .render()
         .unwrap()
     } else {
-        Index.render().unwrap()
+        Index { csrf_token }.render().unwrap()
     };
     Ok(HttpResponse::Ok().content_type("text/html").body(s))
 }
@@ -34,7 +54,7 @@ async fn index(
 #[loony::main]
 async fn main() -> std::io::Result<()> {
     // start http server
-    web::server(move || App::new().service(index))
+    web::server(move || App::new().wrap(Csrf).service((index, submit)))
         .bind("127.0.0.1:8080")?
         .run()
         .await
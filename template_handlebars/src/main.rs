@@ -1,15 +1,21 @@
 #[macro_use]
 extern crate serde_json;
 
+use csrf_middleware::{Csrf, CsrfToken};
 use handlebars::Handlebars;
 use loony::web::{self, App, HttpResponse};
+use serde::Deserialize;
 use std::io;
 
 // Macro documentation can be found in the actix_web_codegen crate
 #[web::get("/")]
-async fn index(hb: web::types::Data<Handlebars<'_>>) -> HttpResponse {
+async fn index(
+    hb: web::types::Data<Handlebars<'_>>,
+    csrf_token: CsrfToken,
+) -> HttpResponse {
     let data = json!({
-        "name": "Handlebars"
+        "name": "Handlebars",
+        "csrf_token": csrf_token
     });
     let body = hb.render("index", &data).unwrap();
 
@@ -21,9 +27,28 @@ async fn user(
     hb: web::types::Data<Handlebars<'_>>,
     info: web::types::Path<(String, String)>,
 ) -> HttpResponse {
+    render_user(&hb, &info.0, &info.1)
+}
+
+#[derive(Deserialize)]
+struct UserForm {
+    user: String,
+    data: String,
+}
+
+// the form on the index page posts here, `Csrf` has checked its token already
+#[web::post("/")]
+async fn submit(
+    hb: web::types::Data<Handlebars<'_>>,
+    form: web::types::Form<UserForm>,
+) -> HttpResponse {
+    render_user(&hb, &form.user, &form.data)
+}
+
+fn render_user(hb: &Handlebars<'_>, user: &str, data: &str) -> HttpResponse {
     let data = json!({
-        "user": info.0,
-        "data": info.1
+        "user": user,
+        "data": data
     });
     let body = hb.render("user", &data).unwrap();
 
@@ -44,7 +69,8 @@ async fn main() -> io::Result<()> {
     web::server(move || {
         App::new()
             .app_data(handlebars_ref.clone())
-            .service((index, user))
+            .wrap(Csrf)
+            .service((index, user, submit))
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use csrf_middleware::{Csrf, CsrfToken};
 use loony::web::{self, error, middleware, App, Error, HttpResponse};
 use tera::Tera;
 
@@ -8,8 +9,27 @@ use tera::Tera;
 async fn index(
     tmpl: web::types::Data<tera::Tera>,
     query: web::types::Query<HashMap<String, String>>,
+    csrf_token: CsrfToken,
 ) -> Result<HttpResponse, Error> {
-    let s = if let Some(name) = query.get("name") {
+    render(&tmpl, &query, &csrf_token)
+}
+
+// the form posts here, `Csrf` has checked its token already
+#[web::post("/")]
+async fn submit(
+    tmpl: web::types::Data<tera::Tera>,
+    form: web::types::Form<HashMap<String, String>>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, Error> {
+    render(&tmpl, &form, &csrf_token)
+}
+
+fn render(
+    tmpl: &Tera,
+    params: &HashMap<String, String>,
+    csrf_token: &CsrfToken,
+) -> Result<HttpResponse, Error> {
+    let s = if let Some(name) = params.get("name") {
         // submitted form
         let mut ctx = tera::Context::new();
         ctx.insert("name", &name.to_owned());
@@ -17,7 +37,9 @@ async fn index(
         tmpl.render("user.html", &ctx)
             .map_err(|_| error::ErrorInternalServerError("Template error"))?
     } else {
-        tmpl.render("index.html", &tera::Context::new())
+        let mut ctx = tera::Context::new();
+        ctx.insert("csrf_token", csrf_token);
+        tmpl.render("index.html", &ctx)
             .map_err(|_| error::ErrorInternalServerError("Template error"))?
     };
     Ok(HttpResponse::Ok().content_type("text/html").body(s))
@@ -34,8 +56,9 @@ async fn main() -> std::io::Result<()> {
 
         App::new()
             .data(tera)
+            .wrap(Csrf)
             .wrap(middleware::Logger::default()) // enable logger
-            .service(index)
+            .service((index, submit))
     })
     .bind("127.0.0.1:8080")?
     .run()
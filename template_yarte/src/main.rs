@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use csrf_middleware::{Csrf, CsrfToken};
 use loony::web::{
     self, error::ErrorInternalServerError, middleware, App, Error, HttpResponse,
 };
@@ -8,14 +9,33 @@ use yarte::TemplateMin;
 #[derive(TemplateMin)]
 #[template(path = "index")]
 struct IndexTemplate {
-    query: web::types::Query<HashMap<String, String>>,
+    /// the query string, or the posted form
+    query: HashMap<String, String>,
+    csrf_token: CsrfToken,
 }
 
 #[web::get("/")]
 async fn index(
     query: web::types::Query<HashMap<String, String>>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, Error> {
+    render(query.into_inner(), csrf_token)
+}
+
+// the form posts here, `Csrf` has checked its token already
+#[web::post("/")]
+async fn submit(
+    form: web::types::Form<HashMap<String, String>>,
+    csrf_token: CsrfToken,
 ) -> Result<HttpResponse, Error> {
-    IndexTemplate { query }
+    render(form.into_inner(), csrf_token)
+}
+
+fn render(
+    query: HashMap<String, String>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, Error> {
+    IndexTemplate { query, csrf_token }
         .call()
         .map(|body| {
             HttpResponse::Ok()
@@ -33,8 +53,9 @@ async fn main() -> std::io::Result<()> {
     // start http server
     web::server(move || {
         App::new()
+            .wrap(Csrf)
             .wrap(middleware::Logger::default()) // enable logger
-            .service(index)
+            .service((index, submit))
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -44,14 +65,36 @@ async fn main() -> std::io::Result<()> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use loony::http::cookie::Cookie;
     use loony::util::Bytes;
     use loony::{http, web::test as atest};
 
+    /// The token `Csrf` keeps using, as the browser sends its cookie back.
+    fn token() -> String {
+        "ab".repeat(32)
+    }
+
+    fn form_page() -> String {
+        format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Actix \
+             web</title></head><body><h1 id=\"welcome\" \
+             class=\"welcome\">Welcome!</h1><div><h3>What is your name?</h3><form \
+             method=\"post\"><input type=\"hidden\" name=\"csrf_token\" \
+             value=\"{}\">Name: <input type=\"text\" name=\"name\"><br>Last name: \
+             <input type=\"text\" name=\"lastname\"><br><p><input \
+             type=\"submit\"></p></form></div></body></html>",
+            token()
+        )
+    }
+
     #[loony::test]
     async fn test() {
-        let mut app = atest::init_service(App::new().service(index)).await;
+        let mut app =
+            atest::init_service(App::new().wrap(Csrf).service((index, submit))).await;
 
-        let req = atest::TestRequest::with_uri("/").to_request();
+        let req = atest::TestRequest::with_uri("/")
+            .cookie(Cookie::new("csrf_token", token()))
+            .to_request();
         let resp = atest::call_service(&mut app, req).await;
 
         assert!(resp.status().is_success());
@@ -62,17 +105,7 @@ mod test {
         );
 
         let bytes = atest::read_body(resp).await;
-        assert_eq!(
-            bytes,
-            Bytes::from_static(
-                "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Actix \
-                 web</title></head><body><h1 id=\"welcome\" \
-                 class=\"welcome\">Welcome!</h1><div><h3>What is your name?</h3><form>Name: \
-                 <input type=\"text\" name=\"name\"><br>Last name: <input type=\"text\" \
-                 name=\"lastname\"><br><p><input type=\"submit\"></p></form></div></body></html>"
-                    .as_ref()
-            )
-        );
+        assert_eq!(bytes, Bytes::from(form_page()));
 
         let req = atest::TestRequest::with_uri("/?name=foo&lastname=bar").to_request();
         let resp = atest::call_service(&mut app, req).await;
@@ -104,7 +137,9 @@ mod test {
 
         assert_eq!(bytes, Bytes::from_static("Some error message".as_ref()));
 
-        let req = atest::TestRequest::with_uri("/?lastname=bar").to_request();
+        let req = atest::TestRequest::with_uri("/?lastname=bar")
+            .cookie(Cookie::new("csrf_token", token()))
+            .to_request();
         let resp = atest::call_service(&mut app, req).await;
 
         assert!(resp.status().is_success());
@@ -115,16 +150,27 @@ mod test {
         );
 
         let bytes = atest::read_body(resp).await;
-        assert_eq!(
-            bytes,
-            Bytes::from_static(
-                "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Actix \
-                 web</title></head><body><h1 id=\"welcome\" \
-                 class=\"welcome\">Welcome!</h1><div><h3>What is your name?</h3><form>Name: \
-                 <input type=\"text\" name=\"name\"><br>Last name: <input type=\"text\" \
-                 name=\"lastname\"><br><p><input type=\"submit\"></p></form></div></body></html>"
-                    .as_ref()
-            )
-        );
+        assert_eq!(bytes, Bytes::from(form_page()));
+
+        // the form only goes through with the token
+        let form = [("name", "foo"), ("lastname", "bar")];
+        let req = atest::TestRequest::post()
+            .uri("/")
+            .cookie(Cookie::new("csrf_token", token()))
+            .set_form(&form)
+            .to_request();
+        let resp = atest::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+
+        let req = atest::TestRequest::post()
+            .uri("/")
+            .cookie(Cookie::new("csrf_token", token()))
+            .header("x-csrf-token", token())
+            .set_form(&form)
+            .to_request();
+        let resp = atest::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        let bytes = atest::read_body(resp).await;
+        assert!(bytes.ends_with(b"<h1>Hi, foo bar!</h1><p id=\"hi\" class=\"welcome\">Welcome</p></body></html>"));
     }
 }
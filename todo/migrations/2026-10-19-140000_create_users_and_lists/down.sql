@@ -0,0 +1,4 @@
+ALTER TABLE tasks DROP COLUMN list_id;
+DROP TABLE list_shares;
+DROP TABLE lists;
+DROP TABLE users;
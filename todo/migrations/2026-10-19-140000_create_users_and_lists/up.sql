@@ -0,0 +1,33 @@
+CREATE TABLE users (
+  id SERIAL PRIMARY KEY,
+  username VARCHAR NOT NULL UNIQUE,
+  password_hash VARCHAR NOT NULL
+);
+
+CREATE TABLE lists (
+  id SERIAL PRIMARY KEY,
+  owner_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name VARCHAR NOT NULL
+);
+CREATE INDEX lists_owner_id ON lists (owner_id);
+
+CREATE TABLE list_shares (
+  list_id INTEGER NOT NULL REFERENCES lists (id) ON DELETE CASCADE,
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  role VARCHAR NOT NULL CHECK (role IN ('viewer', 'editor')),
+  PRIMARY KEY (list_id, user_id)
+);
+CREATE INDEX list_shares_user_id ON list_shares (user_id);
+
+-- Tasks from before there were users go into a list of the `legacy` user,
+-- who can't log in ('!' is no password hash). Hand the list to someone with
+-- UPDATE lists SET owner_id = ... WHERE name = 'Tasks'.
+INSERT INTO users (username, password_hash)
+SELECT 'legacy', '!' WHERE EXISTS (SELECT 1 FROM tasks);
+INSERT INTO lists (owner_id, name)
+SELECT id, 'Tasks' FROM users WHERE username = 'legacy';
+
+ALTER TABLE tasks ADD COLUMN list_id INTEGER REFERENCES lists (id) ON DELETE CASCADE;
+UPDATE tasks SET list_id = (SELECT id FROM lists);
+ALTER TABLE tasks ALTER COLUMN list_id SET NOT NULL;
+CREATE INDEX tasks_list_id ON tasks (list_id);
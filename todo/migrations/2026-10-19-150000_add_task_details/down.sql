@@ -0,0 +1,5 @@
+ALTER TABLE tasks
+  DROP COLUMN tags,
+  DROP COLUMN notes,
+  DROP COLUMN priority,
+  DROP COLUMN due_date;
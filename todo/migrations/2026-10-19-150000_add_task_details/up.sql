@@ -0,0 +1,17 @@
+ALTER TABLE tasks
+  ADD COLUMN due_date DATE,
+  -- 1 low, 2 normal, 3 high
+  ADD COLUMN priority SMALLINT NOT NULL DEFAULT 2 CHECK (priority BETWEEN 1 AND 3),
+  ADD COLUMN notes TEXT NOT NULL DEFAULT '',
+  ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+
+-- Existing tasks get normal priority from the defaults, and the #hashtags
+-- people used to put into descriptions become tags.
+UPDATE tasks
+SET tags = ARRAY(
+  SELECT DISTINCT lower(m[1]) FROM regexp_matches(description, '#(\w+)', 'g') AS m
+)
+WHERE description LIKE '%#%';
+
+CREATE INDEX tasks_due_date ON tasks (due_date) WHERE NOT completed;
+CREATE INDEX tasks_tags ON tasks USING GIN (tags);
@@ -0,0 +1,7 @@
+DROP TABLE task_events;
+
+-- there is no undoing these anymore
+DELETE FROM tasks WHERE deleted_at IS NOT NULL;
+DROP INDEX tasks_list_id;
+ALTER TABLE tasks DROP COLUMN deleted_at;
+CREATE INDEX tasks_list_id ON tasks (list_id);
@@ -0,0 +1,18 @@
+-- Deleted tasks stay around, they can be restored for a while and keep
+-- their history.
+ALTER TABLE tasks ADD COLUMN deleted_at TIMESTAMPTZ;
+DROP INDEX tasks_list_id;
+CREATE INDEX tasks_list_id ON tasks (list_id) WHERE deleted_at IS NULL;
+
+CREATE TABLE task_events (
+  id SERIAL PRIMARY KEY,
+  task_id INTEGER NOT NULL REFERENCES tasks (id) ON DELETE CASCADE,
+  -- who did it
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  action VARCHAR NOT NULL
+    CHECK (action IN ('created', 'completed', 'reopened', 'edited', 'deleted', 'restored')),
+  -- {"field": {"from": ..., "to": ...}} for every field that changed
+  changes JSONB NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX task_events_task_id ON task_events (task_id);
@@ -1,27 +1,115 @@
-// use loony_files::NamedFile;
-use loony::http;
-use loony::web::{self, error, Error, HttpResponse};
+use chrono::{Local, NaiveDate};
+use csrf_middleware::CsrfToken;
+use loony::http::{self, StatusCode};
+use loony::web::{self, error, Error, HttpRequest, HttpResponse};
 use loony_session::Session;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tera::{Context, Tera};
 
-use crate::db;
+use crate::auth::UserId;
+use crate::db::{self, DbError};
+use crate::filter::{Page, TaskFilter};
+use crate::live::{self, Change, Live};
+use crate::model::{
+    self, Access, NewTask, Task, TaskChanges, NORMAL_PRIORITY, PRIORITIES,
+};
+use crate::negotiate::{Format, Input};
 use crate::session::{self, FlashMessage};
 
+const PRIORITY_RANGE: &str = "Priority is 1 (low) to 3 (high)";
+
+/// The tasks of one list next to all lists of the user. `/api/tasks` has
+/// the tasks of every list unless `?list=` picks one. Both take the
+/// `TaskFilter` query.
 pub async fn index(
+    req: HttpRequest,
+    user: Option<UserId>,
+    query: web::types::Query<TaskFilter>,
     pool: web::types::Data<db::PgPool>,
     tmpl: web::types::Data<Tera>,
     session: Session,
+    csrf_token: CsrfToken,
+    format: Format,
 ) -> Result<HttpResponse, Error> {
-    let tasks = web::block(move || db::get_all_tasks(&pool)).await?;
+    let user = match (user, format) {
+        (Some(UserId(user)), _) => user,
+        (None, Format::Json) => {
+            return Ok(json_error(StatusCode::UNAUTHORIZED, "Log in first"))
+        }
+        (None, Format::Html) => return Ok(redirect_to("/login")),
+    };
+    let mut filter = query.into_inner();
+
+    if format == Format::Json {
+        let query = filter.clone();
+        let page = web::block(move || db::get_tasks(user, &query, &pool))
+            .await
+            .map_err(DbError::from)?;
+        return Ok(paginated(req.path(), &filter, page));
+    }
+
+    let (lists, current, page, shares, filter) = web::block(move || {
+        let lists = db::get_lists(user, &pool)?;
+        let current = filter
+            .list
+            .and_then(|id| lists.iter().position(|list| list.id == id))
+            .or_else(|| if lists.is_empty() { None } else { Some(0) });
+        filter.list = current.map(|i| lists[i].id);
+
+        let (page, shares) = match current.map(|i| &lists[i]) {
+            Some(list) => {
+                let page = Some(db::get_tasks(user, &filter, &pool)?);
+                let shares = match list.access {
+                    Access::Owner => db::get_shares(list.id, user, &pool)?,
+                    _ => Vec::new(),
+                };
+                (page, shares)
+            }
+            None => (None, Vec::new()),
+        };
+        Ok((lists, current, page, shares, filter))
+    })
+    .await
+    .map_err(DbError::from)?;
 
     let mut context = Context::new();
-    context.insert("tasks", &tasks);
+    context.insert("lists", &lists);
+    context.insert("list", &current.map(|i| &lists[i]));
+    context.insert("shares", &shares);
+    context.insert("filter", &filter);
+    context.insert("csrf_token", &csrf_token);
+    match &page {
+        Some(page) => {
+            let today = Local::today().naive_local();
+            let overdue: Vec<i32> = page
+                .items
+                .iter()
+                .filter(|task| task.is_overdue(today))
+                .map(|task| task.id)
+                .collect();
+            context.insert("tasks", &page.items);
+            context.insert("overdue", &overdue);
+            context.insert("page", &page.page);
+            context.insert("last_page", &page.last_page());
+            context.insert("total", &page.total);
+            if page.page > 1 {
+                context.insert("prev_query", &filter.query_for_page(page.page - 1));
+            }
+            if page.page < page.last_page() {
+                context.insert("next_query", &filter.query_for_page(page.page + 1));
+            }
+        }
+        None => {
+            context.insert("tasks", &Vec::<Task>::new());
+            context.insert("overdue", &Vec::<i32>::new());
+        }
+    }
 
     //Session is set during operations on other endpoints
     //that can redirect to index
     if let Some(flash) = session::get_flash(&session)? {
         context.insert("msg", &(flash.kind, flash.message));
+        context.insert("undo", &flash.undo);
         session::clear_flash(&session);
     }
 
@@ -29,36 +117,280 @@ pub async fn index(
         .render("index.html.tera", &context)
         .map_err(error::ErrorInternalServerError)?;
 
-    Ok(HttpResponse::Ok().body(rendered))
+    Ok(HttpResponse::Ok()
+        .header(http::header::VARY, "Accept")
+        .body(rendered))
+}
+
+/// The tasks of the page as a JSON array. `X-Total-Count` has the number
+/// of all matching tasks, `Link` the neighbouring pages.
+pub fn paginated(path: &str, filter: &TaskFilter, page: Page<Task>) -> HttpResponse {
+    let mut links = Vec::new();
+    if page.page > 1 {
+        let query = filter.query_for_page(page.page - 1);
+        links.push(format!("<{}?{}>; rel=\"prev\"", path, query));
+    }
+    if page.page < page.last_page() {
+        let query = filter.query_for_page(page.page + 1);
+        links.push(format!("<{}?{}>; rel=\"next\"", path, query));
+    }
+
+    let mut response = HttpResponse::Ok();
+    response
+        .header(http::header::VARY, "Accept")
+        .header("X-Total-Count", page.total.to_string());
+    if !links.is_empty() {
+        response.header(http::header::LINK, links.join(", "));
+    }
+    response.json(&page.items)
+}
+
+#[derive(Deserialize)]
+pub struct TaskParams {
+    id: i32,
+}
+
+pub async fn show(
+    user: UserId,
+    pool: web::types::Data<db::PgPool>,
+    params: web::types::Path<TaskParams>,
+) -> Result<HttpResponse, Error> {
+    let id = params.id;
+    let task = web::block(move || db::get_task(id, user.0, &pool))
+        .await
+        .map_err(DbError::from)?;
+    Ok(HttpResponse::Ok().json(&task))
+}
+
+/// The `<li>` of a task as the index has it, for the page to swap in when
+/// the task changes elsewhere. `204 No Content` if the task doesn't pass the
+/// page's `TaskFilter` query, so the page can drop it. API clients get the
+/// task.
+pub async fn row(
+    user: UserId,
+    params: web::types::Path<TaskParams>,
+    query: web::types::Query<TaskFilter>,
+    format: Format,
+    pool: web::types::Data<db::PgPool>,
+    tmpl: web::types::Data<Tera>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, Error> {
+    let id = params.id;
+    let (task, access) = web::block(move || db::get_task_access(id, user.0, &pool))
+        .await
+        .map_err(DbError::from)?;
+    if format == Format::Json {
+        return Ok(HttpResponse::Ok().json(&task));
+    }
+
+    let today = Local::today().naive_local();
+    if !task.matches(&query, today) {
+        return Ok(HttpResponse::NoContent()
+            .header(http::header::VARY, "Accept")
+            .finish());
+    }
+    let overdue: Vec<i32> = Some(task.id)
+        .filter(|_| task.is_overdue(today))
+        .into_iter()
+        .collect();
+    let mut context = Context::new();
+    context.insert(
+        "list",
+        &serde_json::json!({ "id": task.list_id, "access": access }),
+    );
+    context.insert("task", &task);
+    context.insert("overdue", &overdue);
+    context.insert("csrf_token", &csrf_token);
+    let rendered = tmpl
+        .render("task.html.tera", &context)
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .header(http::header::VARY, "Accept")
+        .body(rendered))
 }
 
 #[derive(Deserialize)]
 pub struct CreateForm {
     description: String,
+    /// the user's first list if left out
+    list_id: Option<i32>,
+    #[serde(default, deserialize_with = "model::optional_date")]
+    due_date: Option<NaiveDate>,
+    priority: Option<i16>,
+    #[serde(default)]
+    notes: String,
+    #[serde(default, deserialize_with = "model::tag_list")]
+    tags: Option<Vec<String>>,
 }
 
+/// `201 Created` with the task and its `Location` for API clients, a
+/// redirect back to the list for forms.
 pub async fn create(
-    params: web::types::Form<CreateForm>,
+    user: UserId,
+    params: Input<CreateForm>,
+    format: Format,
     pool: web::types::Data<db::PgPool>,
+    live: Live,
     session: Session,
 ) -> Result<HttpResponse, Error> {
-    if params.description.is_empty() {
-        session::set_flash(
-            &session,
-            FlashMessage::error("Description cannot be empty"),
-        )?;
-        Ok(redirect_to("/"))
+    let params = params.into_inner();
+    let priority = params.priority.unwrap_or(NORMAL_PRIORITY);
+    let invalid = if params.description.trim().is_empty() {
+        Some("Description cannot be empty")
+    } else if !PRIORITIES.contains(&priority) {
+        Some(PRIORITY_RANGE)
     } else {
-        web::block(move || db::create_task(params.into_inner().description, &pool))
-            .await?;
-        session::set_flash(&session, FlashMessage::success("Task successfully added"))?;
-        Ok(redirect_to("/"))
+        None
+    };
+    if let Some(message) = invalid {
+        return failed_to(
+            format,
+            &session,
+            StatusCode::BAD_REQUEST,
+            message,
+            &list_location(params.list_id),
+        );
+    }
+
+    let db_pool = pool.clone();
+    let task = web::block(move || {
+        let list_id = match params.list_id {
+            Some(list_id) => list_id,
+            None => db::default_list(user.0, &db_pool)?,
+        };
+        let new_task = NewTask {
+            description: params.description,
+            list_id,
+            due_date: params.due_date,
+            priority,
+            notes: params.notes,
+            tags: params.tags.unwrap_or_default(),
+        };
+        db::create_task(user.0, new_task, &db_pool)
+    })
+    .await
+    .map_err(DbError::from)?;
+    live::publish(&live, pool, Change::Created, &task).await;
+
+    match format {
+        Format::Json => Ok(HttpResponse::Created()
+            .header(http::header::LOCATION, task_location(&task))
+            .json(&task)),
+        Format::Html => {
+            session::set_flash(
+                &session,
+                FlashMessage::success("Task successfully added"),
+            )?;
+            Ok(redirect_to(&list_location(Some(task.list_id))))
+        }
     }
 }
 
-#[derive(Deserialize)]
-pub struct UpdateParams {
-    id: i32,
+/// Changes any of the fields of `TaskChanges`.
+pub async fn patch(
+    user: UserId,
+    params: web::types::Path<TaskParams>,
+    changes: Input<TaskChanges>,
+    format: Format,
+    pool: web::types::Data<db::PgPool>,
+    live: Live,
+    session: Session,
+) -> Result<HttpResponse, Error> {
+    let changes = changes.into_inner();
+    let invalid = match (&changes.description, changes.priority) {
+        (Some(description), _) if description.trim().is_empty() => {
+            Some("Description cannot be empty")
+        }
+        (_, Some(priority)) if !PRIORITIES.contains(&priority) => Some(PRIORITY_RANGE),
+        _ => None,
+    };
+    if let Some(message) = invalid {
+        return failed(format, &session, StatusCode::BAD_REQUEST, message);
+    }
+
+    let id = params.id;
+    let db_pool = pool.clone();
+    let task = web::block(move || db::update_task(id, user.0, changes, &db_pool))
+        .await
+        .map_err(DbError::from)?;
+    live::publish(&live, pool, Change::Updated, &task).await;
+    Ok(updated(format, task))
+}
+
+pub async fn delete(
+    user: UserId,
+    params: web::types::Path<TaskParams>,
+    format: Format,
+    pool: web::types::Data<db::PgPool>,
+    live: Live,
+    session: Session,
+) -> Result<HttpResponse, Error> {
+    let id = params.id;
+    let db_pool = pool.clone();
+    let task = web::block(move || db::delete_task(id, user.0, &db_pool))
+        .await
+        .map_err(DbError::from)?;
+    live::publish(&live, pool, Change::Deleted, &task).await;
+    match format {
+        Format::Json => Ok(HttpResponse::NoContent().finish()),
+        Format::Html => {
+            let flash = FlashMessage::success("Task was deleted.")
+                .with_undo(format!("/todo/{}/restore", task.id));
+            session::set_flash(&session, flash)?;
+            Ok(redirect_to(&list_location(Some(task.list_id))))
+        }
+    }
+}
+
+/// Undoes a delete, for `model::UNDO_MINUTES` after it.
+pub async fn restore(
+    user: UserId,
+    params: web::types::Path<TaskParams>,
+    format: Format,
+    pool: web::types::Data<db::PgPool>,
+    live: Live,
+    session: Session,
+) -> Result<HttpResponse, Error> {
+    let id = params.id;
+    let db_pool = pool.clone();
+    let task = web::block(move || db::restore_task(id, user.0, &db_pool))
+        .await
+        .map_err(DbError::from)?;
+    // it's back in the list, as far as other pages are concerned
+    live::publish(&live, pool, Change::Created, &task).await;
+    match format {
+        Format::Json => Ok(HttpResponse::Ok().json(&task)),
+        Format::Html => {
+            session::set_flash(&session, FlashMessage::success("Task was restored."))?;
+            Ok(redirect_to(&list_location(Some(task.list_id))))
+        }
+    }
+}
+
+/// Who changed what and when, oldest first. Deleted tasks have a history
+/// too.
+pub async fn history(
+    user: UserId,
+    params: web::types::Path<TaskParams>,
+    format: Format,
+    pool: web::types::Data<db::PgPool>,
+    tmpl: web::types::Data<Tera>,
+) -> Result<HttpResponse, Error> {
+    let id = params.id;
+    let (task, events) = web::block(move || db::get_history(id, user.0, &pool))
+        .await
+        .map_err(DbError::from)?;
+    if format == Format::Json {
+        return Ok(HttpResponse::Ok().json(&events));
+    }
+
+    let mut context = Context::new();
+    context.insert("task", &task);
+    context.insert("events", &events);
+    let rendered = tmpl
+        .render("history.html.tera", &context)
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().body(rendered))
 }
 
 #[derive(Deserialize)]
@@ -66,15 +398,19 @@ pub struct UpdateForm {
     _method: String,
 }
 
+/// HTML forms can only `POST`, the `_method` field says what they mean.
 pub async fn update(
-    db: web::types::Data<db::PgPool>,
-    params: web::types::Path<UpdateParams>,
+    user: UserId,
+    pool: web::types::Data<db::PgPool>,
+    params: web::types::Path<TaskParams>,
     form: web::types::Form<UpdateForm>,
+    format: Format,
+    live: Live,
     session: Session,
 ) -> Result<HttpResponse, Error> {
     match form._method.as_ref() {
-        "put" => toggle(db, params).await,
-        "delete" => delete(db, params, session).await,
+        "put" => toggle(user, pool, params, format, live).await,
+        "delete" => delete(user, params, format, pool, live, session).await,
         unsupported_method => {
             let msg = format!("Unsupported HTTP method: {}", unsupported_method);
             Err(error::ErrorBadRequest(msg).into())
@@ -83,54 +419,184 @@ pub async fn update(
 }
 
 async fn toggle(
+    user: UserId,
     pool: web::types::Data<db::PgPool>,
-    params: web::types::Path<UpdateParams>,
+    params: web::types::Path<TaskParams>,
+    format: Format,
+    live: Live,
 ) -> Result<HttpResponse, Error> {
-    web::block(move || db::toggle_task(params.id, &pool)).await?;
-    Ok(redirect_to("/"))
+    let id = params.id;
+    let db_pool = pool.clone();
+    let task = web::block(move || db::toggle_task(id, user.0, &db_pool))
+        .await
+        .map_err(DbError::from)?;
+    live::publish(&live, pool, Change::Updated, &task).await;
+    Ok(updated(format, task))
 }
 
-async fn delete(
-    pool: web::types::Data<db::PgPool>,
-    params: web::types::Path<UpdateParams>,
-    session: Session,
+fn updated(format: Format, task: Task) -> HttpResponse {
+    match format {
+        Format::Json => HttpResponse::Ok().json(&task),
+        Format::Html => redirect_to(&list_location(Some(task.list_id))),
+    }
+}
+
+/// An error as JSON for API clients, a flash message on the list for
+/// browsers.
+pub fn failed(
+    format: Format,
+    session: &Session,
+    status: StatusCode,
+    message: &str,
 ) -> Result<HttpResponse, Error> {
-    web::block(move || db::delete_task(params.id, &pool)).await?;
-    session::set_flash(&session, FlashMessage::success("Task was deleted."))?;
-    Ok(redirect_to("/"))
+    failed_to(format, session, status, message, "/")
 }
 
-fn redirect_to(location: &str) -> HttpResponse {
+/// Like `failed`, with browsers sent to `location`.
+pub fn failed_to(
+    format: Format,
+    session: &Session,
+    status: StatusCode,
+    message: &str,
+    location: &str,
+) -> Result<HttpResponse, Error> {
+    match format {
+        Format::Json => Ok(json_error(status, message)),
+        Format::Html => {
+            session::set_flash(session, FlashMessage::error(message))?;
+            Ok(redirect_to(location))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+fn json_error(status: StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(&ErrorBody { error: message })
+}
+
+fn task_location(task: &Task) -> String {
+    format!("/api/tasks/{}", task.id)
+}
+
+pub fn list_location(list_id: Option<i32>) -> String {
+    match list_id {
+        Some(list_id) => format!("/?list={}", list_id),
+        None => "/".to_owned(),
+    }
+}
+
+pub fn redirect_to(location: &str) -> HttpResponse {
     HttpResponse::Found()
         .header(http::header::LOCATION, location)
         .finish()
 }
 
-// pub fn bad_request<B>(res: dev::WebResponse<B>) -> Result<ErrorHandlerResponse<B>> {
-//     let new_resp = NamedFile::open("static/errors/400.html")?
-//         .set_status_code(res.status())
-//         .into_response(res.request())?;
-//     Ok(ErrorHandlerResponse::Response(
-//         res.into_response(new_resp.into_body()),
-//     ))
-// }
-
-// pub fn not_found<B>(res: dev::ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
-//     let new_resp = NamedFile::open("static/errors/404.html")?
-//         .set_status_code(res.status())
-//         .into_response(res.request())?;
-//     Ok(ErrorHandlerResponse::Response(
-//         res.into_response(new_resp.into_body()),
-//     ))
-// }
-
-// pub fn internal_server_error<B>(
-//     res: dev::ServiceResponse<B>,
-// ) -> Result<ErrorHandlerResponse<B>> {
-//     let new_resp = NamedFile::open("static/errors/500.html")?
-//         .set_status_code(res.status())
-//         .into_response(res.request())?;
-//     Ok(ErrorHandlerResponse::Response(
-//         res.into_response(new_resp.into_body()),
-//     ))
-// }
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn render_index(list: Value, query: &str, tasks: Value, shares: Value) -> String {
+        let filter: TaskFilter = serde_urlencoded::from_str(query).unwrap();
+        let mut context = Context::new();
+        context.insert("lists", &[&list]);
+        context.insert("list", &list);
+        context.insert("shares", &shares);
+        context.insert("filter", &filter);
+        context.insert("csrf_token", "token");
+        context.insert("tasks", &tasks);
+        context.insert("overdue", &Vec::<i32>::new());
+        context.insert("page", &1);
+        crate::templates()
+            .render("index.html.tera", &context)
+            .unwrap()
+    }
+
+    #[test]
+    fn escapes_the_filter() {
+        let list =
+            json!({ "id": 1, "name": "Home", "owner": "alice", "access": "owner" });
+        let page = render_index(
+            list,
+            "q=%22%3E%3Cscript%3Ealert(1)%3C%2Fscript%3E&tag=%3Cb%3E",
+            json!([]),
+            json!([]),
+        );
+        assert!(page.contains(r#"value="&quot;&gt;&lt;script&gt;alert(1)"#));
+        assert!(page.contains(r#"value="&lt;b&gt;""#));
+        assert!(!page.contains("<script>alert(1)"));
+        assert!(!page.contains("<b>"));
+    }
+
+    #[test]
+    fn escapes_what_other_users_wrote() {
+        let task = json!({
+            "id": 7,
+            "list_id": 1,
+            "description": "<script>alert(1)</script>",
+            "completed": false,
+            "priority": 2,
+            "due_date": null,
+            "tags": ["<b>"],
+            "notes": "<img src=x onerror=alert(2)>",
+        });
+
+        // a list shared with the user
+        let list = json!({
+            "id": 1,
+            "name": "<i>Home</i>",
+            "owner": "<i>bob</i>",
+            "access": "editor",
+        });
+        let page = render_index(list, "", json!([task]), json!([]));
+        assert!(page.contains("&lt;script&gt;alert(1)"));
+        assert!(!page.contains("<script>alert(1)"));
+        assert!(!page.contains("<img"));
+        assert!(!page.contains("<b>"));
+        assert!(!page.contains("<i>"));
+
+        // the user's own list, shared with others
+        let list =
+            json!({ "id": 1, "name": "Home", "owner": "alice", "access": "owner" });
+        let shares = json!([{ "username": "<i>carol</i>", "access": "viewer" }]);
+        let page = render_index(list, "", json!([task]), shares);
+        assert!(page.contains("&lt;i&gt;carol"));
+        assert!(!page.contains("<i>"));
+    }
+
+    #[test]
+    fn escapes_the_history() {
+        let mut context = Context::new();
+        let task = json!({
+            "id": 7,
+            "list_id": 1,
+            "description": "<i>a</i>",
+            "completed": false,
+        });
+        context.insert("task", &task);
+        context.insert(
+            "events",
+            &json!([{
+                "username": "<i>bob</i>",
+                "action": "edited",
+                "changes": {
+                    "description": { "from": "<i>a</i>", "to": "\"><script>" },
+                },
+                "at": "2026-10-19T10:00:00Z",
+            }]),
+        );
+        let page = crate::templates()
+            .render("history.html.tera", &context)
+            .unwrap();
+        // json_encode's quotes are escaped along with the rest
+        assert!(page.contains("&quot;&lt;i&gt;a&lt;&#x2F;i&gt;&quot;"));
+        assert!(page.contains("&quot;\\&quot;&gt;&lt;script&gt;&quot;"));
+        assert!(!page.contains("<i>"));
+        assert!(!page.contains("<script>"));
+    }
+}
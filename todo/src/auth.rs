@@ -0,0 +1,190 @@
+//! Accounts. The identity cookie holds the id of the logged in user.
+use csrf_middleware::CsrfToken;
+use futures::future::{ready, Ready};
+use loony::http::{Payload, StatusCode};
+use loony::web::{self, error, Error, FromRequest, HttpRequest, HttpResponse};
+use loony_identity::{Identity, RequestIdentity};
+use loony_session::Session;
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+
+use crate::api::{failed_to, redirect_to};
+use crate::db::{self, DbError};
+use crate::live::Live;
+use crate::model::User;
+use crate::negotiate::{Format, Input};
+use crate::session;
+
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_USERNAME_LEN: usize = 64;
+
+/// The logged in user, requests without one are `401 Unauthorized`.
+#[derive(Clone, Copy, Debug)]
+pub struct UserId(pub i32);
+
+impl<Err> FromRequest<Err> for UserId {
+    type Error = Error;
+    type Future = Ready<Result<UserId, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.get_identity()
+                .and_then(|id| id.parse().ok())
+                .map(UserId)
+                .ok_or_else(|| error::ErrorUnauthorized("Log in first").into()),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct Account {
+    id: i32,
+    username: String,
+}
+
+pub async fn login_page(
+    user: Option<UserId>,
+    tmpl: web::types::Data<Tera>,
+    session: Session,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse, Error> {
+    if user.is_some() {
+        return Ok(redirect_to("/"));
+    }
+
+    let mut context = Context::new();
+    context.insert("csrf_token", &csrf_token);
+    if let Some(flash) = session::get_flash(&session)? {
+        context.insert("msg", &(flash.kind, flash.message));
+        session::clear_flash(&session);
+    }
+
+    let rendered = tmpl
+        .render("login.html.tera", &context)
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().body(rendered))
+}
+
+pub async fn login(
+    credentials: Input<Credentials>,
+    format: Format,
+    id: Identity,
+    pool: web::types::Data<db::PgPool>,
+    session: Session,
+) -> Result<HttpResponse, Error> {
+    let Credentials { username, password } = credentials.into_inner();
+    let user = web::block(move || {
+        let user = db::find_user(username.trim(), &pool)?;
+        let valid = |user: &User| verify_password(&user.password_hash, &password);
+        Ok(user.filter(valid))
+    })
+    .await
+    .map_err(DbError::from)?;
+
+    match user {
+        Some(user) => {
+            id.remember(user.id.to_string());
+            // a new session id, so one planted before can't be taken over
+            session.renew();
+            logged_in(format, StatusCode::OK, user.id, user.username)
+        }
+        None => failed_to(
+            format,
+            &session,
+            StatusCode::UNAUTHORIZED,
+            "Wrong username or password",
+            "/login",
+        ),
+    }
+}
+
+pub async fn register(
+    credentials: Input<Credentials>,
+    format: Format,
+    id: Identity,
+    pool: web::types::Data<db::PgPool>,
+    session: Session,
+) -> Result<HttpResponse, Error> {
+    let Credentials { username, password } = credentials.into_inner();
+    let username = username.trim().to_owned();
+    let invalid = if username.is_empty() || username.len() > MAX_USERNAME_LEN {
+        Some("Usernames have 1 to 64 characters")
+    } else if password.chars().count() < MIN_PASSWORD_LEN {
+        Some("Passwords have at least 8 characters")
+    } else {
+        None
+    };
+    if let Some(message) = invalid {
+        return failed_to(format, &session, StatusCode::BAD_REQUEST, message, "/login");
+    }
+
+    let user = web::block(move || {
+        let hash = hash_password(&password)?;
+        db::create_user(&username, &hash, &pool)
+    })
+    .await
+    .map_err(DbError::from)?;
+
+    match user {
+        Some(user) => {
+            id.remember(user.id.to_string());
+            session.renew();
+            logged_in(format, StatusCode::CREATED, user.id, user.username)
+        }
+        None => failed_to(
+            format,
+            &session,
+            StatusCode::CONFLICT,
+            "That username is taken",
+            "/login",
+        ),
+    }
+}
+
+/// Also closes the user's event streams and ends the session.
+pub async fn logout(
+    user: Option<UserId>,
+    id: Identity,
+    session: Session,
+    live: Live,
+    format: Format,
+) -> HttpResponse {
+    if let Some(UserId(user)) = user {
+        live.lock().unwrap().forget(user);
+    }
+    id.forget();
+    session.purge();
+    match format {
+        Format::Json => HttpResponse::NoContent().finish(),
+        Format::Html => redirect_to("/login"),
+    }
+}
+
+fn logged_in(
+    format: Format,
+    status: StatusCode,
+    id: i32,
+    username: String,
+) -> Result<HttpResponse, Error> {
+    match format {
+        Format::Json => Ok(HttpResponse::build(status).json(&Account { id, username })),
+        Format::Html => Ok(redirect_to("/")),
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, DbError> {
+    let salt = rand::random::<[u8; 16]>();
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+        .map_err(|_| DbError::Internal("Error hashing password"))
+}
+
+/// False for anything that isn't an argon2 hash, like the `legacy` user's.
+fn verify_password(hash: &str, password: &str) -> bool {
+    argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+}
@@ -1,41 +1,354 @@
+use std::fmt;
 use std::ops::Deref;
 
 use diesel::pg::PgConnection;
 use diesel::r2d2::{ConnectionManager, Pool, PoolError, PooledConnection};
+use diesel::Connection;
+use loony::http::error::BlockingError;
+use loony::http::StatusCode;
+use loony::web::{HttpRequest, HttpResponse, WebResponseError};
+use serde_json::Map;
 
-use crate::model::{NewTask, Task};
+use crate::filter::{Page, TaskFilter};
+use crate::model::{
+    Access, Action, List, ListSummary, NewList, NewTask, NewUser, Share, Task,
+    TaskChanges, TaskEvent, User,
+};
 
 pub type PgPool = Pool<ConnectionManager<PgConnection>>;
 type PgPooledConnection = PooledConnection<ConnectionManager<PgConnection>>;
 
+/// The list new users start with.
+const DEFAULT_LIST: &str = "Tasks";
+
+const TASK_NOT_FOUND: DbError = DbError::NotFound("Task not found");
+const LIST_NOT_FOUND: DbError = DbError::NotFound("List not found");
+const READ_ONLY: DbError = DbError::Forbidden("This list is read-only for you");
+const NOTHING_TO_UNDO: DbError = DbError::NotFound("Nothing to undo");
+
+/// Why something couldn't be done to a task or list on behalf of a user.
+/// Lists and tasks the user can't see are `NotFound`, `Forbidden` means they
+/// can see it but not do that.
+#[derive(Debug)]
+pub enum DbError {
+    NotFound(&'static str),
+    Forbidden(&'static str),
+    Pool(PoolError),
+    Query(diesel::result::Error),
+    Internal(&'static str),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::NotFound(msg)
+            | DbError::Forbidden(msg)
+            | DbError::Internal(msg) => f.write_str(msg),
+            DbError::Pool(e) => write!(f, "Can't get connection: {}", e),
+            DbError::Query(e) => write!(f, "Query failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<PoolError> for DbError {
+    fn from(e: PoolError) -> Self {
+        DbError::Pool(e)
+    }
+}
+
+impl From<diesel::result::Error> for DbError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => DbError::NotFound("Not found"),
+            e => DbError::Query(e),
+        }
+    }
+}
+
+impl From<BlockingError<DbError>> for DbError {
+    fn from(err: BlockingError<DbError>) -> Self {
+        match err {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => DbError::Internal("Database call was canceled"),
+        }
+    }
+}
+
+/// `{"error": "..."}` with the status that fits, the error pages turn it into
+/// HTML for browsers. What went wrong inside is only logged.
+impl WebResponseError for DbError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DbError::NotFound(_) => StatusCode::NOT_FOUND,
+            DbError::Forbidden(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self, _: &HttpRequest) -> HttpResponse {
+        let status = self.status_code();
+        let message = match self {
+            DbError::NotFound(msg) | DbError::Forbidden(msg) => msg,
+            e => {
+                error!("Database error: {}", e);
+                "Something went wrong, try again later"
+            }
+        };
+        HttpResponse::build(status).json(&serde_json::json!({ "error": message }))
+    }
+}
+
 pub fn init_pool(database_url: &str) -> Result<PgPool, PoolError> {
     let manager = ConnectionManager::<PgConnection>::new(database_url);
     Pool::builder().build(manager)
 }
 
-fn get_conn(pool: &PgPool) -> Result<PgPooledConnection, &'static str> {
-    pool.get().map_err(|_| "Can't get connection")
+fn get_conn(pool: &PgPool) -> Result<PgPooledConnection, DbError> {
+    Ok(pool.get()?)
+}
+
+/// Creates the user with their first list, `None` if the name is taken.
+pub fn create_user(
+    username: &str,
+    password_hash: &str,
+    pool: &PgPool,
+) -> Result<Option<User>, DbError> {
+    let conn = get_conn(pool)?;
+    let user = conn.transaction(|| {
+        let user = User::insert(
+            NewUser {
+                username,
+                password_hash,
+            },
+            &conn,
+        )?;
+        if let Some(user) = &user {
+            let list = NewList {
+                owner_id: user.id,
+                name: DEFAULT_LIST,
+            };
+            List::insert(list, &conn)?;
+        }
+        Ok::<_, diesel::result::Error>(user)
+    })?;
+    Ok(user)
+}
+
+pub fn find_user(username: &str, pool: &PgPool) -> Result<Option<User>, DbError> {
+    Ok(User::find_by_name(username, get_conn(pool)?.deref())?)
+}
+
+pub fn get_lists(user_id: i32, pool: &PgPool) -> Result<Vec<ListSummary>, DbError> {
+    Ok(List::visible(user_id, get_conn(pool)?.deref())?)
+}
+
+pub fn create_list(user_id: i32, name: &str, pool: &PgPool) -> Result<List, DbError> {
+    let new_list = NewList {
+        owner_id: user_id,
+        name,
+    };
+    Ok(List::insert(new_list, get_conn(pool)?.deref())?)
+}
+
+pub fn get_shares(
+    list_id: i32,
+    user_id: i32,
+    pool: &PgPool,
+) -> Result<Vec<Share>, DbError> {
+    let conn = get_conn(pool)?;
+    require_owner(list_id, user_id, &conn)?;
+    Ok(List::shares(list_id, &conn)?)
+}
+
+/// Shares the list with `username`, only its owner can.
+pub fn share_list(
+    list_id: i32,
+    user_id: i32,
+    username: &str,
+    access: Access,
+    pool: &PgPool,
+) -> Result<(), DbError> {
+    let conn = get_conn(pool)?;
+    require_owner(list_id, user_id, &conn)?;
+    let user =
+        User::find_by_name(username, &conn)?.ok_or(DbError::NotFound("No such user"))?;
+    List::share(list_id, user.id, access, &conn)?;
+    Ok(())
+}
+
+pub fn unshare_list(
+    list_id: i32,
+    user_id: i32,
+    username: &str,
+    pool: &PgPool,
+) -> Result<(), DbError> {
+    let conn = get_conn(pool)?;
+    require_owner(list_id, user_id, &conn)?;
+    let removed = match User::find_by_name(username, &conn)? {
+        Some(user) => List::unshare(list_id, user.id, &conn)?,
+        None => 0,
+    };
+    if removed == 0 {
+        return Err(DbError::NotFound("No such share"));
+    }
+    Ok(())
+}
+
+/// Who gets to hear about changes to the list.
+pub fn list_audience(list_id: i32, pool: &PgPool) -> Result<Vec<i32>, DbError> {
+    Ok(List::audience(list_id, get_conn(pool)?.deref())?)
+}
+
+fn require_owner(
+    list_id: i32,
+    user_id: i32,
+    conn: &PgConnection,
+) -> Result<(), DbError> {
+    match List::access(list_id, user_id, conn)? {
+        Some(Access::Owner) => Ok(()),
+        Some(_) => Err(DbError::Forbidden("Only the owner can do that")),
+        None => Err(LIST_NOT_FOUND),
+    }
+}
+
+/// A page of the tasks that pass `filter`, from all lists the user can see
+/// unless it names one.
+pub fn get_tasks(
+    user_id: i32,
+    filter: &TaskFilter,
+    pool: &PgPool,
+) -> Result<Page<Task>, DbError> {
+    let conn = get_conn(pool)?;
+    if let Some(list_id) = filter.list {
+        if List::access(list_id, user_id, &conn)?.is_none() {
+            return Err(LIST_NOT_FOUND);
+        }
+    }
+    Ok(Task::all(user_id, filter, &conn)?)
+}
+
+pub fn get_task(id: i32, user_id: i32, pool: &PgPool) -> Result<Task, DbError> {
+    Task::find(id, user_id, get_conn(pool)?.deref())?.ok_or(TASK_NOT_FOUND)
+}
+
+/// The task with what the user may do with its list.
+pub fn get_task_access(
+    id: i32,
+    user_id: i32,
+    pool: &PgPool,
+) -> Result<(Task, Access), DbError> {
+    let conn = get_conn(pool)?;
+    let task = Task::find(id, user_id, &conn)?.ok_or(TASK_NOT_FOUND)?;
+    let access = List::access(task.list_id, user_id, &conn)?.ok_or(TASK_NOT_FOUND)?;
+    Ok((task, access))
+}
+
+/// The user's first own list, where tasks go that don't name one.
+pub fn default_list(user_id: i32, pool: &PgPool) -> Result<i32, DbError> {
+    List::ids_with(user_id, Access::Owner, get_conn(pool)?.deref())?
+        .first()
+        .copied()
+        .ok_or(LIST_NOT_FOUND)
+}
+
+// Every change to a task goes into its history in the same transaction.
+
+pub fn create_task(
+    user_id: i32,
+    new_task: NewTask,
+    pool: &PgPool,
+) -> Result<Task, DbError> {
+    let conn = get_conn(pool)?;
+    let list_id = new_task.list_id;
+    conn.transaction(|| match Task::insert(new_task, user_id, &conn)? {
+        Some(task) => {
+            let changes = TaskEvent::changes(None, &task);
+            TaskEvent::record(task.id, user_id, Action::Created, changes, &conn)?;
+            Ok(task)
+        }
+        None if List::access(list_id, user_id, &conn)?.is_some() => Err(READ_ONLY),
+        None => Err(LIST_NOT_FOUND),
+    })
+}
+
+pub fn update_task(
+    id: i32,
+    user_id: i32,
+    changes: TaskChanges,
+    pool: &PgPool,
+) -> Result<Task, DbError> {
+    let conn = get_conn(pool)?;
+    conn.transaction(|| {
+        let before = Task::find(id, user_id, &conn)?;
+        let task = Task::update_with_id(id, user_id, changes, &conn)?;
+        let task = changed(task, id, user_id, &conn)?;
+        let changes = TaskEvent::changes(before.as_ref(), &task);
+        if !changes.is_empty() {
+            TaskEvent::record(id, user_id, Action::Edited, changes, &conn)?;
+        }
+        Ok(task)
+    })
+}
+
+pub fn toggle_task(id: i32, user_id: i32, pool: &PgPool) -> Result<Task, DbError> {
+    let conn = get_conn(pool)?;
+    conn.transaction(|| {
+        let task = Task::toggle_with_id(id, user_id, &conn)?;
+        let task = changed(task, id, user_id, &conn)?;
+        let action = if task.completed {
+            Action::Completed
+        } else {
+            Action::Reopened
+        };
+        TaskEvent::record(id, user_id, action, Map::new(), &conn)?;
+        Ok(task)
+    })
 }
 
-pub fn get_all_tasks(pool: &PgPool) -> Result<Vec<Task>, &'static str> {
-    Task::all(get_conn(pool)?.deref()).map_err(|_| "Error inserting task")
+/// The deleted task, which `restore_task` can bring back for a while.
+pub fn delete_task(id: i32, user_id: i32, pool: &PgPool) -> Result<Task, DbError> {
+    let conn = get_conn(pool)?;
+    conn.transaction(|| {
+        let task = Task::delete_with_id(id, user_id, &conn)?;
+        let task = changed(task, id, user_id, &conn)?;
+        TaskEvent::record(id, user_id, Action::Deleted, Map::new(), &conn)?;
+        Ok(task)
+    })
 }
 
-pub fn create_task(todo: String, pool: &PgPool) -> Result<(), &'static str> {
-    let new_task = NewTask { description: todo };
-    Task::insert(new_task, get_conn(pool)?.deref())
-        .map(|_| ())
-        .map_err(|_| "Error inserting task")
+pub fn restore_task(id: i32, user_id: i32, pool: &PgPool) -> Result<Task, DbError> {
+    let conn = get_conn(pool)?;
+    conn.transaction(|| {
+        let task = Task::restore_with_id(id, user_id, &conn)?.ok_or(NOTHING_TO_UNDO)?;
+        TaskEvent::record(id, user_id, Action::Restored, Map::new(), &conn)?;
+        Ok(task)
+    })
 }
 
-pub fn toggle_task(id: i32, pool: &PgPool) -> Result<(), &'static str> {
-    Task::toggle_with_id(id, get_conn(pool)?.deref())
-        .map(|_| ())
-        .map_err(|_| "Error inserting task")
+/// The task, deleted or not, with what happened to it.
+pub fn get_history(
+    id: i32,
+    user_id: i32,
+    pool: &PgPool,
+) -> Result<(Task, Vec<TaskEvent>), DbError> {
+    let conn = get_conn(pool)?;
+    let task = Task::find_with_deleted(id, user_id, &conn)?.ok_or(TASK_NOT_FOUND)?;
+    let events = TaskEvent::for_task(id, &conn)?;
+    Ok((task, events))
 }
 
-pub fn delete_task(id: i32, pool: &PgPool) -> Result<(), &'static str> {
-    Task::delete_with_id(id, get_conn(pool)?.deref())
-        .map(|_| ())
-        .map_err(|_| "Error inserting task")
+/// Tells a task in a read-only list from one that isn't there.
+fn changed(
+    task: Option<Task>,
+    id: i32,
+    user_id: i32,
+    conn: &PgConnection,
+) -> Result<Task, DbError> {
+    match task {
+        Some(task) => Ok(task),
+        None if Task::find(id, user_id, conn)?.is_some() => Err(READ_ONLY),
+        None => Err(TASK_NOT_FOUND),
+    }
 }
@@ -0,0 +1,195 @@
+//! Turns error responses into something the client can use.
+//!
+//! Browsers get the page rendered from `templates/errors/{status}.html.tera`,
+//! or from `errors/error.html.tera` for statuses without their own page, and
+//! are sent to the login form on `401`. API clients get `{"error": "..."}`.
+//! Server errors are logged with their cause, clients only ever see the
+//! message of `4xx` errors.
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use loony::http::body::{Body, ResponseBody};
+use loony::http::{header, StatusCode};
+use loony::web::{Error, ErrorRenderer, HttpResponse, WebRequest, WebResponse};
+use loony::{Service, Transform};
+use tera::Tera;
+
+use crate::api::redirect_to;
+use crate::negotiate::Format;
+
+const FALLBACK_PAGE: &str = "errors/error.html.tera";
+
+pub struct ErrorPages {
+    templates: Rc<Tera>,
+}
+
+impl ErrorPages {
+    pub fn new(templates: Tera) -> Self {
+        ErrorPages {
+            templates: Rc::new(templates),
+        }
+    }
+}
+
+impl<S, Err> Transform<S> for ErrorPages
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Service = ErrorPagesMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        ErrorPagesMiddleware {
+            service: Rc::new(service),
+            templates: self.templates.clone(),
+        }
+    }
+}
+
+pub struct ErrorPagesMiddleware<S> {
+    service: Rc<S>,
+    templates: Rc<Tera>,
+}
+
+impl<S, Err> Service for ErrorPagesMiddleware<S>
+where
+    S: Service<Request = WebRequest<Err>, Response = WebResponse, Error = Error>
+        + 'static,
+    Err: ErrorRenderer,
+{
+    type Request = WebRequest<Err>;
+    type Response = WebResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let svc = self.service.clone();
+        let templates = self.templates.clone();
+
+        Box::pin(async move {
+            let res = svc.call(req).await?;
+            let status = res.status();
+            if !status.is_client_error() && !status.is_server_error() {
+                return Ok(res);
+            }
+
+            let message = error_message(&res);
+            let (method, path) = (res.request().method(), res.request().path());
+            if status.is_server_error() {
+                error!(
+                    "{} {} failed with {}: {}",
+                    method,
+                    path,
+                    status,
+                    message.as_deref().unwrap_or("no details")
+                );
+            } else {
+                debug!("{} {} answered {}", method, path, status);
+            }
+
+            // the cause of a server error is none of the client's business
+            let message = message.filter(|_| status.is_client_error());
+            let response = match Format::of(res.request()) {
+                Format::Json if is_json(&res) => return Ok(res),
+                Format::Json => HttpResponse::build(status).json(&serde_json::json!({
+                    "error": message.as_deref().unwrap_or_else(|| reason(status)),
+                })),
+                Format::Html if status == StatusCode::UNAUTHORIZED => {
+                    redirect_to("/login")
+                }
+                Format::Html => error_page(&templates, status, message.as_deref()),
+            };
+            Ok(res.into_response(response))
+        })
+    }
+}
+
+fn error_page(
+    templates: &Tera,
+    status: StatusCode,
+    message: Option<&str>,
+) -> HttpResponse {
+    let name = format!("errors/{}.html.tera", status.as_u16());
+    let name = if templates.get_template_names().any(|t| t == name) {
+        name.as_str()
+    } else {
+        FALLBACK_PAGE
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("status", &status.as_u16());
+    context.insert("reason", reason(status));
+    context.insert("message", &message);
+    match templates.render(name, &context) {
+        Ok(rendered) => HttpResponse::build(status)
+            .content_type("text/html; charset=utf-8")
+            .body(rendered),
+        Err(e) => {
+            error!("Can't render {}: {}", name, e);
+            HttpResponse::build(status)
+                .content_type("text/plain")
+                .body(reason(status))
+        }
+    }
+}
+
+fn reason(status: StatusCode) -> &'static str {
+    status.canonical_reason().unwrap_or("Error")
+}
+
+fn is_json(res: &WebResponse) -> bool {
+    res.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |ct| ct.starts_with("application/json"))
+}
+
+/// What the handler said went wrong: the `error` of a JSON body, or a plain
+/// text body as is. Streamed bodies aren't looked at.
+fn error_message(res: &WebResponse) -> Option<String> {
+    let bytes = match res.response().body() {
+        ResponseBody::Body(Body::Bytes(bytes))
+        | ResponseBody::Other(Body::Bytes(bytes)) => bytes,
+        _ => return None,
+    };
+    let message = if is_json(res) {
+        let body: serde_json::Value = serde_json::from_slice(bytes).ok()?;
+        body.get("error")?.as_str()?.to_owned()
+    } else {
+        String::from_utf8(bytes.to_vec()).ok()?
+    };
+    Some(message).filter(|message| !message.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(status: StatusCode, message: &str) -> String {
+        let response = error_page(&crate::templates(), status, Some(message));
+        match response.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => {
+                String::from_utf8(bytes.to_vec()).unwrap()
+            }
+            _ => panic!("not rendered"),
+        }
+    }
+
+    #[test]
+    fn escapes_the_message() {
+        // serde repeats what it didn't understand
+        let message = "unknown variant `<script>alert(1)</script>`";
+        for status in &[StatusCode::BAD_REQUEST, StatusCode::CONFLICT] {
+            let page = page(*status, message);
+            assert!(page.contains("unknown variant `&lt;script&gt;alert(1)"));
+            assert!(!page.contains("<script>"));
+        }
+    }
+}
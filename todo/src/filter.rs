@@ -0,0 +1,201 @@
+//! Which tasks the index shows, in which order, and which page of them.
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+const PER_PAGE: i64 = 20;
+const MAX_PER_PAGE: i64 = 100;
+/// far past any real last page, keeps the offset from overflowing
+const MAX_PAGE: i64 = 1_000_000;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    All,
+    Open,
+    Completed,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    /// newest first
+    Created,
+    /// soonest first, tasks without a due date last
+    Due,
+    /// highest first, then by due date
+    Priority,
+}
+
+/// The query string of the index and `/api/tasks`. Empty `list`, `page` and
+/// `per_page` fields count as left out, `page` is capped at `MAX_PAGE`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TaskFilter {
+    #[serde(
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub list: Option<i32>,
+    pub status: Status,
+    /// open tasks due before today
+    pub overdue: bool,
+    pub tag: String,
+    /// searched for in descriptions and notes
+    pub q: String,
+    pub sort: Sort,
+    #[serde(
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub page: Option<i64>,
+    #[serde(
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub per_page: Option<i64>,
+}
+
+impl Default for TaskFilter {
+    fn default() -> Self {
+        TaskFilter {
+            list: None,
+            status: Status::All,
+            overdue: false,
+            tag: String::new(),
+            q: String::new(),
+            sort: Sort::Created,
+            page: None,
+            per_page: None,
+        }
+    }
+}
+
+impl TaskFilter {
+    pub fn tag(&self) -> Option<String> {
+        Some(normalize_tag(&self.tag)).filter(|tag| !tag.is_empty())
+    }
+
+    /// A `LIKE` pattern matching text that contains `q`.
+    pub fn search_pattern(&self) -> Option<String> {
+        let q = self.q.trim();
+        if q.is_empty() {
+            return None;
+        }
+        let escaped = q
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        Some(format!("%{}%", escaped))
+    }
+
+    pub fn page(&self) -> i64 {
+        self.page.unwrap_or(1).max(1).min(MAX_PAGE)
+    }
+
+    pub fn per_page(&self) -> i64 {
+        self.per_page.unwrap_or(PER_PAGE).max(1).min(MAX_PER_PAGE)
+    }
+
+    pub fn offset(&self) -> i64 {
+        (self.page() - 1) * self.per_page()
+    }
+
+    /// The query string of another page of the same tasks.
+    pub fn query_for_page(&self, page: i64) -> String {
+        let filter = TaskFilter {
+            page: Some(page),
+            per_page: Some(self.per_page()),
+            ..self.clone()
+        };
+        serde_urlencoded::to_string(&filter).unwrap_or_default()
+    }
+}
+
+/// One page of results.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+impl<T> Page<T> {
+    pub fn last_page(&self) -> i64 {
+        ((self.total + self.per_page - 1) / self.per_page).max(1)
+    }
+}
+
+/// A number, an empty string is none like a left out field.
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !value.trim().is_empty() => {
+            value.trim().parse().map(Some).map_err(de::Error::custom)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Tags are lowercase and without a leading `#`.
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().trim_start_matches('#').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_from_query_strings() {
+        let filter: TaskFilter =
+            serde_urlencoded::from_str("status=open&tag=%23Home&q=50%25_off&page=0")
+                .unwrap();
+        assert_eq!(filter.status, Status::Open);
+        assert_eq!(filter.tag().as_deref(), Some("home"));
+        assert_eq!(filter.search_pattern().as_deref(), Some("%50\\%\\_off%"));
+        assert_eq!(filter.page(), 1);
+        assert_eq!(filter.sort, Sort::Created);
+
+        let next = filter.query_for_page(2);
+        assert!(next.contains("page=2"));
+        assert!(next.contains("status=open"));
+    }
+
+    #[test]
+    fn empty_fields_are_left_out() {
+        let filter: TaskFilter =
+            serde_urlencoded::from_str("list=&page=&per_page=&q=").unwrap();
+        assert_eq!(filter.list, None);
+        assert_eq!(filter.page(), 1);
+        assert_eq!(filter.per_page(), PER_PAGE);
+        assert!(serde_urlencoded::from_str::<TaskFilter>("list=abc").is_err());
+    }
+
+    #[test]
+    fn huge_pages_do_not_overflow() {
+        let filter: TaskFilter =
+            serde_urlencoded::from_str("page=9223372036854775807&per_page=100").unwrap();
+        assert_eq!(filter.page(), MAX_PAGE);
+        assert_eq!(filter.offset(), (MAX_PAGE - 1) * MAX_PER_PAGE);
+    }
+
+    #[test]
+    fn counts_pages() {
+        let page = |total| Page::<()> {
+            items: Vec::new(),
+            page: 1,
+            per_page: 20,
+            total,
+        };
+        assert_eq!(page(0).last_page(), 1);
+        assert_eq!(page(20).last_page(), 1);
+        assert_eq!(page(21).last_page(), 2);
+    }
+}
@@ -0,0 +1,164 @@
+use loony::http::{self, StatusCode};
+use loony::web::{self, Error, HttpRequest, HttpResponse};
+use loony_session::Session;
+use serde::Deserialize;
+
+use crate::api::{failed, list_location, paginated, redirect_to};
+use crate::auth::UserId;
+use crate::db::{self, DbError};
+use crate::filter::TaskFilter;
+use crate::model::Access;
+use crate::negotiate::{Format, Input};
+use crate::session::{self, FlashMessage};
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ShareParams {
+    id: i32,
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewListForm {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ShareForm {
+    username: String,
+    /// `viewer` or `editor`
+    access: Access,
+}
+
+/// The lists the user owns or that are shared with them.
+pub async fn index(
+    user: UserId,
+    pool: web::types::Data<db::PgPool>,
+) -> Result<HttpResponse, Error> {
+    let lists = web::block(move || db::get_lists(user.0, &pool))
+        .await
+        .map_err(DbError::from)?;
+    Ok(HttpResponse::Ok().json(&lists))
+}
+
+pub async fn create(
+    user: UserId,
+    form: Input<NewListForm>,
+    format: Format,
+    pool: web::types::Data<db::PgPool>,
+    session: Session,
+) -> Result<HttpResponse, Error> {
+    let name = form.into_inner().name.trim().to_owned();
+    if name.is_empty() {
+        return failed(
+            format,
+            &session,
+            StatusCode::BAD_REQUEST,
+            "Name cannot be empty",
+        );
+    }
+
+    let list = web::block(move || db::create_list(user.0, &name, &pool))
+        .await
+        .map_err(DbError::from)?;
+    match format {
+        Format::Json => Ok(HttpResponse::Created()
+            .header(
+                http::header::LOCATION,
+                format!("/api/lists/{}/tasks", list.id),
+            )
+            .json(&list)),
+        Format::Html => Ok(redirect_to(&list_location(Some(list.id)))),
+    }
+}
+
+/// Like `/api/tasks?list={id}`.
+pub async fn tasks(
+    req: HttpRequest,
+    user: UserId,
+    params: web::types::Path<ListParams>,
+    query: web::types::Query<TaskFilter>,
+    pool: web::types::Data<db::PgPool>,
+) -> Result<HttpResponse, Error> {
+    let filter = TaskFilter {
+        list: Some(params.id),
+        ..query.into_inner()
+    };
+    let query = filter.clone();
+    let page = web::block(move || db::get_tasks(user.0, &query, &pool))
+        .await
+        .map_err(DbError::from)?;
+    Ok(paginated(req.path(), &filter, page))
+}
+
+/// Who the list is shared with, only for its owner.
+pub async fn shares(
+    user: UserId,
+    params: web::types::Path<ListParams>,
+    pool: web::types::Data<db::PgPool>,
+) -> Result<HttpResponse, Error> {
+    let id = params.id;
+    let shares = web::block(move || db::get_shares(id, user.0, &pool))
+        .await
+        .map_err(DbError::from)?;
+    Ok(HttpResponse::Ok().json(&shares))
+}
+
+/// Shares the list, or changes what an existing share allows.
+pub async fn share(
+    user: UserId,
+    params: web::types::Path<ListParams>,
+    form: Input<ShareForm>,
+    format: Format,
+    pool: web::types::Data<db::PgPool>,
+    session: Session,
+) -> Result<HttpResponse, Error> {
+    let ShareForm { username, access } = form.into_inner();
+    if access == Access::Owner {
+        return failed(
+            format,
+            &session,
+            StatusCode::BAD_REQUEST,
+            "Lists are shared with viewers or editors",
+        );
+    }
+
+    let id = params.id;
+    web::block(move || db::share_list(id, user.0, username.trim(), access, &pool))
+        .await
+        .map_err(DbError::from)?;
+    shared_back(format, &session, id, "List shared")
+}
+
+pub async fn unshare(
+    user: UserId,
+    params: web::types::Path<ShareParams>,
+    format: Format,
+    pool: web::types::Data<db::PgPool>,
+    session: Session,
+) -> Result<HttpResponse, Error> {
+    let ShareParams { id, username } = params.into_inner();
+    web::block(move || db::unshare_list(id, user.0, &username, &pool))
+        .await
+        .map_err(DbError::from)?;
+    shared_back(format, &session, id, "List no longer shared")
+}
+
+fn shared_back(
+    format: Format,
+    session: &Session,
+    list_id: i32,
+    message: &str,
+) -> Result<HttpResponse, Error> {
+    match format {
+        Format::Json => Ok(HttpResponse::NoContent().finish()),
+        Format::Html => {
+            session::set_flash(session, FlashMessage::success(message))?;
+            Ok(redirect_to(&list_location(Some(list_id))))
+        }
+    }
+}
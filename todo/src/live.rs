@@ -0,0 +1,153 @@
+//! Pushes task changes to the open pages of everyone who can see the list,
+//! as server-sent events on `/events`:
+//!
+//! ```text
+//! event: task
+//! data: {"change":"updated","task":{"id":1,"list_id":2,...}}
+//! ```
+//!
+//! Streams belong to a user, and a change only goes to the owner of the
+//! list and the users it is shared with.
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Stream;
+use loony::util::Bytes;
+use loony::web::{self, Error, HttpResponse};
+use serde::Serialize;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::{interval_at, Instant};
+
+use crate::auth::UserId;
+use crate::db::{self, DbError};
+use crate::model::Task;
+
+pub type Live = web::types::Data<Mutex<Broadcaster>>;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(Serialize)]
+struct TaskEvent<'a> {
+    change: Change,
+    task: &'a Task,
+}
+
+/// The stream of changes to the lists the user can see.
+pub async fn events(user: UserId, live: Live) -> HttpResponse {
+    let rx = live.lock().unwrap().new_client(user.0);
+
+    HttpResponse::Ok()
+        .header("content-type", "text/event-stream")
+        .header("cache-control", "no-cache")
+        .no_chunking()
+        .streaming(rx)
+}
+
+/// Tells everyone who can see the list of `task` about the change. The
+/// change already happened, so failing to look them up is only logged.
+pub async fn publish(
+    live: &Live,
+    pool: web::types::Data<db::PgPool>,
+    change: Change,
+    task: &Task,
+) {
+    let list_id = task.list_id;
+    let audience = match web::block(move || db::list_audience(list_id, &pool)).await {
+        Ok(audience) => audience,
+        Err(e) => {
+            error!(
+                "Can't publish change to list {}: {}",
+                list_id,
+                DbError::from(e)
+            );
+            return;
+        }
+    };
+    match serde_json::to_string(&TaskEvent { change, task }) {
+        Ok(data) => live.lock().unwrap().send_to(&audience, "task", &data),
+        Err(e) => error!("Can't serialize change: {}", e),
+    }
+}
+
+pub struct Broadcaster {
+    clients: Vec<(i32, Sender<Bytes>)>,
+}
+
+impl Broadcaster {
+    pub fn create() -> Live {
+        // Data ≃ Arc
+        let me = web::types::Data::new(Mutex::new(Broadcaster {
+            clients: Vec::new(),
+        }));
+
+        // ping clients every 10 seconds to see if they are alive
+        Broadcaster::spawn_ping(me.clone());
+
+        me
+    }
+
+    fn spawn_ping(me: Live) {
+        loony::rt::spawn(async move {
+            let mut task = interval_at(Instant::now(), Duration::from_secs(10));
+            loop {
+                task.tick().await;
+                me.lock().unwrap().remove_stale_clients();
+            }
+        });
+    }
+
+    fn remove_stale_clients(&mut self) {
+        // comments keep the connection open without firing events
+        let ping = Bytes::from(": ping\n\n");
+        self.clients
+            .retain(|(_, client)| client.clone().try_send(ping.clone()).is_ok());
+    }
+
+    fn new_client(&mut self, user_id: i32) -> Client {
+        let (tx, rx) = channel(100);
+
+        tx.clone().try_send(Bytes::from(": connected\n\n")).unwrap();
+
+        self.clients.push((user_id, tx));
+        Client(rx)
+    }
+
+    fn send_to(&self, users: &[i32], event: &str, data: &str) {
+        let msg = Bytes::from(["event: ", event, "\ndata: ", data, "\n\n"].concat());
+
+        for (_, client) in self.clients.iter().filter(|(user, _)| users.contains(user)) {
+            client.clone().try_send(msg.clone()).unwrap_or(());
+        }
+    }
+
+    // dropping the senders ends the matching `Client` streams
+    pub fn forget(&mut self, user_id: i32) {
+        self.clients.retain(|(user, _)| *user != user_id);
+    }
+}
+
+// wrap Receiver in own type, with correct error type
+struct Client(Receiver<Bytes>);
+
+impl Stream for Client {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.0).poll_recv(cx) {
+            Poll::Ready(Some(v)) => Poll::Ready(Some(Ok(v))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -3,23 +3,35 @@ extern crate diesel;
 #[macro_use]
 extern crate log;
 
+use std::sync::Arc;
+use std::time::Duration;
 use std::{env, io};
 
+use csrf_middleware::Csrf;
 use dotenv::dotenv;
+use loony::rt::time_driver::sleep;
 use loony::web;
 use loony::web::middleware::Logger;
 use loony_files as fs;
-use loony_session::CookieSession;
+use loony_identity::{CookieIdentityPolicy, IdentityService};
+use session_store::{
+    MemoryStore, ServerSession, SessionStore, DEFAULT_ABSOLUTE_TIMEOUT,
+    DEFAULT_IDLE_TIMEOUT,
+};
 use tera::Tera;
 
 mod api;
+mod auth;
 mod db;
+mod errors;
+mod filter;
+mod lists;
+mod live;
 mod model;
+mod negotiate;
 mod schema;
 mod session;
 
-static SESSION_SIGNING_KEY: &[u8] = &[0; 32];
-
 #[loony::main]
 async fn main() -> io::Result<()> {
     dotenv().ok();
@@ -29,27 +41,106 @@ async fn main() -> io::Result<()> {
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let pool = db::init_pool(&database_url).expect("Failed to create pool");
+    let keys = cookie_keys::Keyring::from_env().expect("Failed to load cookie keys");
+    let live = live::Broadcaster::create();
+
+    // holds the flash messages, the login lives in the identity cookie
+    let sessions: Arc<dyn SessionStore> = Arc::new(MemoryStore::default());
+    let store = sessions.clone();
+    loony::rt::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(60)).await;
+            let removed = session_store::remove_expired(
+                &*store,
+                DEFAULT_IDLE_TIMEOUT,
+                DEFAULT_ABSOLUTE_TIMEOUT,
+            )
+            .await;
+            if let Err(e) = removed {
+                error!("Removing expired sessions failed: {:?}", e);
+            }
+        }
+    });
 
     let app = move || {
         debug!("Constructing the App");
 
-        let templates: Tera = Tera::new("templates/**/*").unwrap();
-
-        let session_store = CookieSession::signed(SESSION_SIGNING_KEY).secure(false);
+        let templates = templates();
 
         web::App::new()
-            .data(templates)
+            .data(templates.clone())
             .data(pool.clone())
+            .app_data(live.clone())
+            .wrap(Csrf)
+            .wrap(errors::ErrorPages::new(templates))
             .wrap(Logger::default())
-            .wrap(session_store)
+            .wrap(ServerSession::new(sessions.clone()).secure(false))
+            .wrap(IdentityService::new(
+                CookieIdentityPolicy::new(keys.active())
+                    .name("auth")
+                    .secure(false),
+            ))
+            .wrap(keys.rotation().private("auth"))
             .service((
                 web::resource("/").route(web::get().to(api::index)),
+                web::resource("/login")
+                    .route(web::get().to(auth::login_page))
+                    .route(web::post().to(auth::login)),
+                web::resource("/register").route(web::post().to(auth::register)),
+                web::resource("/logout").route(web::post().to(auth::logout)),
+                web::resource("/lists").route(web::post().to(lists::create)),
+                web::resource("/lists/{id}/shares").route(web::post().to(lists::share)),
+                // forms can't send DELETE
+                web::resource("/lists/{id}/shares/{username}")
+                    .route(web::post().to(lists::unshare))
+                    .route(web::delete().to(lists::unshare)),
                 web::resource("/todo").route(web::post().to(api::create)),
-                web::resource("/todo/{id}").route(web::post().to(api::update)),
-                fs::Files::new("/static", "static/"),
+                web::resource("/todo/{id}")
+                    .route(web::get().to(api::row))
+                    .route(web::post().to(api::update))
+                    .route(web::patch().to(api::patch))
+                    .route(web::delete().to(api::delete)),
+                web::resource("/todo/{id}/restore").route(web::post().to(api::restore)),
+                web::resource("/todo/{id}/history").route(web::get().to(api::history)),
+                web::resource("/events").route(web::get().to(live::events)),
             ))
+            .service((
+                web::resource("/api/tasks")
+                    .route(web::get().to(api::index))
+                    .route(web::post().to(api::create)),
+                web::resource("/api/tasks/{id}")
+                    .route(web::get().to(api::show))
+                    .route(web::patch().to(api::patch))
+                    .route(web::delete().to(api::delete)),
+                web::resource("/api/tasks/{id}/restore")
+                    .route(web::post().to(api::restore)),
+                web::resource("/api/tasks/{id}/history")
+                    .route(web::get().to(api::history)),
+                web::resource("/api/lists")
+                    .route(web::get().to(lists::index))
+                    .route(web::post().to(lists::create)),
+                web::resource("/api/lists/{id}/tasks")
+                    .route(web::get().to(lists::tasks)),
+                web::resource("/api/lists/{id}/shares")
+                    .route(web::get().to(lists::shares))
+                    .route(web::post().to(lists::share)),
+                web::resource("/api/lists/{id}/shares/{username}")
+                    .route(web::delete().to(lists::unshare)),
+                web::resource("/api/login").route(web::post().to(auth::login)),
+                web::resource("/api/register").route(web::post().to(auth::register)),
+                web::resource("/api/logout").route(web::post().to(auth::logout)),
+            ))
+            .service(fs::Files::new("/static", "static/"))
     };
 
     debug!("Starting server");
     web::server(app).bind("localhost:8088")?.run().await
 }
+
+/// The pages and the error pages. Tera only escapes templates ending in
+/// `.html`, `.htm` or `.xml` by default, so `.html.tera` is added.
+fn templates() -> Tera {
+    let mut templates = Tera::new("templates/**/*").unwrap();
+    templates.autoescape_on(vec![".html.tera"]);
+    templates
+}
@@ -1,16 +1,270 @@
-use diesel::pg::PgConnection;
+use std::ops::RangeInclusive;
+
+use chrono::{DateTime, Duration, Local, NaiveDate, Utc};
+use diesel::pg::{Pg, PgConnection};
 use diesel::prelude::*;
-use serde::Serialize;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::filter::{normalize_tag, Page, Sort, Status, TaskFilter};
 
 use crate::schema::{
-    tasks,
+    list_shares, lists, task_events, tasks,
     tasks::dsl::{completed as task_completed, tasks as all_tasks},
+    users,
 };
 
+/// What a user may do with a list. Owners can also share it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+impl Access {
+    /// The `list_shares.role` of a share.
+    pub fn role(self) -> &'static str {
+        match self {
+            Access::Viewer => "viewer",
+            Access::Editor => "editor",
+            Access::Owner => "owner",
+        }
+    }
+
+    fn from_role(role: &str) -> Option<Access> {
+        match role {
+            "viewer" => Some(Access::Viewer),
+            "editor" => Some(Access::Editor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Queryable)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub password_hash: String,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "users"]
+pub struct NewUser<'a> {
+    pub username: &'a str,
+    pub password_hash: &'a str,
+}
+
+impl User {
+    /// `None` if the name is taken.
+    pub fn insert(user: NewUser, conn: &PgConnection) -> QueryResult<Option<User>> {
+        diesel::insert_into(users::table)
+            .values(&user)
+            .on_conflict_do_nothing()
+            .get_result(conn)
+            .optional()
+    }
+
+    pub fn find_by_name(
+        username: &str,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<User>> {
+        users::table
+            .filter(users::username.eq(username))
+            .first(conn)
+            .optional()
+    }
+}
+
+#[derive(Debug, Queryable, Serialize)]
+pub struct List {
+    pub id: i32,
+    pub owner_id: i32,
+    pub name: String,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "lists"]
+pub struct NewList<'a> {
+    pub owner_id: i32,
+    pub name: &'a str,
+}
+
+/// A list as one of the users who can see it sees it.
+#[derive(Debug, Serialize)]
+pub struct ListSummary {
+    pub id: i32,
+    pub name: String,
+    pub owner: String,
+    pub access: Access,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Share {
+    pub username: String,
+    pub access: Access,
+}
+
+impl List {
+    pub fn insert(list: NewList, conn: &PgConnection) -> QueryResult<List> {
+        diesel::insert_into(lists::table)
+            .values(&list)
+            .get_result(conn)
+    }
+
+    /// The user's own lists first, then the ones shared with them.
+    pub fn visible(user_id: i32, conn: &PgConnection) -> QueryResult<Vec<ListSummary>> {
+        let own = lists::table
+            .inner_join(users::table)
+            .filter(lists::owner_id.eq(user_id))
+            .order(lists::id)
+            .select((lists::id, lists::name, users::username))
+            .load::<(i32, String, String)>(conn)?;
+        let shared = list_shares::table
+            .inner_join(lists::table.inner_join(users::table))
+            .filter(list_shares::user_id.eq(user_id))
+            .order(lists::id)
+            .select((lists::id, lists::name, users::username, list_shares::role))
+            .load::<(i32, String, String, String)>(conn)?;
+
+        let own = own.into_iter().map(|(id, name, owner)| ListSummary {
+            id,
+            name,
+            owner,
+            access: Access::Owner,
+        });
+        let shared = shared.into_iter().filter_map(|(id, name, owner, role)| {
+            Some(ListSummary {
+                id,
+                name,
+                owner,
+                access: Access::from_role(&role)?,
+            })
+        });
+        Ok(own.chain(shared).collect())
+    }
+
+    pub fn access(
+        list_id: i32,
+        user_id: i32,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<Access>> {
+        let owner_id = lists::table
+            .find(list_id)
+            .select(lists::owner_id)
+            .first::<i32>(conn)
+            .optional()?;
+        match owner_id {
+            None => Ok(None),
+            Some(owner_id) if owner_id == user_id => Ok(Some(Access::Owner)),
+            Some(_) => {
+                let role = list_shares::table
+                    .find((list_id, user_id))
+                    .select(list_shares::role)
+                    .first::<String>(conn)
+                    .optional()?;
+                Ok(role.as_deref().and_then(Access::from_role))
+            }
+        }
+    }
+
+    /// Ids of the lists the user has at least `access` to.
+    pub fn ids_with(
+        user_id: i32,
+        access: Access,
+        conn: &PgConnection,
+    ) -> QueryResult<Vec<i32>> {
+        let mut ids = lists::table
+            .filter(lists::owner_id.eq(user_id))
+            .select(lists::id)
+            .load::<i32>(conn)?;
+        let roles: &[&str] = match access {
+            Access::Viewer => &["viewer", "editor"],
+            Access::Editor => &["editor"],
+            Access::Owner => &[],
+        };
+        ids.extend(
+            list_shares::table
+                .filter(list_shares::user_id.eq(user_id))
+                .filter(list_shares::role.eq_any(roles))
+                .select(list_shares::list_id)
+                .load::<i32>(conn)?,
+        );
+        Ok(ids)
+    }
+
+    /// Ids of the owner and of the users the list is shared with.
+    pub fn audience(list_id: i32, conn: &PgConnection) -> QueryResult<Vec<i32>> {
+        let mut ids = lists::table
+            .find(list_id)
+            .select(lists::owner_id)
+            .load::<i32>(conn)?;
+        ids.extend(
+            list_shares::table
+                .filter(list_shares::list_id.eq(list_id))
+                .select(list_shares::user_id)
+                .load::<i32>(conn)?,
+        );
+        Ok(ids)
+    }
+
+    pub fn shares(list_id: i32, conn: &PgConnection) -> QueryResult<Vec<Share>> {
+        let shares = list_shares::table
+            .inner_join(users::table)
+            .filter(list_shares::list_id.eq(list_id))
+            .order(users::username)
+            .select((users::username, list_shares::role))
+            .load::<(String, String)>(conn)?;
+        Ok(shares
+            .into_iter()
+            .filter_map(|(username, role)| {
+                Some(Share {
+                    username,
+                    access: Access::from_role(&role)?,
+                })
+            })
+            .collect())
+    }
+
+    /// Shares the list or changes the role of an existing share.
+    pub fn share(
+        list_id: i32,
+        user_id: i32,
+        access: Access,
+        conn: &PgConnection,
+    ) -> QueryResult<usize> {
+        diesel::insert_into(list_shares::table)
+            .values((
+                list_shares::list_id.eq(list_id),
+                list_shares::user_id.eq(user_id),
+                list_shares::role.eq(access.role()),
+            ))
+            .on_conflict((list_shares::list_id, list_shares::user_id))
+            .do_update()
+            .set(list_shares::role.eq(access.role()))
+            .execute(conn)
+    }
+
+    pub fn unshare(
+        list_id: i32,
+        user_id: i32,
+        conn: &PgConnection,
+    ) -> QueryResult<usize> {
+        diesel::delete(list_shares::table.find((list_id, user_id))).execute(conn)
+    }
+}
+
 #[derive(Debug, Insertable)]
 #[table_name = "tasks"]
 pub struct NewTask {
     pub description: String,
+    pub list_id: i32,
+    pub due_date: Option<NaiveDate>,
+    pub priority: i16,
+    pub notes: String,
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Queryable, Serialize)]
@@ -18,30 +272,492 @@ pub struct Task {
     pub id: i32,
     pub description: String,
     pub completed: bool,
+    pub list_id: i32,
+    pub due_date: Option<NaiveDate>,
+    /// 1 low, 2 normal, 3 high
+    pub priority: i16,
+    pub notes: String,
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
+pub const PRIORITIES: RangeInclusive<i16> = 1..=3;
+pub const NORMAL_PRIORITY: i16 = 2;
+/// How long a deleted task can be restored.
+pub const UNDO_MINUTES: i64 = 10;
+
+/// The fields a `PATCH` changes, missing ones are left alone. A `null` or
+/// empty `due_date` removes it.
+#[derive(Debug, AsChangeset, Deserialize)]
+#[table_name = "tasks"]
+pub struct TaskChanges {
+    pub description: Option<String>,
+    pub completed: Option<bool>,
+    #[serde(default, deserialize_with = "clearable_date")]
+    pub due_date: Option<Option<NaiveDate>>,
+    pub priority: Option<i16>,
+    pub notes: Option<String>,
+    #[serde(default, deserialize_with = "tag_list")]
+    pub tags: Option<Vec<String>>,
+}
+
+impl TaskChanges {
+    fn is_empty(&self) -> bool {
+        self.description.is_none()
+            && self.completed.is_none()
+            && self.due_date.is_none()
+            && self.priority.is_none()
+            && self.notes.is_none()
+            && self.tags.is_none()
+    }
+}
+
+/// A date as `2026-10-19`, an empty string is none.
+pub fn optional_date<'de, D>(deserializer: D) -> Result<Option<NaiveDate>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(date) if !date.trim().is_empty() => {
+            date.trim().parse().map(Some).map_err(de::Error::custom)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn clearable_date<'de, D>(deserializer: D) -> Result<Option<Option<NaiveDate>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    optional_date(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Tags {
+    List(Vec<String>),
+    /// separated by commas or spaces, as typed into a form
+    Text(String),
+}
+
+/// Tags as a list or a string, normalized and without duplicates.
+pub fn tag_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tags = match Option::<Tags>::deserialize(deserializer)? {
+        Some(Tags::List(tags)) => tags,
+        Some(Tags::Text(text)) => text
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .map(str::to_owned)
+            .collect(),
+        None => return Ok(None),
+    };
+    let mut normalized = Vec::new();
+    for tag in tags.iter().map(|tag| normalize_tag(tag)) {
+        if !tag.is_empty() && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    Ok(Some(normalized))
+}
+
+// Every query takes the user and only touches tasks in lists they can see,
+// or edit for the ones that change something. Deleted tasks are left out
+// unless the name says otherwise.
 impl Task {
-    pub fn all(conn: &PgConnection) -> QueryResult<Vec<Task>> {
-        all_tasks.order(tasks::id.desc()).load::<Task>(conn)
+    pub fn is_overdue(&self, today: NaiveDate) -> bool {
+        !self.completed && self.due_date.map_or(false, |due| due < today)
     }
 
-    pub fn insert(todo: NewTask, conn: &PgConnection) -> QueryResult<usize> {
-        diesel::insert_into(tasks::table)
-            .values(&todo)
-            .execute(conn)
+    /// Whether `Task::all` would find the task with `filter`, leaving the
+    /// page aside.
+    pub fn matches(&self, filter: &TaskFilter, today: NaiveDate) -> bool {
+        let q = filter.q.trim().to_lowercase();
+        let status = match filter.status {
+            Status::All => true,
+            Status::Open => !self.completed,
+            Status::Completed => self.completed,
+        };
+        self.deleted_at.is_none()
+            && filter.list.map_or(true, |list_id| list_id == self.list_id)
+            && status
+            && (!filter.overdue || self.is_overdue(today))
+            && filter.tag().map_or(true, |tag| self.tags.contains(&tag))
+            && (q.is_empty()
+                || self.description.to_lowercase().contains(&q)
+                || self.notes.to_lowercase().contains(&q))
+    }
+
+    /// A page of the tasks the user can see that pass `filter`.
+    pub fn all(
+        user_id: i32,
+        filter: &TaskFilter,
+        conn: &PgConnection,
+    ) -> QueryResult<Page<Task>> {
+        let visible = List::ids_with(user_id, Access::Viewer, conn)?;
+        let total = Task::filtered(visible.clone(), filter)
+            .count()
+            .get_result::<i64>(conn)?;
+
+        let query = Task::filtered(visible, filter);
+        let query = match filter.sort {
+            Sort::Created => query.order(tasks::id.desc()),
+            // ascending puts nulls last
+            Sort::Due => query.order((tasks::due_date.asc(), tasks::id.desc())),
+            Sort::Priority => query.order((
+                tasks::priority.desc(),
+                tasks::due_date.asc(),
+                tasks::id.desc(),
+            )),
+        };
+        let items = query
+            .limit(filter.per_page())
+            .offset(filter.offset())
+            .load::<Task>(conn)?;
+
+        Ok(Page {
+            items,
+            page: filter.page(),
+            per_page: filter.per_page(),
+            total,
+        })
+    }
+
+    fn filtered(
+        visible: Vec<i32>,
+        filter: &TaskFilter,
+    ) -> tasks::BoxedQuery<'static, Pg> {
+        let mut query = all_tasks
+            .filter(tasks::list_id.eq_any(visible))
+            .filter(tasks::deleted_at.is_null())
+            .into_boxed();
+        if let Some(list_id) = filter.list {
+            query = query.filter(tasks::list_id.eq(list_id));
+        }
+        match filter.status {
+            Status::All => {}
+            Status::Open => query = query.filter(task_completed.eq(false)),
+            Status::Completed => query = query.filter(task_completed.eq(true)),
+        }
+        if filter.overdue {
+            query = query
+                .filter(task_completed.eq(false))
+                .filter(tasks::due_date.lt(Local::today().naive_local()));
+        }
+        if let Some(tag) = filter.tag() {
+            query = query.filter(tasks::tags.contains(vec![tag]));
+        }
+        if let Some(pattern) = filter.search_pattern() {
+            query = query.filter(
+                tasks::description
+                    .ilike(pattern.clone())
+                    .or(tasks::notes.ilike(pattern)),
+            );
+        }
+        query
+    }
+
+    pub fn find(
+        id: i32,
+        user_id: i32,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<Task>> {
+        let task = Task::find_with_deleted(id, user_id, conn)?;
+        Ok(task.filter(|task| task.deleted_at.is_none()))
     }
 
-    pub fn toggle_with_id(id: i32, conn: &PgConnection) -> QueryResult<usize> {
-        let task = all_tasks.find(id).get_result::<Task>(conn)?;
+    pub fn find_with_deleted(
+        id: i32,
+        user_id: i32,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<Task>> {
+        let visible = List::ids_with(user_id, Access::Viewer, conn)?;
+        all_tasks
+            .find(id)
+            .filter(tasks::list_id.eq_any(visible))
+            .get_result::<Task>(conn)
+            .optional()
+    }
+
+    /// `None` if the user can't edit the list.
+    pub fn insert(
+        todo: NewTask,
+        user_id: i32,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<Task>> {
+        match List::access(todo.list_id, user_id, conn)? {
+            Some(access) if access >= Access::Editor => {
+                diesel::insert_into(tasks::table)
+                    .values(&todo)
+                    .get_result(conn)
+                    .map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn update_with_id(
+        id: i32,
+        user_id: i32,
+        changes: TaskChanges,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<Task>> {
+        let editable = List::ids_with(user_id, Access::Editor, conn)?;
+        let task = all_tasks
+            .find(id)
+            .filter(tasks::list_id.eq_any(editable))
+            .filter(tasks::deleted_at.is_null());
+        if changes.is_empty() {
+            // diesel refuses an empty changeset
+            return task.get_result(conn).optional();
+        }
+        diesel::update(task)
+            .set(&changes)
+            .get_result(conn)
+            .optional()
+    }
+
+    pub fn toggle_with_id(
+        id: i32,
+        user_id: i32,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<Task>> {
+        let editable = List::ids_with(user_id, Access::Editor, conn)?;
+        let task = all_tasks
+            .find(id)
+            .filter(tasks::list_id.eq_any(editable))
+            .filter(tasks::deleted_at.is_null());
+        diesel::update(task)
+            .set(task_completed.eq(diesel::dsl::not(task_completed)))
+            .get_result(conn)
+            .optional()
+    }
 
-        let new_status = !task.completed;
-        let updated_task = diesel::update(all_tasks.find(id));
-        updated_task
-            .set(task_completed.eq(new_status))
+    /// Marks the task deleted, `restore_with_id` brings it back.
+    pub fn delete_with_id(
+        id: i32,
+        user_id: i32,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<Task>> {
+        let editable = List::ids_with(user_id, Access::Editor, conn)?;
+        let task = all_tasks
+            .find(id)
+            .filter(tasks::list_id.eq_any(editable))
+            .filter(tasks::deleted_at.is_null());
+        diesel::update(task)
+            .set(tasks::deleted_at.eq(Some(Utc::now())))
+            .get_result(conn)
+            .optional()
+    }
+
+    /// `None` unless the task was deleted in the last `UNDO_MINUTES`.
+    pub fn restore_with_id(
+        id: i32,
+        user_id: i32,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<Task>> {
+        let editable = List::ids_with(user_id, Access::Editor, conn)?;
+        let since = Utc::now() - Duration::minutes(UNDO_MINUTES);
+        let task = all_tasks
+            .find(id)
+            .filter(tasks::list_id.eq_any(editable))
+            .filter(tasks::deleted_at.gt(since));
+        diesel::update(task)
+            .set(tasks::deleted_at.eq(None::<DateTime<Utc>>))
+            .get_result(conn)
+            .optional()
+    }
+}
+
+/// What happened to a task, `task_events.action`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Created,
+    Completed,
+    Reopened,
+    Edited,
+    Deleted,
+    Restored,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Created => "created",
+            Action::Completed => "completed",
+            Action::Reopened => "reopened",
+            Action::Edited => "edited",
+            Action::Deleted => "deleted",
+            Action::Restored => "restored",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Action> {
+        [
+            Action::Created,
+            Action::Completed,
+            Action::Reopened,
+            Action::Edited,
+            Action::Deleted,
+            Action::Restored,
+        ]
+        .iter()
+        .copied()
+        .find(|action| action.name() == name)
+    }
+}
+
+/// One entry of the history of a task.
+#[derive(Debug, Serialize)]
+pub struct TaskEvent {
+    pub username: String,
+    pub action: Action,
+    /// `{"field": {"from": ..., "to": ...}}`
+    pub changes: Value,
+    pub at: DateTime<Utc>,
+}
+
+impl TaskEvent {
+    pub fn record(
+        task_id: i32,
+        user_id: i32,
+        action: Action,
+        changes: Map<String, Value>,
+        conn: &PgConnection,
+    ) -> QueryResult<usize> {
+        diesel::insert_into(task_events::table)
+            .values((
+                task_events::task_id.eq(task_id),
+                task_events::user_id.eq(user_id),
+                task_events::action.eq(action.name()),
+                task_events::changes.eq(Value::Object(changes)),
+            ))
             .execute(conn)
     }
 
-    pub fn delete_with_id(id: i32, conn: &PgConnection) -> QueryResult<usize> {
-        diesel::delete(all_tasks.find(id)).execute(conn)
+    /// Oldest first.
+    pub fn for_task(task_id: i32, conn: &PgConnection) -> QueryResult<Vec<TaskEvent>> {
+        let events = task_events::table
+            .inner_join(users::table)
+            .filter(task_events::task_id.eq(task_id))
+            .order((task_events::created_at, task_events::id))
+            .select((
+                users::username,
+                task_events::action,
+                task_events::changes,
+                task_events::created_at,
+            ))
+            .load::<(String, String, Value, DateTime<Utc>)>(conn)?;
+        Ok(events
+            .into_iter()
+            .filter_map(|(username, action, changes, at)| {
+                Some(TaskEvent {
+                    username,
+                    action: Action::from_name(&action)?,
+                    changes,
+                    at,
+                })
+            })
+            .collect())
+    }
+
+    /// The fields that differ between `before` and `after`, all of them for
+    /// a new task.
+    pub fn changes(before: Option<&Task>, after: &Task) -> Map<String, Value> {
+        let as_map = |task: &Task| match serde_json::to_value(task) {
+            Ok(Value::Object(fields)) => fields,
+            _ => Map::new(),
+        };
+        let before = before.map(as_map).unwrap_or_default();
+        as_map(after)
+            .into_iter()
+            .filter(|(field, _)| {
+                !matches!(field.as_str(), "id" | "list_id" | "deleted_at")
+            })
+            .filter_map(|(field, to)| {
+                let from = before.get(&field).cloned().unwrap_or(Value::Null);
+                if from == to {
+                    return None;
+                }
+                Some((field, json!({ "from": from, "to": to })))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task() -> Task {
+        Task {
+            id: 1,
+            description: "water the plants".to_owned(),
+            completed: false,
+            list_id: 1,
+            due_date: None,
+            priority: NORMAL_PRIORITY,
+            notes: String::new(),
+            tags: Vec::new(),
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn records_what_changed() {
+        let before = task();
+        let after = Task {
+            description: "water the cactus".to_owned(),
+            tags: vec!["home".to_owned()],
+            deleted_at: Some(Utc::now()),
+            ..task()
+        };
+        let changes = TaskEvent::changes(Some(&before), &after);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes["description"],
+            json!({ "from": "water the plants", "to": "water the cactus" })
+        );
+        assert_eq!(changes["tags"], json!({ "from": [], "to": ["home"] }));
+
+        let created = TaskEvent::changes(None, &before);
+        assert_eq!(created["description"]["from"], Value::Null);
+        assert!(!created.contains_key("id"));
+    }
+
+    #[test]
+    fn matches_filters() {
+        let today = NaiveDate::from_ymd(2026, 10, 19);
+        let filter =
+            |query: &str| serde_urlencoded::from_str::<TaskFilter>(query).unwrap();
+        let watering = || Task {
+            notes: "Use the green can".to_owned(),
+            tags: vec!["home".to_owned()],
+            due_date: Some(NaiveDate::from_ymd(2026, 10, 18)),
+            ..task()
+        };
+
+        let task = watering();
+        assert!(task.matches(&filter(""), today));
+        assert!(task.matches(&filter("list=1&status=open&tag=%23Home"), today));
+        assert!(task.matches(&filter("q=GREEN&overdue=true&page=3"), today));
+        assert!(!task.matches(&filter("list=2"), today));
+        assert!(!task.matches(&filter("status=completed"), today));
+        assert!(!task.matches(&filter("tag=work"), today));
+        assert!(!task.matches(&filter("q=cactus"), today));
+
+        let completed = Task {
+            completed: true,
+            ..watering()
+        };
+        assert!(!completed.matches(&filter("overdue=true"), today));
+        let deleted = Task {
+            deleted_at: Some(Utc::now()),
+            ..watering()
+        };
+        assert!(!deleted.matches(&filter(""), today));
     }
 }
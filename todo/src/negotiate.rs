@@ -0,0 +1,149 @@
+//! Lets one handler answer browsers and API clients alike.
+//!
+//! `Format` is HTML or JSON, whichever the `Accept` header prefers. When it
+//! has no preference, paths under `/api/` get JSON and everything else HTML.
+//! `Input` reads the request body as JSON or as an urlencoded form,
+//! depending on its `Content-Type`.
+use std::future::Future;
+use std::pin::Pin;
+
+use futures::future::{ready, Ready};
+use loony::http::{header, HttpMessage, Payload};
+use loony::web::types::{Form, Json};
+use loony::web::{error, Error, ErrorRenderer, FromRequest, HttpRequest};
+use serde::de::DeserializeOwned;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    /// The preferred of the two by the `q` values in `accept`, `default` on
+    /// a tie.
+    pub fn from_accept(accept: &str, default: Format) -> Format {
+        let json = quality(accept, "application/json").unwrap_or(0.0);
+        let html = quality(accept, "text/html").unwrap_or(0.0);
+        if json > html {
+            Format::Json
+        } else if html > json {
+            Format::Html
+        } else {
+            default
+        }
+    }
+
+    /// What `req` asks for, also used by the error pages.
+    pub fn of(req: &HttpRequest) -> Format {
+        let default = if req.path().starts_with("/api/") {
+            Format::Json
+        } else {
+            Format::Html
+        };
+        let accept = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        Format::from_accept(accept, default)
+    }
+}
+
+impl<Err> FromRequest<Err> for Format {
+    type Error = Error;
+    type Future = Ready<Result<Format, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(Format::of(req)))
+    }
+}
+
+/// The `q` of the most specific range in `accept` that matches
+/// `media_type`, if any does.
+fn quality(accept: &str, media_type: &str) -> Option<f32> {
+    let any_subtype = format!("{}/*", media_type.split('/').next()?);
+    let mut best: Option<(u8, f32)> = None;
+
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let range_type = params.next().unwrap_or("");
+        let specificity = if range_type.eq_ignore_ascii_case(media_type) {
+            2
+        } else if range_type.eq_ignore_ascii_case(&any_subtype) {
+            1
+        } else if range_type == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let q = params
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        if best.map_or(true, |(most_specific, _)| specificity > most_specific) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map(|(_, q)| q)
+}
+
+/// A request body sent either as JSON or as an urlencoded form.
+pub struct Input<T>(pub T);
+
+impl<T> Input<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T, Err> FromRequest<Err> for Input<T>
+where
+    T: DeserializeOwned + 'static,
+    Err: ErrorRenderer,
+{
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Input<T>, Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if req.content_type() == "application/json" {
+            let body = <Json<T> as FromRequest<Err>>::from_request(req, payload);
+            Box::pin(async move {
+                match body.await {
+                    Ok(body) => Ok(Input(body.into_inner())),
+                    Err(e) => Err(error::ErrorBadRequest(e).into()),
+                }
+            })
+        } else {
+            let body = <Form<T> as FromRequest<Err>>::from_request(req, payload);
+            Box::pin(async move {
+                match body.await {
+                    Ok(body) => Ok(Input(body.into_inner())),
+                    Err(e) => Err(error::ErrorBadRequest(e).into()),
+                }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_preferred_format() {
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(Format::from_accept(browser, Format::Json), Format::Html);
+        assert_eq!(
+            Format::from_accept("application/json", Format::Html),
+            Format::Json
+        );
+        assert_eq!(
+            Format::from_accept("text/html;q=0.5, application/*", Format::Html),
+            Format::Json
+        );
+        // curl sends */*, no preference
+        assert_eq!(Format::from_accept("*/*", Format::Json), Format::Json);
+        assert_eq!(Format::from_accept("", Format::Html), Format::Html);
+    }
+}
@@ -1,7 +1,57 @@
+table! {
+    list_shares (list_id, user_id) {
+        list_id -> Int4,
+        user_id -> Int4,
+        role -> Varchar,
+    }
+}
+
+table! {
+    lists (id) {
+        id -> Int4,
+        owner_id -> Int4,
+        name -> Varchar,
+    }
+}
+
 table! {
     tasks (id) {
         id -> Int4,
         description -> Varchar,
         completed -> Bool,
+        list_id -> Int4,
+        due_date -> Nullable<Date>,
+        priority -> Int2,
+        notes -> Text,
+        tags -> Array<Text>,
+        deleted_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    task_events (id) {
+        id -> Int4,
+        task_id -> Int4,
+        user_id -> Int4,
+        action -> Varchar,
+        changes -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    users (id) {
+        id -> Int4,
+        username -> Varchar,
+        password_hash -> Varchar,
     }
 }
+
+joinable!(list_shares -> lists (list_id));
+joinable!(list_shares -> users (user_id));
+joinable!(lists -> users (owner_id));
+joinable!(task_events -> tasks (task_id));
+joinable!(task_events -> users (user_id));
+joinable!(tasks -> lists (list_id));
+
+allow_tables_to_appear_in_same_query!(list_shares, lists, task_events, tasks, users,);
@@ -20,6 +20,9 @@ pub fn clear_flash(session: &Session) {
 pub struct FlashMessage {
     pub kind: String,
     pub message: String,
+    /// where an "Undo" button posts to
+    #[serde(default)]
+    pub undo: Option<String>,
 }
 
 impl FlashMessage {
@@ -27,6 +30,7 @@ impl FlashMessage {
         Self {
             kind: "success".to_owned(),
             message: message.to_owned(),
+            undo: None,
         }
     }
 
@@ -34,6 +38,14 @@ impl FlashMessage {
         Self {
             kind: "error".to_owned(),
             message: message.to_owned(),
+            undo: None,
+        }
+    }
+
+    pub fn with_undo(self, action: String) -> Self {
+        Self {
+            undo: Some(action),
+            ..self
         }
     }
 }